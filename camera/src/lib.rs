@@ -411,6 +411,35 @@ fn init_cameras(camera_number: u32) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// 图像在传感器上的区域，对应相机的 Width/Height/OffsetX/OffsetY
+///
+/// 以 OpenCV 使用的 `i32` 表示，SDK 中的整型参数只在 [`query_roi`] 中转换。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Roi {
+    pub width: i32,
+    pub height: i32,
+    pub offset_x: i32,
+    pub offset_y: i32,
+}
+
+/// 读取相机实际生效的图像区域
+///
+/// 相机按步进对齐写入的宽高与偏移，实际取值可能与配置不同，畸变校正应以此为准。
+pub fn query_roi(cam_id: u32) -> Result<Roi> {
+    let read = |name: &str| -> Result<i32> {
+        let info = get_int_param_safe(cam_id, name.to_string())
+            .with_context(|| format!("[cam err04] 无法读取相机参数{}", name))?;
+        i32::try_from(info.current)
+            .with_context(|| format!("[cam err04] 相机参数{}超出范围：{}", name, info.current))
+    };
+    Ok(Roi {
+        width: read("Width")?,
+        height: read("Height")?,
+        offset_x: read("OffsetX")?,
+        offset_y: read("OffsetY")?,
+    })
+}
+
 // fn config_camera(cam_id: u32) -> Result<(), anyhow::Error> {
 //     let config::Camera {
 //         exposure_time,
@@ -0,0 +1,221 @@
+//! 配置加载与保存过程中的错误
+use std::{fmt, io, path::PathBuf};
+
+use crate::Origin;
+
+/// 单个字段的校验错误
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct FieldError {
+    /// 以 `section.field` 形式表示的字段路径
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`: {}", self.field, self.message)
+    }
+}
+
+/// 配置错误
+#[derive(Debug)]
+pub enum ConfigError {
+    /// 在查找路径上未找到配置文件
+    NotFound { searched: Vec<PathBuf> },
+    /// 读写配置文件失败
+    Io { path: PathBuf, source: io::Error },
+    /// 配置文件包含非合法 UTF-8 的字节
+    InvalidUtf8 { path: PathBuf },
+    /// TOML 语法错误，行号与列号从 1 开始
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// 配置文件的版本高于当前程序支持的版本
+    UnsupportedVersion {
+        path: PathBuf,
+        version: u32,
+        supported: u32,
+    },
+    /// 字段校验失败，包含全部出错的字段
+    Validation(Vec<FieldError>),
+    /// 序列化配置失败
+    Serialize(toml::ser::Error),
+    /// 启用的配置档未在配置文件中定义
+    UnknownProfile {
+        name: String,
+        available: Vec<String>,
+    },
+    /// 撤销所需的修改记录已超出历史容量而被丢弃
+    RevertUnavailable { seq: u64, oldest: u64 },
+    /// 运行时修改的字段由环境变量或命令行覆盖，无法保存到配置文件
+    Overridden { field: String, origin: Origin },
+    /// 命令行参数格式错误
+    Args(String),
+    /// 全局配置已初始化，无法再次初始化
+    AlreadyInitialized,
+}
+
+impl ConfigError {
+    /// 由 TOML 解析错误构造，根据错误位置计算行号与列号
+    pub(crate) fn parse(path: PathBuf, content: &str, err: toml::de::Error) -> Self {
+        let offset = err.span().map(|span| span.start).unwrap_or_default();
+        let before = &content[..offset.min(content.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rsplit('\n')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .count()
+            + 1;
+        ConfigError::Parse {
+            path,
+            line,
+            column,
+            message: err.message().trim().to_string(),
+        }
+    }
+
+    /// 由某个配置段的反序列化错误构造字段校验错误
+    pub(crate) fn section(section: &str, err: toml::de::Error) -> FieldError {
+        // 错误信息的末行形如 "in `field`"
+        let display = err.to_string();
+        let field = display
+            .lines()
+            .last()
+            .and_then(|line| line.strip_prefix("in `"))
+            .and_then(|line| line.strip_suffix('`'));
+        FieldError {
+            field: match field {
+                Some(field) => format!("{}.{}", section, field),
+                None => section.to_string(),
+            },
+            message: err.message().trim().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NotFound { searched } => {
+                writeln!(f, "在查找路径上未找到配置文件Param.toml，已查找的路径：")?;
+                for path in searched {
+                    writeln!(f, "    {}", path.display())?;
+                }
+                write!(
+                    f,
+                    "有四种解决方案，请任选其一：
+    1. 使用命令行参数 --config 指定Param.toml的路径
+    2. 请设置环境变量QUASAR_CONFIG_PATH，指向Param.toml的绝对路径
+    3. 将Param.toml放置到项目根目录
+    4. 将Param.toml放置到命令行中的当前目录（pwd）"
+                )
+            }
+            ConfigError::Io { path, source } => {
+                write!(f, "读写配置文件“{}”失败：{}", path.display(), source)
+            }
+            ConfigError::InvalidUtf8 { path } => write!(
+                f,
+                "读取配置文件“{}”失败，文件编码包含非合法UTF-8的字节",
+                path.display()
+            ),
+            ConfigError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "配置文件“{}”第{}行第{}列解析失败：{}",
+                path.display(),
+                line,
+                column,
+                message
+            ),
+            ConfigError::UnsupportedVersion {
+                path,
+                version,
+                supported,
+            } => write!(
+                f,
+                "配置文件“{}”的版本为{}，当前程序最高支持版本{}，请更新程序",
+                path.display(),
+                version,
+                supported
+            ),
+            ConfigError::Validation(errors) => {
+                write!(f, "配置校验失败，共{}处错误：", errors.len())?;
+                for error in errors {
+                    write!(f, "\n    {}", error)?;
+                }
+                Ok(())
+            }
+            ConfigError::Serialize(err) => write!(f, "序列化配置失败：{}", err),
+            ConfigError::UnknownProfile { name, available } => write!(
+                f,
+                "配置档“{}”不存在，已定义的配置档：[{}]",
+                name,
+                available.join(", ")
+            ),
+            ConfigError::RevertUnavailable { seq, oldest } => write!(
+                f,
+                "无法撤销到序号{}，修改历史中最早的记录序号为{}",
+                seq, oldest
+            ),
+            ConfigError::Overridden { field, origin } => write!(
+                f,
+                "无法保存字段`{}`，该字段由{}覆盖，请修改或移除该覆盖",
+                field, origin
+            ),
+            ConfigError::Args(message) => write!(f, "命令行参数错误：{}", message),
+            ConfigError::AlreadyInitialized => {
+                write!(f, "全局配置已初始化，须在首次访问配置之前进行初始化")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Serialize(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_position() {
+        let content = "[camera]\ngain = = 2\n";
+        let err = toml::from_str::<toml::Table>(content).unwrap_err();
+        let ConfigError::Parse { line, column, .. } =
+            ConfigError::parse(PathBuf::from("Param.toml"), content, err)
+        else {
+            panic!("应当为解析错误");
+        };
+        assert_eq!((line, column), (2, 8));
+    }
+
+    #[test]
+    fn test_section_field() {
+        #[derive(serde::Deserialize, Debug)]
+        struct Section {
+            #[allow(dead_code)]
+            gain: f32,
+        }
+        let value: toml::Value = toml::from_str::<toml::Table>("gain = \"high\"")
+            .unwrap()
+            .into();
+        let err = value.try_into::<Section>().unwrap_err();
+        assert_eq!(ConfigError::section("camera", err).field, "camera.gain");
+    }
+}
@@ -0,0 +1,332 @@
+//! 配置修改历史与撤销
+//!
+//! 运行时对配置的每一次修改（热重载、切换配置档、可视化界面远程修改、程序内调用
+//! `CONFIG.update` 等）都会按字段记录为一条 [`ChangeRecord`]，保存在容量有限的历史中，
+//! 超出容量时丢弃最早的记录。通过 [`Config::history`] 读取历史，通过 [`Config::revert`]
+//! 将配置恢复到任意一条记录之后的状态，撤销本身也会记录在历史中。
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+use serde::Serialize;
+use toml::{Table, Value};
+
+use crate::{schema, Config, ConfigChange, ConfigError, ConfigInner, Origin, Section, UnknownKeys};
+
+/// 默认保留的修改记录数量
+pub const DEFAULT_HISTORY: usize = 256;
+
+/// 修改的来源
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeSource {
+    /// 配置文件变化后重新加载
+    File,
+    /// 切换配置档
+    Profile,
+    /// 可视化界面通过 WebSocket 修改
+    Websocket,
+    /// 命令行工具修改
+    Cli,
+    /// 程序内部调用 `CONFIG.update` 等接口修改
+    Api,
+    /// 撤销到历史中的某一时刻
+    Revert,
+}
+
+impl fmt::Display for ChangeSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeSource::File => write!(f, "配置文件"),
+            ChangeSource::Profile => write!(f, "配置档"),
+            ChangeSource::Websocket => write!(f, "可视化界面"),
+            ChangeSource::Cli => write!(f, "命令行"),
+            ChangeSource::Api => write!(f, "程序接口"),
+            ChangeSource::Revert => write!(f, "撤销"),
+        }
+    }
+}
+
+/// 单个字段的修改
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FieldChange {
+    pub section: Section,
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+/// 比较配置段修改前后的取值，返回发生变化的字段
+pub(crate) fn diff(section: Section, old: &Table, new: &Table) -> Vec<FieldChange> {
+    new.iter()
+        .filter_map(|(field, value)| {
+            let old = old.get(field)?;
+            (old != value).then(|| FieldChange {
+                section,
+                field: field.clone(),
+                old: old.clone(),
+                new: value.clone(),
+            })
+        })
+        .collect()
+}
+
+/// 一条修改记录
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChangeRecord {
+    /// 递增的序号，从 1 开始
+    pub seq: u64,
+    /// 修改时间，自 UNIX 纪元起的毫秒数
+    pub timestamp: u64,
+    pub section: Section,
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+    pub source: ChangeSource,
+}
+
+/// 容量有限的修改历史
+#[derive(Debug)]
+pub(crate) struct History {
+    records: VecDeque<ChangeRecord>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+impl History {
+    pub(crate) const fn new() -> Self {
+        Self {
+            records: VecDeque::new(),
+            capacity: DEFAULT_HISTORY,
+            next_seq: 1,
+        }
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.truncate();
+    }
+
+    fn truncate(&mut self) {
+        while self.records.len() > self.capacity {
+            self.records.pop_front();
+        }
+    }
+
+    /// 记录一组修改
+    pub(crate) fn push(&mut self, changes: Vec<FieldChange>, source: ChangeSource) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        for change in changes {
+            info!(
+                "[配置] `{}.{}`: {} → {}（来源：{}）",
+                change.section, change.field, change.old, change.new, source
+            );
+            self.records.push_back(ChangeRecord {
+                seq: self.next_seq,
+                timestamp,
+                section: change.section,
+                field: change.field,
+                old: change.old,
+                new: change.new,
+                source,
+            });
+            self.next_seq += 1;
+        }
+        self.truncate();
+    }
+
+    /// 撤销序号大于 `seq` 的全部修改后各字段应有的取值
+    fn revert_targets(&self, seq: u64) -> Result<BTreeMap<Section, Table>, ConfigError> {
+        let oldest = self
+            .records
+            .front()
+            .map_or(self.next_seq, |record| record.seq);
+        if seq.saturating_add(1) < oldest {
+            return Err(ConfigError::RevertUnavailable { seq, oldest });
+        }
+        // 从新到旧遍历，同一字段最终取最早一条记录的旧值
+        let mut targets: BTreeMap<Section, Table> = BTreeMap::new();
+        for record in self
+            .records
+            .iter()
+            .rev()
+            .take_while(|record| record.seq > seq)
+        {
+            targets
+                .entry(record.section)
+                .or_default()
+                .insert(record.field.clone(), record.old.clone());
+        }
+        Ok(targets)
+    }
+}
+
+impl Config {
+    /// 获取修改历史，按时间从旧到新排列
+    pub fn history(&self) -> Vec<ChangeRecord> {
+        self.history
+            .lock()
+            .expect("锁中毒")
+            .records
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// 撤销序号大于 `seq` 的全部修改，即恢复到序号为 `seq` 的记录刚生效后的状态
+    ///
+    /// `seq` 为 0 时撤销历史中的全部修改。若所需的记录已因超出容量而被丢弃，返回
+    /// [`ConfigError::RevertUnavailable`]。撤销同样经过校验、通知订阅者并记录在历史中。
+    ///
+    /// 全部配置段先一并校验，任一配置段校验失败时不做任何修改，并报告全部出错的字段。
+    ///
+    /// # 返回值
+    /// 发生变化的配置段
+    pub fn revert(&self, seq: u64) -> Result<Vec<Section>, ConfigError> {
+        let targets = self.history.lock().expect("锁中毒").revert_targets(seq)?;
+        let changes = self.try_get()?.replace_with(|mut table| {
+            let mut errors = Vec::new();
+            for (section, fields) in &targets {
+                let Some(Value::Table(current)) = table.get_mut(section.name()) else {
+                    unreachable!("配置序列化的结果应当包含全部配置段");
+                };
+                current.extend(fields.clone());
+                schema::check_section(*section, current, None, UnknownKeys::Deny, &mut errors);
+            }
+            if !errors.is_empty() {
+                return Err(ConfigError::Validation(errors));
+            }
+            let new = ConfigInner::from_table(&table)?;
+            // 与运行时修改相同，持有写锁期间记入来源，避免被并发的重新加载遗漏
+            if let Some(effective) = self.effective.lock().expect("锁中毒").as_mut() {
+                for section in targets.keys() {
+                    if let Some(Value::Table(fields)) = table.remove(section.name()) {
+                        effective.apply(section.name(), fields, &Origin::Runtime);
+                    }
+                }
+            }
+            Ok(new)
+        })?;
+
+        let mut changed: Vec<_> = changes.iter().map(|change| change.section).collect();
+        changed.dedup();
+        self.record(changes, ChangeSource::Revert);
+        for &section in &changed {
+            self.notify(ConfigChange { section });
+        }
+        Ok(changed)
+    }
+
+    /// 记录一组修改
+    pub(crate) fn record(&self, changes: Vec<FieldChange>, source: ChangeSource) {
+        self.history.lock().expect("锁中毒").push(changes, source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::temp_config, Camera};
+
+    fn change(field: &str, old: i64, new: i64) -> FieldChange {
+        FieldChange {
+            section: Section::Camera,
+            field: field.to_string(),
+            old: Value::Integer(old),
+            new: Value::Integer(new),
+        }
+    }
+
+    #[test]
+    fn test_history_capacity() {
+        let mut history = History::new();
+        history.set_capacity(2);
+        history.push(
+            vec![change("width", 1, 2), change("height", 1, 2)],
+            ChangeSource::Api,
+        );
+        history.push(vec![change("width", 2, 3)], ChangeSource::Websocket);
+        let seqs: Vec<_> = history.records.iter().map(|record| record.seq).collect();
+        assert_eq!(seqs, [2, 3]);
+
+        // 最早的记录已被丢弃，无法撤销到其之前
+        assert!(history.revert_targets(0).is_err());
+        let targets = history.revert_targets(1).unwrap();
+        assert_eq!(targets[&Section::Camera]["width"].as_integer(), Some(2));
+        assert_eq!(targets[&Section::Camera]["height"].as_integer(), Some(1));
+    }
+
+    #[test]
+    fn test_revert() {
+        let config = temp_config("history.toml", "gain = 5.0\nwidth = 1440");
+        config
+            .update(|camera: &mut Camera| camera.gain = 8.0)
+            .unwrap();
+        let checkpoint = config.history().last().unwrap().seq;
+        config
+            .set_field(
+                Section::Camera,
+                "gain",
+                Value::Float(12.0),
+                ChangeSource::Websocket,
+            )
+            .unwrap();
+        config
+            .update(|camera: &mut Camera| camera.width = 1280)
+            .unwrap();
+
+        let history = config.history();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[1].source, ChangeSource::Websocket);
+        assert_eq!(history[1].old, Value::Float(8.0));
+
+        assert_eq!(config.revert(checkpoint).unwrap(), vec![Section::Camera]);
+        let camera = config.camera.load();
+        assert_eq!((camera.gain, camera.width), (8.0, 1440));
+        // 撤销本身也记录在历史中
+        assert_eq!(
+            config.history().last().unwrap().source,
+            ChangeSource::Revert
+        );
+
+        config.revert(0).unwrap();
+        assert_eq!(config.camera.load().gain, 5.0);
+
+        // 客户端传入的序号过大时不溢出，没有需要撤销的修改
+        assert!(config.revert(u64::MAX).unwrap().is_empty());
+        std::fs::remove_file(config.effective().base).unwrap();
+    }
+
+    #[test]
+    fn test_revert_all_or_nothing() {
+        let config = temp_config("revert_atomic.toml", "gain = 5.0");
+        config
+            .update(|camera: &mut Camera| camera.gain = 8.0)
+            .unwrap();
+        let checkpoint = config.history().last().unwrap().seq - 1;
+        // 伪造一条旧值不合法的记录，撤销时该配置段无法通过校验
+        config.record(
+            vec![FieldChange {
+                section: Section::Gui,
+                field: "metrics_interval".to_string(),
+                old: Value::Integer(1),
+                new: Value::Integer(1000),
+            }],
+            ChangeSource::Api,
+        );
+
+        let Err(ConfigError::Validation(errors)) = config.revert(checkpoint) else {
+            panic!("应当校验失败");
+        };
+        assert_eq!(errors[0].field, "gui.metrics_interval");
+        // 其他配置段同样保持不变
+        assert_eq!(config.camera.load().gain, 8.0);
+        std::fs::remove_file(config.effective().base).unwrap();
+    }
+}
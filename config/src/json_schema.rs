@@ -0,0 +1,138 @@
+//! 导出配置的 JSON Schema
+//!
+//! 由各配置段的 [`FieldSpec`] 生成符合 JSON Schema（2020-12）的描述，供可视化界面与调参工具
+//! 自动生成编辑器。字段的文档注释作为 `description`，取值范围对应 `minimum`/`maximum`，
+//! 单位写入自定义关键字 `unit`。
+use serde_json::{json, Map, Value};
+
+use crate::{FieldSpec, Kind, Section, CURRENT_VERSION};
+
+fn kind_schema(kind: &Kind) -> Value {
+    match kind {
+        Kind::Bool => json!({ "type": "boolean" }),
+        Kind::Integer => json!({ "type": "integer" }),
+        Kind::Float => json!({ "type": "number" }),
+        Kind::String => json!({ "type": "string" }),
+        Kind::Enum(variants) => json!({ "type": "string", "enum": variants }),
+        Kind::Array(item, len) => json!({
+            "type": "array",
+            "items": kind_schema(item),
+            "minItems": len,
+            "maxItems": len,
+        }),
+    }
+}
+
+fn field_schema(spec: &FieldSpec) -> Value {
+    let mut schema = kind_schema(&spec.kind);
+    let object = schema.as_object_mut().expect("字段的 schema 应当是对象");
+    let mut description = spec.description.clone();
+    if let Some(unit) = spec.unit {
+        description = format!("{}（单位：{}）", description, unit);
+        object.insert("unit".into(), unit.into());
+    }
+    object.insert("description".into(), description.into());
+    if let Some((min, max)) = spec.range {
+        object.insert("minimum".into(), json!(min));
+        object.insert("maximum".into(), json!(max));
+    }
+    if let Ok(default) = serde_json::to_value(&spec.default) {
+        object.insert("default".into(), default);
+    }
+    schema
+}
+
+/// 配置段的 schema，`with_required` 为假时不列出必填字段（用于配置档）
+fn section_schema(section: Section, with_required: bool) -> Value {
+    let fields = section.fields();
+    let properties: Map<_, _> = fields
+        .iter()
+        .map(|spec| (spec.name.to_string(), field_schema(spec)))
+        .collect();
+    let required: Vec<_> = fields
+        .iter()
+        .filter(|spec| with_required && spec.required)
+        .map(|spec| spec.name)
+        .collect();
+    json!({
+        "type": "object",
+        "description": section.description(),
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+/// 生成 `Param.toml` 的 JSON Schema
+pub fn json_schema() -> Value {
+    let mut properties = Map::new();
+    properties.insert(
+        "version".into(),
+        json!({
+            "type": "integer",
+            "description": "配置文件格式版本，由程序自动迁移",
+            "minimum": 0,
+            "maximum": CURRENT_VERSION,
+        }),
+    );
+    for section in Section::ALL {
+        properties.insert(section.name().into(), section_schema(section, true));
+    }
+    let profile: Map<_, _> = Section::ALL
+        .into_iter()
+        .map(|section| (section.name().to_string(), section_schema(section, false)))
+        .collect();
+    properties.insert(
+        "profiles".into(),
+        json!({
+            "type": "object",
+            "description": "配置档，启用后按顺序叠加在基础配置之上",
+            "additionalProperties": {
+                "type": "object",
+                "properties": profile,
+                "additionalProperties": false,
+            },
+        }),
+    );
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Param.toml",
+        "description": "Quasar Trajectory 配置文件",
+        "type": "object",
+        "properties": properties,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_schema() {
+        let schema = json_schema();
+        let camera = &schema["properties"]["camera"];
+        assert_eq!(camera["description"], "相机配置");
+        assert_eq!(camera["required"], json!(["camera_matrix"]));
+
+        let gain = &camera["properties"]["gain"];
+        assert_eq!(gain["type"], "number");
+        assert_eq!(gain["unit"], "dB(分贝)");
+        assert_eq!(gain["maximum"], json!(48.0));
+        assert!(gain["description"].as_str().unwrap().starts_with("增益"));
+
+        let matrix = &camera["properties"]["camera_matrix"];
+        assert_eq!(matrix["items"]["type"], "number");
+        assert_eq!(matrix["minItems"], 9);
+        assert_eq!(
+            camera["properties"]["undistort"]["enum"],
+            json!(["none", "frame", "points"])
+        );
+
+        let profile = &schema["properties"]["profiles"]["additionalProperties"];
+        assert_eq!(
+            profile["properties"]["camera"]["required"],
+            json!([] as [&str; 0])
+        );
+    }
+}
@@ -0,0 +1,669 @@
+//! 分层配置解析
+//!
+//! 最终生效的配置由以下各层依次合并而成，后者覆盖前者：
+//! 1. 各配置结构体编译期内置的默认值（`Default` 实现）；
+//! 2. 基础配置文件 `Param.toml`（查找顺序见 crate 文档，可用 `--config` 显式指定）；
+//! 3. 基础配置文件中 `[profiles.<name>]` 定义的配置档，按 `--profile` 或 `QUASAR_PROFILE`
+//!    指定的顺序叠加；
+//! 4. 可选的机器人专属覆盖文件，由 `--overlay` 或环境变量 `QUASAR_OVERLAY_PATH` 指定；
+//! 5. `QUASAR_<SECTION>_<FIELD>` 形式的环境变量，如 `QUASAR_CAMERA_EXPOSURE_TIME=2000`；
+//! 6. 命令行参数 `--set <section>.<field>=<value>`，如 `--set camera.gain=12`。
+//!
+//! 合并以 TOML 表为单位逐键进行，同时记录每个值的来源，可通过 [`Effective`] 打印以便调试。
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use toml::{Table, Value};
+
+use crate::{migrate, ConfigError, ConfigInner, FieldError, UnknownKeys, DEFAULT_HISTORY};
+
+/// 环境变量覆盖的前缀
+const ENV_PREFIX: &str = "QUASAR_";
+
+/// 配置值的来源
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// 编译期内置的默认值
+    Default,
+    /// 基础配置文件
+    File(PathBuf),
+    /// 基础配置文件中的配置档，记录配置档名称
+    Profile(String),
+    /// 覆盖配置文件
+    Overlay(PathBuf),
+    /// 环境变量，记录变量名
+    Env(String),
+    /// 命令行参数 `--set`
+    Cli,
+    /// 运行时通过 `Config::update` 修改
+    Runtime,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "默认值"),
+            Origin::File(path) => write!(f, "配置文件 {}", path.display()),
+            Origin::Profile(name) => write!(f, "配置档 {}", name),
+            Origin::Overlay(path) => write!(f, "覆盖文件 {}", path.display()),
+            Origin::Env(name) => write!(f, "环境变量 {}", name),
+            Origin::Cli => write!(f, "命令行 --set"),
+            Origin::Runtime => write!(f, "运行时修改"),
+        }
+    }
+}
+
+/// 保存配置时默认保留的备份数量
+pub const DEFAULT_BACKUPS: usize = 3;
+
+/// 配置加载选项
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// 显式指定的基础配置文件（或其所在目录），为 `None` 时按搜索顺序查找
+    pub base: Option<PathBuf>,
+    /// 覆盖配置文件
+    pub overlay: Option<PathBuf>,
+    /// 启用的配置档，按叠加顺序排列
+    pub profiles: Vec<String>,
+    /// 以 `QUASAR_` 开头的环境变量
+    pub env: Vec<(String, String)>,
+    /// 命令行 `--set` 指定的键值对，键为 `<section>.<field>`
+    pub overrides: Vec<(String, String)>,
+    /// 未知字段的处理方式
+    pub unknown_keys: UnknownKeys,
+    /// 保存配置时保留的带时间戳备份数量，为 0 时不备份
+    pub backups: usize,
+    /// 是否将旧版本配置文件的迁移结果写回原文件
+    pub write_migrated: bool,
+    /// 修改历史保留的记录数量
+    pub history: usize,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            base: None,
+            overlay: None,
+            profiles: Vec::new(),
+            env: Vec::new(),
+            overrides: Vec::new(),
+            unknown_keys: UnknownKeys::default(),
+            backups: DEFAULT_BACKUPS,
+            write_migrated: false,
+            history: DEFAULT_HISTORY,
+        }
+    }
+}
+
+impl LoadOptions {
+    /// 从进程环境变量构造加载选项
+    pub fn from_env() -> Self {
+        let env: Vec<_> = std::env::vars()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        let var = |key: &str| {
+            env.iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.clone())
+        };
+        let overlay = var("QUASAR_OVERLAY_PATH").map(PathBuf::from);
+        let profiles = var("QUASAR_PROFILE")
+            .map(|value| split_profiles(&value))
+            .unwrap_or_default();
+        let backups = var("QUASAR_CONFIG_BACKUPS")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_BACKUPS);
+        Self {
+            overlay,
+            profiles,
+            env,
+            backups,
+            ..Default::default()
+        }
+    }
+
+    /// 解析命令行参数中的 `--config <path>`、`--overlay <path>`、`--profile <name>`、
+    /// `--set <key>=<value>`、`--backups <n>`、`--history <n>`、`--write-migrated` 与
+    /// `--deny-unknown-keys`
+    ///
+    /// `--profile` 可重复指定，也可用逗号分隔多个配置档，命令行指定的配置档将取代环境变量
+    /// `QUASAR_PROFILE` 中的配置档。
+    ///
+    /// # 返回值
+    /// 未被识别的其余参数，按原顺序返回
+    pub fn parse_args<I>(&mut self, args: I) -> Result<Vec<String>, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut rest = Vec::new();
+        let mut profiles = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| {
+                args.next()
+                    .ok_or_else(|| ConfigError::Args(format!("{} 缺少取值", flag)))
+            };
+            match arg.as_str() {
+                "--config" => self.base = Some(value("--config")?.into()),
+                "--overlay" => self.overlay = Some(value("--overlay")?.into()),
+                "--profile" => profiles.extend(split_profiles(&value("--profile")?)),
+                "--set" => {
+                    let pair = value("--set")?;
+                    let (key, raw) = pair.split_once('=').ok_or_else(|| {
+                        ConfigError::Args(format!(
+                            "--set 的格式应为 <section>.<field>=<value>，实际为 `{}`",
+                            pair
+                        ))
+                    })?;
+                    self.overrides
+                        .push((key.trim().to_string(), raw.trim().to_string()));
+                }
+                "--backups" => {
+                    let count = value("--backups")?;
+                    self.backups = count.parse().map_err(|_| {
+                        ConfigError::Args(format!(
+                            "--backups 的取值应为非负整数，实际为 `{}`",
+                            count
+                        ))
+                    })?;
+                }
+                "--history" => {
+                    let count = value("--history")?;
+                    self.history = count.parse().map_err(|_| {
+                        ConfigError::Args(format!(
+                            "--history 的取值应为非负整数，实际为 `{}`",
+                            count
+                        ))
+                    })?;
+                }
+                "--write-migrated" => self.write_migrated = true,
+                "--deny-unknown-keys" => self.unknown_keys = UnknownKeys::Deny,
+                _ => rest.push(arg),
+            }
+        }
+        if !profiles.is_empty() {
+            self.profiles = profiles;
+        }
+        Ok(rest)
+    }
+}
+
+/// 合并后生效的配置，以及每个值的来源
+#[derive(Debug, Clone, Default)]
+pub struct Effective {
+    /// 合并后的配置表
+    pub table: Table,
+    /// 每个叶子值（以 `section.field` 形式的路径为键）的来源
+    pub origins: BTreeMap<String, Origin>,
+    /// 基础配置文件的路径
+    pub base: PathBuf,
+    /// 基础配置文件中定义的全部配置档名称
+    pub profiles: Vec<String>,
+}
+
+impl Effective {
+    /// 将 `src` 逐键合并到当前配置表中，并记录来源
+    fn merge(&mut self, src: Table, origin: &Origin) {
+        merge_table(&mut self.table, src, "", origin, &mut self.origins);
+    }
+
+    /// 按 `section.field` 形式的路径设置单个值
+    fn set(&mut self, path: &str, value: Value, origin: &Origin) {
+        let nested = path.rsplit('.').fold(value, |value, key| {
+            Value::Table(Table::from_iter([(key.to_string(), value)]))
+        });
+        if let Value::Table(table) = nested {
+            self.merge(table, origin);
+        }
+    }
+
+    /// 用 `table` 更新某个配置段，仅记录取值发生变化的字段的来源
+    pub(crate) fn apply(&mut self, section: &str, table: Table, origin: &Origin) {
+        let current = self.table.get(section).and_then(Value::as_table);
+        let changed: Table = table
+            .into_iter()
+            .filter(|(key, value)| current.and_then(|current| current.get(key)) != Some(value))
+            .collect();
+        self.merge(
+            Table::from_iter([(section.to_string(), Value::Table(changed))]),
+            origin,
+        );
+    }
+
+    /// 获取某个值的来源
+    pub fn origin(&self, path: &str) -> Option<&Origin> {
+        self.origins.get(path)
+    }
+
+    /// 运行时修改且尚未保存的值，以 `section.field` 形式的路径为键
+    pub(crate) fn runtime_values(&self) -> Vec<(String, Value)> {
+        let mut leaves = Vec::new();
+        flatten(&self.table, "", &mut leaves);
+        leaves
+            .into_iter()
+            .filter(|(path, _)| matches!(self.origins.get(path), Some(Origin::Runtime)))
+            .map(|(path, value)| (path, value.clone()))
+            .collect()
+    }
+
+    /// 将运行时修改的值重新叠加在各层配置之上
+    ///
+    /// # 返回值
+    /// 重新叠加的路径。与各层配置取值相同的值不再视为运行时修改
+    pub(crate) fn reapply_runtime(&mut self, values: Vec<(String, Value)>) -> Vec<String> {
+        let mut applied = Vec::new();
+        for (path, value) in values {
+            if lookup(&self.table, &path) == Some(&value) {
+                continue;
+            }
+            self.set(&path, value, &Origin::Runtime);
+            applied.push(path);
+        }
+        applied
+    }
+
+    /// 保存后，运行时修改的值改记为来自写入的层
+    ///
+    /// `layered` 为保存前各层配置的解析结果，原本来自默认值的字段写入基础配置文件。
+    pub(crate) fn mark_saved(&mut self, layered: &Effective) {
+        for (path, origin) in &mut self.origins {
+            if *origin == Origin::Runtime {
+                *origin = match layered.origin(path) {
+                    Some(Origin::Default) | None => Origin::File(self.base.clone()),
+                    Some(layer) => layer.clone(),
+                };
+            }
+        }
+    }
+}
+
+impl fmt::Display for Effective {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (section, value) in &self.table {
+            let Value::Table(fields) = value else {
+                continue;
+            };
+            writeln!(f, "[{}]", section)?;
+            let mut leaves = Vec::new();
+            flatten(fields, "", &mut leaves);
+            for (key, value) in leaves {
+                let origin = self
+                    .origins
+                    .get(&format!("{}.{}", section, key))
+                    .map(ToString::to_string)
+                    .unwrap_or_default();
+                writeln!(f, "{:<40} # {}", format!("{} = {}", key, value), origin)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn merge_table(
+    dst: &mut Table,
+    src: Table,
+    prefix: &str,
+    origin: &Origin,
+    origins: &mut BTreeMap<String, Origin>,
+) {
+    for (key, value) in src {
+        let path = join(prefix, &key);
+        match (dst.get_mut(&key), value) {
+            (Some(Value::Table(dst)), Value::Table(src)) => {
+                merge_table(dst, src, &path, origin, origins)
+            }
+            (_, value) => {
+                // 整体替换时清除旧的子项来源
+                let child_prefix = format!("{}.", path);
+                origins.retain(|key, _| !key.starts_with(&child_prefix));
+                record(&value, &path, origin, origins);
+                dst.insert(key, value);
+            }
+        }
+    }
+}
+
+fn record(value: &Value, path: &str, origin: &Origin, origins: &mut BTreeMap<String, Origin>) {
+    match value {
+        Value::Table(table) => {
+            for (key, value) in table {
+                record(value, &join(path, key), origin, origins);
+            }
+        }
+        _ => {
+            origins.insert(path.to_string(), origin.clone());
+        }
+    }
+}
+
+/// 按 `section.field` 形式的路径查找值
+fn lookup<'a>(table: &'a Table, path: &str) -> Option<&'a Value> {
+    let mut keys = path.split('.');
+    let mut value = table.get(keys.next()?)?;
+    for key in keys {
+        value = value.get(key)?;
+    }
+    Some(value)
+}
+
+fn flatten<'a>(table: &'a Table, prefix: &str, leaves: &mut Vec<(String, &'a Value)>) {
+    for (key, value) in table {
+        match value {
+            Value::Table(table) => flatten(table, &join(prefix, key), leaves),
+            _ => leaves.push((join(prefix, key), value)),
+        }
+    }
+}
+
+/// 将环境变量或命令行中的字符串解析为 TOML 值，无法解析时视为字符串
+fn parse_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/// 将 `QUASAR_<SECTION>_<FIELD>` 形式的环境变量名转换为 `section.field`，
+/// 段名不在配置中的变量（如 `QUASAR_CONFIG_PATH`）返回 `None`
+fn env_key(name: &str, table: &Table) -> Option<String> {
+    let (section, field) = name.strip_prefix(ENV_PREFIX)?.split_once('_')?;
+    let section = section.to_lowercase();
+    if field.is_empty() || !matches!(table.get(&section), Some(Value::Table(_))) {
+        return None;
+    }
+    Some(format!("{}.{}", section, field.to_lowercase()))
+}
+
+/// 解析以逗号分隔的配置档列表
+fn split_profiles(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// 按 `options.profiles` 的顺序取出基础配置文件中定义的配置档
+fn select_profiles(
+    profiles: Option<Value>,
+    options: &LoadOptions,
+    effective: &mut Effective,
+) -> Result<Vec<(String, Table)>, ConfigError> {
+    let mut defined = match profiles {
+        Some(Value::Table(table)) => table,
+        Some(value) => {
+            return Err(ConfigError::Validation(vec![FieldError {
+                field: "profiles".to_string(),
+                message: format!("应为表，实际为{}", value),
+            }]))
+        }
+        None => Table::new(),
+    };
+    effective.profiles = defined.keys().cloned().collect();
+
+    let mut selected = Vec::new();
+    for name in &options.profiles {
+        match defined.remove(name) {
+            Some(Value::Table(table)) => selected.push((name.clone(), table)),
+            Some(value) => {
+                return Err(ConfigError::Validation(vec![FieldError {
+                    field: format!("profiles.{}", name),
+                    message: format!("应为表，实际为{}", value),
+                }]))
+            }
+            None => {
+                return Err(ConfigError::UnknownProfile {
+                    name: name.clone(),
+                    available: effective.profiles.clone(),
+                })
+            }
+        }
+    }
+    Ok(selected)
+}
+
+/// 读取配置文件的内容
+pub(crate) fn read_file(path: &Path) -> Result<String, ConfigError> {
+    std::fs::read_to_string(path).map_err(|source| match source.kind() {
+        io::ErrorKind::InvalidData => ConfigError::InvalidUtf8 {
+            path: path.to_path_buf(),
+        },
+        _ => ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        },
+    })
+}
+
+/// 按 [`LoadOptions`] 依次合并各层配置
+pub(crate) fn resolve(options: &LoadOptions) -> Result<Effective, ConfigError> {
+    let mut effective = Effective::default();
+    let defaults = Value::try_from(ConfigInner::default())
+        .map_err(ConfigError::Serialize)?
+        .try_into()
+        .expect("默认配置序列化的结果应当是表");
+    effective.merge(defaults, &Origin::Default);
+
+    let base = crate::find_config(options.base.as_deref())?;
+    let (mut table, version) = migrate::load_file(&base, options, 0)?;
+    let profiles = select_profiles(table.remove("profiles"), options, &mut effective)?;
+    effective.merge(table, &Origin::File(base.clone()));
+    effective.base = base;
+
+    for (name, profile) in profiles {
+        effective.merge(profile, &Origin::Profile(name));
+    }
+
+    if let Some(path) = &options.overlay {
+        // 覆盖文件缺少版本号时视为与基础配置文件相同
+        let (overlay, _) = migrate::load_file(path, options, version)?;
+        effective.merge(overlay, &Origin::Overlay(path.clone()));
+    }
+
+    for (name, raw) in &options.env {
+        if let Some(key) = env_key(name, &effective.table) {
+            effective.set(&key, parse_value(raw), &Origin::Env(name.clone()));
+        }
+    }
+
+    for (key, raw) in &options.overrides {
+        effective.set(key, parse_value(raw), &Origin::Cli);
+    }
+
+    Ok(effective)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_temp(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("quasar_{}_{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_parse_value() {
+        assert_eq!(parse_value("12"), Value::Integer(12));
+        assert_eq!(parse_value("1.5"), Value::Float(1.5));
+        assert_eq!(parse_value("true"), Value::Boolean(true));
+        assert_eq!(parse_value("frame"), Value::String("frame".into()));
+        assert_eq!(parse_value("\"frame\""), Value::String("frame".into()));
+    }
+
+    #[test]
+    fn test_env_key() {
+        let table: Table = toml::from_str("[camera]\n[gui]").unwrap();
+        assert_eq!(
+            env_key("QUASAR_CAMERA_EXPOSURE_TIME", &table).as_deref(),
+            Some("camera.exposure_time")
+        );
+        // 不属于任何配置段的变量被忽略
+        assert_eq!(env_key("QUASAR_CONFIG_PATH", &table), None);
+        assert_eq!(env_key("QUASAR_OVERLAY_PATH", &table), None);
+        assert_eq!(env_key("QUASAR_CAMERA", &table), None);
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let mut options = LoadOptions::default();
+        let rest = options
+            .parse_args(
+                [
+                    "--set",
+                    "camera.gain=12",
+                    "--overlay",
+                    "hero.toml",
+                    "--backups",
+                    "5",
+                    "--history",
+                    "16",
+                    "--profile",
+                    "hero, red",
+                    "--profile",
+                    "night",
+                    "--print-config",
+                ]
+                .map(String::from),
+            )
+            .unwrap();
+        assert_eq!(options.overrides, vec![("camera.gain".into(), "12".into())]);
+        assert_eq!(options.overlay, Some(PathBuf::from("hero.toml")));
+        assert_eq!(options.backups, 5);
+        assert_eq!(options.history, 16);
+        assert_eq!(options.profiles, ["hero", "red", "night"]);
+        assert_eq!(rest, vec!["--print-config".to_string()]);
+
+        assert!(LoadOptions::default()
+            .parse_args(["--set", "camera.gain"].map(String::from))
+            .is_err());
+        assert!(LoadOptions::default()
+            .parse_args(["--overlay"].map(String::from))
+            .is_err());
+        assert!(LoadOptions::default()
+            .parse_args(["--backups", "-1"].map(String::from))
+            .is_err());
+    }
+
+    #[test]
+    fn test_resolve_layers() {
+        let base = write_temp(
+            "layer_base.toml",
+            "[camera]\nexposure_time = 3000.0\ngain = 5.0\nwidth = 1280\n",
+        );
+        let overlay = write_temp("layer_overlay.toml", "[camera]\ngain = 8.0\nwidth = 640\n");
+        let options = LoadOptions {
+            base: Some(base.clone()),
+            overlay: Some(overlay.clone()),
+            env: vec![("QUASAR_CAMERA_WIDTH".into(), "800".into())],
+            overrides: vec![("camera.gain".into(), "12".into())],
+            ..Default::default()
+        };
+        let effective = resolve(&options).unwrap();
+        let camera = effective.table["camera"].as_table().unwrap();
+
+        // 文件中缺失的字段使用默认值
+        assert_eq!(effective.origin("camera.height"), Some(&Origin::Default));
+        assert_eq!(camera["exposure_time"].as_float(), Some(3000.0));
+        assert_eq!(
+            effective.origin("camera.exposure_time"),
+            Some(&Origin::File(base.clone()))
+        );
+        assert_eq!(camera["width"].as_integer(), Some(800));
+        assert_eq!(
+            effective.origin("camera.width"),
+            Some(&Origin::Env("QUASAR_CAMERA_WIDTH".into()))
+        );
+        assert_eq!(camera["gain"].as_integer(), Some(12));
+        assert_eq!(effective.origin("camera.gain"), Some(&Origin::Cli));
+
+        let inner = ConfigInner::from_table(&effective.table).unwrap();
+        let camera = inner.camera.load();
+        assert_eq!(camera.gain, 12.0);
+        assert_eq!(camera.width, 800);
+        assert_eq!(camera.height, 1080);
+
+        std::fs::remove_file(base).unwrap();
+        std::fs::remove_file(overlay).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_profiles() {
+        let base = write_temp(
+            "layer_profiles.toml",
+            "[camera]\ngain = 5.0\n\n[profiles.hero.camera]\ngain = 8.0\nwidth = 1280\n\n[profiles.red.camera]\ngain = 10.0\n",
+        );
+        let mut options = LoadOptions {
+            base: Some(base.clone()),
+            profiles: vec!["hero".into(), "red".into()],
+            ..Default::default()
+        };
+        let effective = resolve(&options).unwrap();
+        let camera = effective.table["camera"].as_table().unwrap();
+        assert!(!effective.table.contains_key("profiles"));
+        assert_eq!(effective.profiles, ["hero", "red"]);
+        // 后启用的配置档覆盖先启用的配置档
+        assert_eq!(camera["gain"].as_float(), Some(10.0));
+        assert_eq!(
+            effective.origin("camera.gain"),
+            Some(&Origin::Profile("red".into()))
+        );
+        assert_eq!(camera["width"].as_integer(), Some(1280));
+
+        options.profiles = vec!["sentry".into()];
+        assert!(matches!(
+            resolve(&options),
+            Err(ConfigError::UnknownProfile { name, .. }) if name == "sentry"
+        ));
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let missing = std::env::temp_dir().join("quasar_missing_dir");
+        let options = LoadOptions {
+            base: Some(missing.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            resolve(&options),
+            Err(ConfigError::NotFound { searched }) if searched.contains(&missing.join("Param.toml"))
+        ));
+
+        let base = write_temp("layer_invalid_utf8.toml", "");
+        std::fs::write(&base, b"[camera]\ngain = \"\xff\"\n").unwrap();
+        let options = LoadOptions {
+            base: Some(base.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            resolve(&options),
+            Err(ConfigError::InvalidUtf8 { .. })
+        ));
+
+        std::fs::write(&base, "[camera]\ngain = 1.0\nwidth = \n").unwrap();
+        assert!(matches!(
+            resolve(&options),
+            Err(ConfigError::Parse {
+                line: 3,
+                column: 9,
+                ..
+            })
+        ));
+        std::fs::remove_file(base).unwrap();
+    }
+}
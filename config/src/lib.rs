@@ -4,68 +4,456 @@
 //! # 配置文件搜索顺序
 //!
 //! 配置文件 `Param.toml` 按以下顺序搜索：
-//! 1. 环境变量 `QUASAR_CONFIG_PATH` 指定的路径。
-//! 2. 项目根目录（由环境变量 `CARGO_MANIFEST_DIR` 确定）。
-//! 3. 命令行的当前工作目录（pwd）。
+//! 1. 命令行参数 `--config` 指定的路径（通过 [`LoadOptions::parse_args`] 解析）。
+//! 2. 环境变量 `QUASAR_CONFIG_PATH` 指定的路径。
+//! 3. 项目根目录（由环境变量 `CARGO_MANIFEST_DIR` 确定）。
+//! 4. 命令行的当前工作目录（pwd）。
+//!
+//! # 分层配置
+//!
+//! 生效的配置由内置默认值、`Param.toml`、可选的覆盖文件、`QUASAR_*` 环境变量与命令行 `--set`
+//! 依次合并而成，详见 [`layer`] 模块。每个值的来源记录在 [`Effective`] 中，可通过
+//! `CONFIG.effective()` 打印。
 //!
 //! # 结构体
 //!
 //! - `Config`: 一个 `OnceLock<ConfigInner>` 的包装器，用于提供对配置的全局访问。
-//! - `ConfigInner`: 持有实际配置数据的内部结构，包括：
+//! - `ConfigInner`: 持有实际配置数据的内部结构，每个配置段存放在一个 [`Slot`] 中，包括：
 //!   - `camera`: 相机设置的配置。
 //!   - `detect`: 检测设置的配置。
 //!   - `track`: 跟踪设置的配置。
 //!   - `robot`: 机器人设置的配置。
-//! - `Camera`: 相机的配置设置，例如曝光和增益。
+//! - `Camera`: 相机的配置设置，例如曝光、增益、ROI、内参与畸变校正方式。
 //! - `Detect`: 检测的配置设置（当前为空）。
 //! - `Track`: 跟踪的配置设置（当前为空）。
 //! - `Robot`: 机器人的配置设置（当前为空）。
 //!
 //! # 函数
 //!
-//! - `find_config() -> Option<(File, PathBuf)>`: 在指定路径中搜索配置文件，如果找到则返回文件句柄和路径。
-//! - `load_config() -> ConfigInner`: 从 `Param.toml` 文件加载配置并返回 `ConfigInner` 结构。如果文件未找到或无法读取，则会引发 panic。
-//! - `save_config()`: 将当前配置保存到 `Param.toml` 文件。如果文件无法写入，则会引发 panic。
+//! - `find_config(Option<&Path>) -> Result<PathBuf, ConfigError>`: 在指定路径中搜索配置文件，未找到时返回已查找的全部路径。
+//! - `load_config(&LoadOptions) -> Result<(ConfigInner, Effective), ConfigError>`: 合并各层配置并返回 `ConfigInner` 结构，不影响全局配置。
+//! - `json_schema() -> serde_json::Value`: 由各配置段的字段声明生成 `Param.toml` 的 JSON Schema，
+//!   也可通过命令行子命令 `schema` 打印。
+//! - `try_save_config() -> Result<(), ConfigError>`: 将运行时修改保存到提供其取值的配置文件（基础配置、配置档或覆盖文件），保留文件中的注释与格式，
+//!   以原子方式替换原文件并保留带时间戳的备份（数量由 `--backups` 或 `QUASAR_CONFIG_BACKUPS` 指定，默认 3 个）。
+//! - `save_config()`: `try_save_config` 的包装，失败时引发 panic。
+//!
+//! # 错误处理
+//!
+//! 所有加载与保存操作均有返回 [`ConfigError`] 的版本（`Config::try_init`、`Config::try_get`、
+//! `Config::reload`、`try_save_config`）。`Config::init`、`Config::get`、`save_config` 以及
+//! `Deref` 访问仅是对应版本的包装，出错时引发 panic，适用于程序启动阶段。
+//!
+//! # 校验
+//!
+//! 各配置段通过 [`schema`] 模块中的 `config_section!` 声明字段的类型、单位、取值范围与是否必填。
+//! 加载、重新加载以及通过 `CONFIG.update` 在运行时修改配置时都会进行校验，全部错误一并报告在
+//! [`ConfigError::Validation`] 中。未知字段默认仅输出警告，可通过 `LoadOptions::unknown_keys`
+//! 或命令行参数 `--deny-unknown-keys` 改为报错。
+//!
+//! # 配置档
+//!
+//! `Param.toml` 中可以定义 `[profiles.<name>]` 形式的配置档（如按兵种区分的 `hero`、按队伍颜色
+//! 区分的 `red`），启用后按顺序叠加在基础配置之上。启动时通过命令行参数 `--profile <name>`
+//! （可重复）或环境变量 `QUASAR_PROFILE=hero,red` 选择，运行时可通过 `CONFIG.set_profiles`
+//! 切换，发生变化的配置段会通知订阅者，运行时所做且尚未保存的修改在切换后保留。
+//!
+//! # 修改历史
+//!
+//! 运行时的每一次修改都按字段记录来源与新旧取值，可通过 `CONFIG.history()` 查看，并通过
+//! `CONFIG.revert(seq)` 撤销到任意一条记录之后的状态，详见 [`ChangeRecord`]。
+//!
+//! # 版本迁移
+//!
+//! `Param.toml` 顶层的 `version` 键记录文件格式的版本。加载旧版本的配置文件时按
+//! [`MIGRATIONS`] 逐版本升级并在日志中列出修改，指定 `--write-migrated` 时将结果写回原文件。
+//!
+//! # 热重载
+//!
+//! `CONFIG.watch(interval)` 启动后台线程监视配置文件，文件变化时重新解析并替换发生变化的配置段，
+//! 再通过 `CONFIG.subscribe()` 返回的通道向订阅者发送 [`ConfigChange`]：
+//!
+//! ```rust,no_run
+//! # use config::{Section, CONFIG};
+//! # use std::time::Duration;
+//! let _watcher = CONFIG.watch(Duration::from_millis(500));
+//! let changes = CONFIG.subscribe();
+//! for change in changes {
+//!     if change.section == Section::Camera {
+//!         // 重新配置相机
+//!     }
+//! }
+//! ```
 //!
 //! # 用法
 //!
-//! 要访问配置，请使用全局 `CONFIG` 常量。例如：
+//! 在程序入口处以命令行参数初始化全局配置（可选，未初始化时首次访问将仅使用环境变量加载）：
+//!
+//! ```rust,no_run
+//! use config::{LoadOptions, CONFIG};
+//!
+//! let mut options = LoadOptions::from_env();
+//! let _rest = options.parse_args(std::env::args().skip(1)).unwrap();
+//! CONFIG.init(options);
+//! println!("{}", CONFIG.effective());
+//! ```
+//!
+//! 要访问配置，请使用全局 `CONFIG` 常量。各配置段以无锁快照的形式存放（见 [`Slot`]），
+//! 读取时获得不可变的 `Arc`：
 //!
-//! ```rust
-//! let camera_config = CONFIG.camera.lock().unwrap();
+//! ```rust,no_run
+//! # use config::CONFIG;
+//! let camera_config = CONFIG.camera.load();
 //! println!("Camera exposure auto: {}", camera_config.exposure_auto);
 //! ```
 //!
-//! 在进行更改后保存配置：
+//! 通过 `CONFIG.update` 进行更改后保存配置：
 //!
-//! ```rust
-//! {
-//!     let mut camera_config = CONFIG.camera.lock().unwrap();
-//!     camera_config.exposure_auto = false;
-//! }
+//! ```rust,no_run
+//! # use config::{save_config, Camera, CONFIG};
+//! CONFIG
+//!     .update(|camera: &mut Camera| camera.exposure_auto = false)
+//!     .unwrap();
 //! save_config();
 //! ```
+mod error;
+mod history;
+mod json_schema;
+pub mod layer;
+mod migrate;
+#[macro_use]
+pub mod schema;
+mod save;
+mod snapshot;
+mod watch;
+
 use std::{
-    fs::File,
-    io::Read,
+    fmt,
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Mutex, OnceLock},
 };
 
-use serde::{Deserialize, Serialize};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use history::{FieldChange, History};
+use log::info;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub use error::{ConfigError, FieldError};
+pub use history::{ChangeRecord, ChangeSource, DEFAULT_HISTORY};
+pub use json_schema::json_schema;
+pub use layer::{Effective, LoadOptions, Origin, DEFAULT_BACKUPS};
+pub use migrate::{Migration, Step, CURRENT_VERSION, MIGRATIONS};
+pub use schema::{FieldSpec, FieldType, Kind, SectionSchema, UnknownKeys};
+pub use snapshot::{Reader, Slot};
+pub use watch::Watcher;
 
 pub static CONFIG: Config = Config::new();
 
-pub struct Config(OnceLock<ConfigInner>);
+pub struct Config {
+    inner: OnceLock<ConfigInner>,
+    /// 初始化时使用的加载选项，重新加载时沿用
+    options: Mutex<Option<LoadOptions>>,
+    effective: Mutex<Option<Effective>>,
+    subscribers: Mutex<Vec<Sender<ConfigChange>>>,
+    history: Mutex<History>,
+}
 
 impl Config {
     const fn new() -> Self {
-        Self(OnceLock::new())
+        Self {
+            inner: OnceLock::new(),
+            options: Mutex::new(None),
+            effective: Mutex::new(None),
+            subscribers: Mutex::new(Vec::new()),
+            history: Mutex::new(History::new()),
+        }
     }
 
+    /// 以指定的加载选项初始化全局配置，须在首次访问配置之前调用
+    pub fn try_init(&self, options: LoadOptions) -> Result<(), ConfigError> {
+        if self.inner.get().is_some() {
+            return Err(ConfigError::AlreadyInitialized);
+        }
+        self.try_load(options).map(|_| ())
+    }
+
+    /// [`Config::try_init`] 的包装，失败时引发 panic
+    pub fn init(&self, options: LoadOptions) {
+        self.try_init(options)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// 获取全局配置，未初始化时使用环境变量中的加载选项进行加载
+    pub fn try_get(&self) -> Result<&ConfigInner, ConfigError> {
+        match self.inner.get() {
+            Some(inner) => Ok(inner),
+            None => self.try_load(LoadOptions::from_env()),
+        }
+    }
+
+    /// [`Config::try_get`] 的包装，失败时引发 panic
     pub fn get(&self) -> &ConfigInner {
-        self.0.get_or_init(|| load_config())
+        self.try_get().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// 获取合并后生效的配置及每个值的来源
+    pub fn effective(&self) -> Effective {
+        self.get();
+        self.effective
+            .lock()
+            .expect("锁中毒")
+            .clone()
+            .expect("全局配置未初始化")
+    }
+
+    fn try_load(&self, options: LoadOptions) -> Result<&ConfigInner, ConfigError> {
+        let (inner, effective) = load_config(&options)?;
+        // 仅在成功写入全局配置时记录加载选项与来源，并发加载时以先完成者为准
+        Ok(self.inner.get_or_init(move || {
+            *self.effective.lock().expect("锁中毒") = Some(effective);
+            self.history
+                .lock()
+                .expect("锁中毒")
+                .set_capacity(options.history);
+            *self.options.lock().expect("锁中毒") = Some(options);
+            inner
+        }))
+    }
+
+    /// 订阅配置变化，每当某个配置段被重新加载替换时，返回的通道会收到一条 [`ConfigChange`]
+    pub fn subscribe(&self) -> Receiver<ConfigChange> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().expect("锁中毒").push(sender);
+        receiver
+    }
+
+    /// 向所有订阅者发送配置变化，并移除已断开的订阅者
+    fn notify(&self, change: ConfigChange) {
+        self.subscribers
+            .lock()
+            .expect("锁中毒")
+            .retain(|sender| sender.send(change).is_ok());
+    }
+
+    /// 在运行时修改某个配置段
+    ///
+    /// 修改后的值须通过字段约束校验才会生效，生效后发布新的快照、记录修改历史并通知订阅者；
+    /// 校验失败时保留原值。修改来源记为 [`ChangeSource::Api`]。
+    ///
+    /// ```rust,no_run
+    /// # use config::{Camera, CONFIG};
+    /// CONFIG.update(|camera: &mut Camera| camera.gain = 12.0).unwrap();
+    /// ```
+    pub fn update<S, F>(&self, f: F) -> Result<(), ConfigError>
+    where
+        S: SectionSchema,
+        F: FnOnce(&mut S),
+    {
+        self.update_from(ChangeSource::Api, f)
+    }
+
+    /// 同 [`Config::update`]，并指定记录在修改历史中的来源
+    pub fn update_from<S, F>(&self, source: ChangeSource, f: F) -> Result<(), ConfigError>
+    where
+        S: SectionSchema,
+        F: FnOnce(&mut S),
+    {
+        let writer = S::slot(self.try_get()?).write();
+        let current = writer.current();
+        let mut value = S::clone(&current);
+        f(&mut value);
+        if value == *current {
+            return Ok(());
+        }
+
+        let table = to_table(&value)?;
+        let mut errors = Vec::new();
+        schema::check_section(S::SECTION, &table, None, UnknownKeys::Deny, &mut errors);
+        if !errors.is_empty() {
+            return Err(ConfigError::Validation(errors));
+        }
+
+        let changes = history::diff(S::SECTION, &to_table(&*current)?, &table);
+        writer.publish(value);
+        // 持有写锁期间记录历史与来源，保证历史的顺序与发布的顺序一致，且重新加载时不会遗漏
+        self.record(changes, source);
+        if let Some(effective) = self.effective.lock().expect("锁中毒").as_mut() {
+            effective.apply(S::SECTION.name(), table, &Origin::Runtime);
+        }
+        drop(writer);
+        self.notify(ConfigChange {
+            section: S::SECTION,
+        });
+        Ok(())
+    }
+
+    /// 在运行时按名称修改单个字段，校验、记录与通知同 [`Config::update`]
+    ///
+    /// 适用于仅在运行时才知道字段名称的场合，如可视化界面远程修改配置。
+    ///
+    /// ```rust,no_run
+    /// # use config::{ChangeSource, Section, CONFIG};
+    /// CONFIG
+    ///     .set_field(Section::Camera, "gain", toml::Value::Float(12.0), ChangeSource::Websocket)
+    ///     .unwrap();
+    /// ```
+    pub fn set_field(
+        &self,
+        section: Section,
+        field: &str,
+        value: toml::Value,
+        source: ChangeSource,
+    ) -> Result<(), ConfigError> {
+        let fields = toml::Table::from_iter([(field.to_string(), value)]);
+        self.set_fields(section, fields, source)
+    }
+
+    /// 在运行时按名称修改同一配置段的多个字段
+    pub(crate) fn set_fields(
+        &self,
+        section: Section,
+        fields: toml::Table,
+        source: ChangeSource,
+    ) -> Result<(), ConfigError> {
+        match section {
+            Section::Camera => self.set_fields_of::<Camera>(fields, source),
+            Section::Detect => self.set_fields_of::<Detect>(fields, source),
+            Section::Track => self.set_fields_of::<Track>(fields, source),
+            Section::Robot => self.set_fields_of::<Robot>(fields, source),
+            Section::Gui => self.set_fields_of::<GUI>(fields, source),
+            Section::Rt => self.set_fields_of::<Rt>(fields, source),
+            Section::Log => self.set_fields_of::<Log>(fields, source),
+        }
+    }
+
+    fn set_fields_of<S: SectionSchema>(
+        &self,
+        fields: toml::Table,
+        source: ChangeSource,
+    ) -> Result<(), ConfigError> {
+        let specs = S::fields();
+        let unknown: Vec<_> = fields
+            .keys()
+            .filter(|field| !specs.iter().any(|spec| spec.name == *field))
+            .map(|field| FieldError {
+                field: format!("{}.{}", S::SECTION, field),
+                message: "未知字段".to_string(),
+            })
+            .collect();
+        if !unknown.is_empty() {
+            return Err(ConfigError::Validation(unknown));
+        }
+
+        // 在持有写锁的闭包内替换字段，避免覆盖其他线程同时进行的修改
+        let mut result = Ok(());
+        self.update_from(source, |current: &mut S| {
+            let mut table = to_table(&*current).expect("配置段应当可以序列化");
+            table.extend(fields);
+            match toml::Value::Table(table).try_into() {
+                Ok(new) => *current = new,
+                Err(err) => {
+                    result = Err(ConfigError::Validation(vec![ConfigError::section(
+                        S::SECTION.name(),
+                        err,
+                    )]))
+                }
+            }
+        })?;
+        result
+    }
+
+    /// 当前使用的加载选项，未初始化时为默认选项
+    pub(crate) fn options(&self) -> LoadOptions {
+        self.options
+            .lock()
+            .expect("锁中毒")
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// 按初始化时的加载选项重新解析各层配置，替换发生变化的配置段并通知订阅者
+    ///
+    /// 运行时修改且尚未保存的值保留，叠加在重新解析的各层配置之上，文件中对这些字段的修改在
+    /// 运行时的修改被保存之前不会生效。解析失败时保留原配置不变。
+    ///
+    /// # 返回值
+    /// 发生变化的配置段
+    pub fn reload(&self) -> Result<Vec<Section>, ConfigError> {
+        self.try_get()?;
+        self.reload_with(self.options(), ChangeSource::File)
+    }
+
+    /// 当前启用的配置档，按叠加顺序排列
+    pub fn profiles(&self) -> Vec<String> {
+        self.get();
+        self.options().profiles
+    }
+
+    /// 在运行时切换配置档，替换发生变化的配置段并通知订阅者
+    ///
+    /// 配置档按给定顺序叠加在基础配置之上，传入空列表时恢复为基础配置。切换失败（如配置档不存在）
+    /// 时保留原配置与原配置档不变。运行时通过 [`Config::update`] 所做且尚未保存的修改保留，
+    /// 叠加在新的配置档之上。
+    ///
+    /// ```rust,no_run
+    /// # use config::CONFIG;
+    /// CONFIG.set_profiles(&["hero", "blue"]).unwrap();
+    /// ```
+    ///
+    /// # 返回值
+    /// 发生变化的配置段
+    pub fn set_profiles<S: AsRef<str>>(&self, profiles: &[S]) -> Result<Vec<Section>, ConfigError> {
+        self.try_get()?;
+        let mut options = self.options();
+        options.profiles = profiles
+            .iter()
+            .map(|name| name.as_ref().to_string())
+            .collect();
+        let changed = self.reload_with(options, ChangeSource::Profile)?;
+        info!(
+            "[配置] 已切换到配置档 [{}]，发生变化的配置段：{:?}",
+            self.profiles().join(", "),
+            changed
+        );
+        Ok(changed)
+    }
+
+    /// 按给定的加载选项重新解析各层配置，成功后记录加载选项
+    fn reload_with(
+        &self,
+        options: LoadOptions,
+        source: ChangeSource,
+    ) -> Result<Vec<Section>, ConfigError> {
+        let current = self.try_get()?;
+        let mut kept = Vec::new();
+        // 运行时修改在持有配置段写锁期间记入来源，因此在持有全部写锁期间读取运行时修改并替换来源，
+        // 不会遗漏与重新加载并发的修改
+        let changes = current.replace_with(|_| {
+            let mut slot = self.effective.lock().expect("锁中毒");
+            // 运行时修改且尚未保存的值叠加在重新解析的各层配置之上，不因重新加载而丢失
+            let runtime = slot
+                .as_ref()
+                .map(Effective::runtime_values)
+                .unwrap_or_default();
+            let mut effective = layer::resolve(&options)?;
+            kept = effective.reapply_runtime(runtime);
+            schema::validate(&effective, options.unknown_keys)?;
+            let inner = ConfigInner::from_table(&effective.table)?;
+            *slot = Some(effective);
+            Ok(inner)
+        })?;
+        if !kept.is_empty() {
+            info!("[配置] 保留尚未保存的运行时修改：{}", kept.join(", "));
+        }
+        let mut changed: Vec<_> = changes.iter().map(|change| change.section).collect();
+        changed.dedup();
+        self.record(changes, source);
+        *self.options.lock().expect("锁中毒") = Some(options);
+        for &section in &changed {
+            self.notify(ConfigChange { section });
+        }
+        Ok(changed)
     }
 }
 
@@ -79,128 +467,554 @@ impl Deref for Config {
 
 impl DerefMut for Config {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        self.0.get_mut().expect("全局配置未初始化")
+        self.inner.get_mut().expect("全局配置未初始化")
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ConfigInner {
-    pub camera: Mutex<Camera>,
-    pub detect: Mutex<Detect>,
-    pub track: Mutex<Track>,
-    pub robot: Mutex<Robot>,
-    pub gui: Mutex<GUI>,
+    pub camera: Slot<Camera>,
+    pub detect: Slot<Detect>,
+    pub track: Slot<Track>,
+    pub robot: Slot<Robot>,
+    pub gui: Slot<GUI>,
+    pub rt: Slot<Rt>,
+    pub log: Slot<Log>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
-pub struct Camera {
-    pub exposure_auto: bool,
-    pub gain_auto: bool,
-    pub exposure_time: f32,
-    pub gain: f32,
-    pub width: u32,
-    pub height: u32,
+impl ConfigInner {
+    /// 由合并后的配置表逐段反序列化，收集全部出错的字段
+    pub fn from_table(table: &toml::Table) -> Result<Self, ConfigError> {
+        fn section<T>(table: &toml::Table, name: &str, errors: &mut Vec<FieldError>) -> Slot<T>
+        where
+            T: DeserializeOwned + Default,
+        {
+            let value = match table.get(name).cloned().map(toml::Value::try_into) {
+                Some(Ok(value)) => value,
+                Some(Err(err)) => {
+                    errors.push(ConfigError::section(name, err));
+                    T::default()
+                }
+                None => T::default(),
+            };
+            Slot::new(value)
+        }
+
+        let mut errors = Vec::new();
+        let inner = ConfigInner {
+            camera: section(table, Section::Camera.name(), &mut errors),
+            detect: section(table, Section::Detect.name(), &mut errors),
+            track: section(table, Section::Track.name(), &mut errors),
+            robot: section(table, Section::Robot.name(), &mut errors),
+            gui: section(table, Section::Gui.name(), &mut errors),
+            rt: section(table, Section::Rt.name(), &mut errors),
+            log: section(table, Section::Log.name(), &mut errors),
+        };
+        if errors.is_empty() {
+            Ok(inner)
+        } else {
+            Err(ConfigError::Validation(errors))
+        }
+    }
+
+    /// 按固定顺序取得全部配置段的写锁，由 `build` 根据当前配置表生成新的配置，再替换其中与
+    /// 当前值不同的配置段
+    ///
+    /// 持有全部写锁期间生成新配置，避免与其他写者交错；`build` 返回错误时不做任何修改。各配置段
+    /// 分别发布，同时读取多个配置段的读者可能短暂看到部分替换后的结果。
+    ///
+    /// # 返回值
+    /// 发生变化的字段，按配置段的顺序排列
+    fn replace_with(
+        &self,
+        build: impl FnOnce(toml::Table) -> Result<ConfigInner, ConfigError>,
+    ) -> Result<Vec<FieldChange>, ConfigError> {
+        let camera = self.camera.write();
+        let detect = self.detect.write();
+        let track = self.track.write();
+        let robot = self.robot.write();
+        let gui = self.gui.write();
+        let rt = self.rt.write();
+        let log = self.log.write();
+        let new = build(to_table(self)?)?;
+
+        let mut changes = Vec::new();
+        macro_rules! replace {
+            ($writer:ident, $section:expr) => {
+                let value = new.$writer.load();
+                let current = $writer.current();
+                if *current != *value {
+                    let old = to_table(&*current).expect("配置段应当可以序列化");
+                    let table = to_table(&*value).expect("配置段应当可以序列化");
+                    changes.extend(history::diff($section, &old, &table));
+                    $writer.publish(value);
+                }
+            };
+        }
+        replace!(camera, Section::Camera);
+        replace!(detect, Section::Detect);
+        replace!(track, Section::Track);
+        replace!(robot, Section::Robot);
+        replace!(gui, Section::Gui);
+        replace!(rt, Section::Rt);
+        replace!(log, Section::Log);
+        Ok(changes)
+    }
+
+    /// 全部配置段版本号之和，任一配置段发布新值时都会增大
+    ///
+    /// 可用于每帧廉价地判断配置自上一帧以来是否发生过变化。
+    pub fn generation(&self) -> u64 {
+        self.camera.generation()
+            + self.detect.generation()
+            + self.track.generation()
+            + self.robot.generation()
+            + self.gui.generation()
+            + self.rt.generation()
+            + self.log.generation()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub struct Detect {}
+/// 配置段
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Section {
+    Camera,
+    Detect,
+    Track,
+    Robot,
+    Gui,
+    Rt,
+    Log,
+}
+
+impl Section {
+    pub const ALL: [Section; 7] = [
+        Section::Camera,
+        Section::Detect,
+        Section::Track,
+        Section::Robot,
+        Section::Gui,
+        Section::Rt,
+        Section::Log,
+    ];
+
+    /// 由配置段在 `Param.toml` 中的名称查找配置段
+    pub fn from_name(name: &str) -> Option<Section> {
+        Section::ALL
+            .into_iter()
+            .find(|section| section.name() == name)
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub struct Track {}
+    /// 配置段的说明
+    pub fn description(&self) -> String {
+        match self {
+            Section::Camera => Camera::description(),
+            Section::Detect => Detect::description(),
+            Section::Track => Track::description(),
+            Section::Robot => Robot::description(),
+            Section::Gui => GUI::description(),
+            Section::Rt => Rt::description(),
+            Section::Log => Log::description(),
+        }
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub struct Robot {}
+    /// 配置段中全部字段的描述与约束
+    pub fn fields(&self) -> Vec<FieldSpec> {
+        match self {
+            Section::Camera => Camera::fields(),
+            Section::Detect => Detect::fields(),
+            Section::Track => Track::fields(),
+            Section::Robot => Robot::fields(),
+            Section::Gui => GUI::fields(),
+            Section::Rt => Rt::fields(),
+            Section::Log => Log::fields(),
+        }
+    }
+
+    /// 配置段在 `Param.toml` 中的名称
+    pub fn name(&self) -> &'static str {
+        match self {
+            Section::Camera => "camera",
+            Section::Detect => "detect",
+            Section::Track => "track",
+            Section::Robot => "robot",
+            Section::Gui => "gui",
+            Section::Rt => "rt",
+            Section::Log => "log",
+        }
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// 配置变化通知
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigChange {
+    /// 被替换的配置段
+    pub section: Section,
+}
+
+/// 相机传感器的水平分辨率，ROI 不能超出此范围
+pub const SENSOR_WIDTH: u32 = 1440;
+/// 相机传感器的垂直分辨率，ROI 不能超出此范围
+pub const SENSOR_HEIGHT: u32 = 1080;
+
+config_section! {
+    Section::Camera => camera;
+    /// 相机配置
+    #[derive(Copy)]
+    pub struct Camera {
+        /// 是否开启自动曝光
+        pub exposure_auto: bool = false,
+        /// 是否开启自动增益
+        pub gain_auto: bool = false,
+        /// 曝光时间，关闭自动曝光时生效
+        pub exposure_time: f32 = 1000.0 => { unit: "us(微秒)", range: 1.0..=1_000_000.0 },
+        /// 增益，关闭自动增益时生效
+        pub gain: f32 = 0.0 => { unit: "dB(分贝)", range: 0.0..=48.0 },
+        /// 图像宽度
+        pub width: u32 = SENSOR_WIDTH => { unit: "px(像素)", range: 8..=SENSOR_WIDTH },
+        /// 图像高度
+        pub height: u32 = SENSOR_HEIGHT => { unit: "px(像素)", range: 8..=SENSOR_HEIGHT },
+        /// ROI水平偏移，与图像宽度之和不能超过传感器宽度
+        pub offset_x: u32 = 0 => { unit: "px(像素)", range: 0..=SENSOR_WIDTH - 8 },
+        /// ROI垂直偏移，与图像高度之和不能超过传感器高度
+        pub offset_y: u32 = 0 => { unit: "px(像素)", range: 0..=SENSOR_HEIGHT - 8 },
+        /// 相机内参矩阵（行优先的 3x3 矩阵），按传感器全分辨率标定
+        pub camera_matrix: [f64; 9] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0] => {
+            required: true,
+        },
+        /// 畸变系数 [k1, k2, p1, p2, k3]
+        pub dist_coeffs: [f64; 5] = [0.0; 5],
+        /// 畸变校正方式，不为 none 时 camera_matrix 须为标定结果而非默认的单位矩阵
+        pub undistort: UndistortMode = UndistortMode::Points,
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub struct GUI {
-    // pub video_fps: u8,
+/// 畸变校正方式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UndistortMode {
+    /// 不进行畸变校正
+    None,
+    /// 对整帧图像进行重映射
+    Frame,
+    /// 仅对检测得到的关键点进行校正
+    #[default]
+    Points,
 }
 
-fn find_config() -> Option<(File, PathBuf)> {
+impl FieldType for UndistortMode {
+    fn kind() -> Kind {
+        Kind::Enum(&["none", "frame", "points"])
+    }
+}
+
+config_section! {
+    Section::Detect => detect;
+    /// 检测配置
+    #[derive(Copy)]
+    pub struct Detect {}
+}
+
+config_section! {
+    Section::Track => track;
+    /// 跟踪配置
+    #[derive(Copy)]
+    pub struct Track {}
+}
+
+config_section! {
+    Section::Robot => robot;
+    /// 机器人配置
+    #[derive(Copy)]
+    pub struct Robot {}
+}
+
+config_section! {
+    Section::Gui => gui;
+    /// 可视化配置
+    #[derive(Copy)]
+    pub struct GUI {
+        // pub video_fps: u8,
+        /// 指标快照推送到客户端的间隔
+        pub metrics_interval: u32 = 1000 => { unit: "ms(毫秒)", range: 100..=60_000 },
+        /// 以 Prometheus 文本格式提供指标的本机 HTTP 端口，0 表示不启用，仅在启动时生效
+        pub prometheus_port: u16 = 0,
+    }
+}
+
+config_section! {
+    Section::Rt => rt;
+    /// 实时调度配置，仅在启动时生效
+    ///
+    /// 核心编号为 -1 时不绑定核心，优先级为 0 时使用普通调度，否则使用该优先级的 `SCHED_FIFO`
+    /// 调度。进程缺少 `CAP_SYS_NICE`、`CAP_IPC_LOCK` 等权限时仅输出警告。
+    #[derive(Copy)]
+    pub struct Rt {
+        /// 是否锁定进程内存，避免缺页导致的延迟抖动
+        pub lock_memory: bool = false,
+        /// 相机线程绑定的 CPU 核心
+        pub camera_core: i32 = -1 => { range: -1..=1023 },
+        /// 相机线程的实时优先级
+        pub camera_priority: i32 = 0 => { range: 0..=99 },
+        /// 检测线程绑定的 CPU 核心
+        pub detect_core: i32 = -1 => { range: -1..=1023 },
+        /// 检测线程的实时优先级
+        pub detect_priority: i32 = 0 => { range: 0..=99 },
+        /// 跟踪线程绑定的 CPU 核心
+        pub track_core: i32 = -1 => { range: -1..=1023 },
+        /// 跟踪线程的实时优先级
+        pub track_priority: i32 = 0 => { range: 0..=99 },
+        /// 可视化线程绑定的 CPU 核心
+        pub gui_core: i32 = -1 => { range: -1..=1023 },
+        /// 可视化线程的实时优先级
+        pub gui_priority: i32 = 0 => { range: 0..=99 },
+    }
+}
+
+config_section! {
+    Section::Log => log;
+    /// 日志配置
+    pub struct Log {
+        /// 过滤规则，格式与 `RUST_LOG` 相同，如 `info,server=debug`，设置了环境变量 `RUST_LOG` 时
+        /// 启动时以环境变量为准
+        pub filter: String = "info".to_string(),
+        /// 日志文件的路径，为空时不写入文件，仅在启动时生效
+        pub file: String = String::new(),
+        /// 单个日志文件的大小上限，超出时轮转
+        pub max_file_size: u32 = 10 => { unit: "MB(兆字节)", range: 1..=1024 },
+        /// 保留的旧日志文件数量
+        pub max_files: u32 = 5 => { range: 0..=100 },
+    }
+}
+
+/// 配置文件的候选路径，按优先级排序
+///
+/// 显式指定路径时仅查找该路径；指定的路径可以是文件，也可以是 `Param.toml` 所在的目录。
+fn candidate_paths(explicit: Option<&Path>) -> Vec<PathBuf> {
+    let with_dir = |path: PathBuf| [path.clone(), path.join("Param.toml")];
+    if let Some(path) = explicit {
+        return with_dir(path.to_path_buf()).into();
+    }
+
+    let mut paths = Vec::new();
     // 从环境变量获取
     if let Ok(env_path) = std::env::var("QUASAR_CONFIG_PATH") {
-        let env_path = PathBuf::from(env_path);
-        if let Ok(fp) = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&env_path)
-        {
-            return Some((fp, env_path));
-        } else if let Ok(fp) = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(env_path.join("Param.toml"))
-        {
-            return Some((fp, env_path.join("Param.toml")));
-        }
+        paths.extend(with_dir(PathBuf::from(env_path)));
     }
-
     // 检查默认路径
     if let Ok(cargo_manifest_dir) = std::env::var("CARGO_MANIFEST_DIR") {
-        let default_path = PathBuf::from(cargo_manifest_dir).join("Param.toml");
-        if let Ok(fp) = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&default_path)
-        {
-            return Some((fp, default_path));
-        }
+        paths.push(PathBuf::from(cargo_manifest_dir).join("Param.toml"));
     }
-
     // 检查当前目录
-    let current_dir = std::env::current_dir().ok()?;
-    let current_config = current_dir.join("Param.toml");
-    std::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&current_config)
-        .ok()
-        .map(|fp| (fp, current_config))
-}
-
-fn load_config() -> ConfigInner {
-    let (mut config, config_path) = find_config().expect(
-        "在查找路径上未找到配置文件Param.toml。有三种解决方案，请任选其一：
-    1. 请设置环境变量QUASAR_CONFIG_PATH，指向Param.toml的绝对路径
-    2. 将Param.toml放置到项目根目录
-    3. 将Param.toml放置到命令行中的当前目录（pwd）",
-    );
-    let mut config_str = String::new();
-    config.read_to_string(&mut config_str).unwrap_or_else(|_| {
-        panic!(
-            "读取指定的Param.toml失败，指定的文件编码包含非合法UTF-8的字节。
-Param.toml的读取路径为：{}，若此路径与你实际指定的路径不符，请详细阅读下方说明。
-Quasar Trajectory对于配置文件的查找路径有三种方案，按优先级依次排序：
-    1. 环境变量QUASAR_CONFIG_PATH所指向的路径
-    2. 项目根目录
-    3. 命令行执行命令的当前目录（pwd）",
-            config_path.display()
-        )
-    });
+    if let Ok(current_dir) = std::env::current_dir() {
+        paths.push(current_dir.join("Param.toml"));
+    }
+    paths
+}
 
-    toml::from_str(&config_str).unwrap_or_else(|err| {
-        panic!(
-            "指定的配置文件“{}”解析失败: {}，请检查此文件的内容是否正确",
-            config_path.display(),
-            err
-        )
-    })
+/// 在候选路径中查找配置文件
+///
+/// # 返回值
+/// 找到的配置文件路径；未找到时返回包含全部已查找路径的 [`ConfigError::NotFound`]
+pub fn find_config(explicit: Option<&Path>) -> Result<PathBuf, ConfigError> {
+    let searched = candidate_paths(explicit);
+    searched
+        .iter()
+        .find(|path| path.is_file())
+        .cloned()
+        .ok_or(ConfigError::NotFound { searched })
 }
 
+/// 将配置段序列化为 TOML 表
+fn to_table<S: Serialize>(value: &S) -> Result<toml::Table, ConfigError> {
+    Ok(toml::Value::try_from(value)
+        .map_err(ConfigError::Serialize)?
+        .try_into()
+        .expect("配置段序列化的结果应当是表"))
+}
+
+/// 合并各层配置并反序列化，不影响全局配置
+pub fn load_config(options: &LoadOptions) -> Result<(ConfigInner, Effective), ConfigError> {
+    let effective = layer::resolve(options)?;
+    schema::validate(&effective, options.unknown_keys)?;
+    let inner = ConfigInner::from_table(&effective.table)?;
+    Ok((inner, effective))
+}
+
+/// 将当前全局配置保存到配置文件，详见 [`Config::save`]
+pub fn try_save_config() -> Result<(), ConfigError> {
+    CONFIG.save()
+}
+
+/// [`try_save_config`] 的包装，失败时引发 panic
 pub fn save_config() {
-    let config = toml::to_string(CONFIG.get()).expect("序列化全局config失败");
-
-    let (_, config_path) = find_config().expect(
-        "在查找路径上未找到配置文件Param.toml。有三种解决方案，请任选其一：
-    1. 请设置环境变量QUASAR_CONFIG_PATH，指向Param.toml的绝对路径
-    2. 将Param.toml放置到项目根目录
-    3. 将Param.toml放置到命令行中的当前目录（pwd）",
-    );
-
-    std::fs::write(&config_path, config).unwrap_or_else(|_| panic!("Param.toml写入失败，写入路径为：{}，若此路径与你实际指定的路径不符，请详细阅读下方说明。
-    Quasar Trajectory对于配置文件的查找路径有三种方案，按优先级依次排序：
-    1. 环境变量QUASAR_CONFIG_PATH所指向的路径
-    2. 项目根目录
-    3. 命令行执行命令的当前目录（pwd）",
-        config_path.display()));
+    try_save_config().unwrap_or_else(|err| panic!("{}", err))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// 写入仅包含 `[camera]` 段的配置文件，必填的内参矩阵会自动补全
+    pub(crate) fn write_camera(path: &Path, fields: &str) {
+        let content = format!(
+            "[camera]\ncamera_matrix = [1800.0, 0.0, 720.0, 0.0, 1800.0, 540.0, 0.0, 0.0, 1.0]\n{}\n",
+            fields
+        );
+        std::fs::write(path, content).unwrap();
+    }
+
+    /// 以临时配置文件初始化一个独立的 `Config`
+    pub(crate) fn temp_config(name: &str, fields: &str) -> &'static Config {
+        let path = std::env::temp_dir().join(format!("quasar_{}_{}", std::process::id(), name));
+        write_camera(&path, fields);
+        let config = Box::leak(Box::new(Config::new()));
+        config.init(LoadOptions {
+            base: Some(path),
+            ..Default::default()
+        });
+        config
+    }
+
+    #[test]
+    fn test_load_validation() {
+        let path =
+            std::env::temp_dir().join(format!("quasar_{}_validation.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[camera]\nexposure_time = -1.0\nwidth = 4096\n[gui]\nfps = 30\n",
+        )
+        .unwrap();
+        let mut options = LoadOptions {
+            base: Some(path.clone()),
+            ..Default::default()
+        };
+        let Err(ConfigError::Validation(errors)) = load_config(&options) else {
+            panic!("应当校验失败");
+        };
+        let fields: Vec<_> = errors.iter().map(|err| err.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            [
+                "camera.exposure_time",
+                "camera.width",
+                "camera.camera_matrix",
+                // 未标定时不能使用默认的畸变校正方式
+                "camera.undistort"
+            ]
+        );
+
+        options.unknown_keys = UnknownKeys::Deny;
+        let Err(ConfigError::Validation(errors)) = load_config(&options) else {
+            panic!("应当校验失败");
+        };
+        assert!(errors.iter().any(|err| err.field == "gui.fps"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_update() {
+        let config = temp_config("update.toml", "gain = 5.0");
+        let changes = config.subscribe();
+
+        config
+            .update(|camera: &mut Camera| camera.gain = 12.0)
+            .unwrap();
+        assert_eq!(config.camera.load().gain, 12.0);
+        assert_eq!(changes.try_recv().unwrap().section, Section::Camera);
+        assert_eq!(
+            config.effective().origin("camera.gain"),
+            Some(&Origin::Runtime)
+        );
+
+        // 校验失败时保留原值且不通知
+        let err = config
+            .update(|camera: &mut Camera| {
+                camera.gain = -3.0;
+                camera.width = 0;
+            })
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::Validation(errors) if errors.len() == 2));
+        assert_eq!(config.camera.load().gain, 12.0);
+        assert!(changes.try_recv().is_err());
+
+        std::fs::remove_file(config.effective().base).unwrap();
+    }
+
+    #[test]
+    fn test_set_field() {
+        let config = temp_config("set_field.toml", "gain = 5.0");
+        // 整数可以赋给浮点数字段
+        config
+            .set_field(
+                Section::Camera,
+                "gain",
+                toml::Value::Integer(12),
+                ChangeSource::Api,
+            )
+            .unwrap();
+        assert_eq!(config.camera.load().gain, 12.0);
+
+        let field_of = |result: Result<(), ConfigError>| match result {
+            Err(ConfigError::Validation(errors)) => errors[0].field.clone(),
+            _ => panic!("应当校验失败"),
+        };
+        let value = toml::Value::String("high".into());
+        assert_eq!(
+            field_of(config.set_field(Section::Camera, "gain", value, ChangeSource::Api)),
+            "camera.gain"
+        );
+        let value = toml::Value::Float(100.0);
+        assert_eq!(
+            field_of(config.set_field(Section::Camera, "gain", value.clone(), ChangeSource::Api)),
+            "camera.gain"
+        );
+        assert_eq!(
+            field_of(config.set_field(Section::Camera, "fps", value, ChangeSource::Api)),
+            "camera.fps"
+        );
+        assert_eq!(config.camera.load().gain, 12.0);
+        std::fs::remove_file(config.effective().base).unwrap();
+    }
+
+    #[test]
+    fn test_set_profiles() {
+        let config = temp_config(
+            "profiles.toml",
+            "gain = 5.0\n[profiles.hero.camera]\ngain = 8.0",
+        );
+        let changes = config.subscribe();
+        assert!(config.profiles().is_empty());
+
+        assert_eq!(
+            config.set_profiles(&["hero"]).unwrap(),
+            vec![Section::Camera]
+        );
+        assert_eq!(changes.try_recv().unwrap().section, Section::Camera);
+        assert_eq!(config.camera.load().gain, 8.0);
+        assert_eq!(config.profiles(), ["hero"]);
+
+        // 切换失败时保留原配置档
+        assert!(config.set_profiles(&["red"]).is_err());
+        assert_eq!(config.profiles(), ["hero"]);
+
+        config.set_profiles::<&str>(&[]).unwrap();
+        assert_eq!(config.camera.load().gain, 5.0);
+
+        // 尚未保存的运行时修改在切换配置档后保留
+        config
+            .update(|camera: &mut Camera| camera.exposure_time = 2000.0)
+            .unwrap();
+        config.set_profiles(&["hero"]).unwrap();
+        assert_eq!(config.camera.load().gain, 8.0);
+        assert_eq!(config.camera.load().exposure_time, 2000.0);
+        std::fs::remove_file(config.effective().base).unwrap();
+    }
 }
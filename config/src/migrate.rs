@@ -0,0 +1,372 @@
+//! 配置文件版本与迁移
+//!
+//! `Param.toml` 顶层的 `version` 键记录文件格式的版本，基础配置文件缺省时视为版本 0，覆盖文件
+//! 缺省时视为与基础配置文件相同。加载时若文件版本低于 [`CURRENT_VERSION`]，则按 [`MIGRATIONS`]
+//! 逐版本升级：重命名字段、移动配置段、为新增字段写入默认值等，移动与删除同样作用于
+//! `[profiles.<name>]` 中的配置档。每个文件的迁移内容首次加载时输出到日志。迁移在保留注释的
+//! 文档上进行，指定 `--write-migrated` 时将迁移结果写回原文件（写回前同样会备份原文件）。
+//!
+//! 修改配置结构导致旧文件无法解析时，应将 [`CURRENT_VERSION`] 加一，并在 [`MIGRATIONS`]
+//! 末尾追加从上一版本升级的步骤。
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::{debug, info};
+use toml::Table;
+use toml_edit::{DocumentMut, Item, TableLike};
+
+use crate::{save, ConfigError, FieldError, LoadOptions};
+
+/// 当前程序使用的配置文件版本
+pub const CURRENT_VERSION: u32 = 1;
+
+/// 迁移中的单个步骤，路径均为以 `.` 分隔的键，如 `camera.exposure`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// 将字段或配置段移动到新的路径，可用于重命名字段或移动配置段
+    Move {
+        from: &'static str,
+        to: &'static str,
+    },
+    /// 字段不存在时写入默认值，取值为 TOML 字面量，不作用于配置档
+    Default {
+        path: &'static str,
+        value: &'static str,
+    },
+    /// 删除已废弃的字段或配置段
+    Remove { path: &'static str },
+}
+
+/// 从版本 `from` 升级到 `from + 1` 的迁移
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    pub steps: &'static [Step],
+}
+
+/// 全部迁移，按版本升序排列
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    from: 0,
+    description: "引入版本号，删除未使用的可视化帧率",
+    steps: &[Step::Remove {
+        path: "gui.video_fps",
+    }],
+}];
+
+/// 按路径查找父表，`create` 为真时创建缺失的表
+fn parent<'a>(
+    root: &'a mut dyn TableLike,
+    path: &'a str,
+    create: bool,
+) -> Option<(&'a mut dyn TableLike, &'a str)> {
+    let mut keys: Vec<_> = path.split('.').collect();
+    let key = keys.pop()?;
+    let mut table = root;
+    for name in keys {
+        if create && !table.contains_key(name) {
+            table.insert(name, toml_edit::table());
+        }
+        table = table.get_mut(name)?.as_table_like_mut()?;
+    }
+    Some((table, key))
+}
+
+impl Step {
+    /// 在文档的顶层以及各配置档上执行该步骤
+    ///
+    /// # 返回值
+    /// 对每处修改的描述
+    fn apply_all(&self, document: &mut DocumentMut) -> Vec<String> {
+        let mut changes: Vec<_> = self
+            .apply(document.as_table_mut(), "")
+            .into_iter()
+            .collect();
+        if matches!(self, Step::Default { .. }) {
+            // 配置档只包含需要覆盖的字段，写入默认值会覆盖基础配置
+            return changes;
+        }
+        let Some(profiles) = document
+            .get_mut("profiles")
+            .and_then(Item::as_table_like_mut)
+        else {
+            return changes;
+        };
+        for (name, profile) in profiles.iter_mut() {
+            if let Some(profile) = profile.as_table_like_mut() {
+                let prefix = format!("profiles.{}.", name.get());
+                changes.extend(self.apply(profile, &prefix));
+            }
+        }
+        changes
+    }
+
+    /// 在 `root` 上执行该步骤，`prefix` 为 `root` 在文档中的路径，仅用于描述
+    ///
+    /// # 返回值
+    /// 对修改的描述，文档未发生变化时为 `None`
+    fn apply(&self, root: &mut dyn TableLike, prefix: &str) -> Option<String> {
+        match *self {
+            Step::Move { from, to } => {
+                let item = parent(root, from, false).and_then(|(table, key)| table.remove(key))?;
+                let (table, key) = parent(root, to, true)?;
+                table.insert(key, item);
+                Some(format!("`{}{}` 移动到 `{}{}`", prefix, from, prefix, to))
+            }
+            Step::Default { path, value } => {
+                let (table, key) = parent(root, path, true)?;
+                if table.contains_key(key) {
+                    return None;
+                }
+                let value: toml_edit::Value = value.parse().expect("默认值应为合法的 TOML 字面量");
+                table.insert(key, Item::Value(value));
+                Some(format!("新增 `{}{}` = {}", prefix, path, table.get(key)?))
+            }
+            Step::Remove { path } => {
+                parent(root, path, false).and_then(|(table, key)| table.remove(key))?;
+                Some(format!("删除 `{}{}`", prefix, path))
+            }
+        }
+    }
+}
+
+/// 读取文档的版本号，不得高于 `supported`，缺少 `version` 键时为 `missing`
+fn version(
+    path: &Path,
+    document: &DocumentMut,
+    supported: u32,
+    missing: u32,
+) -> Result<u32, ConfigError> {
+    let Some(item) = document.get("version") else {
+        return Ok(missing);
+    };
+    let invalid = || {
+        ConfigError::Validation(vec![FieldError {
+            field: "version".to_string(),
+            message: format!("应为非负整数，实际为{}", item.to_string().trim()),
+        }])
+    };
+    let version = item.as_integer().ok_or_else(invalid)?;
+    let version = u32::try_from(version).map_err(|_| invalid())?;
+    if version > supported {
+        return Err(ConfigError::UnsupportedVersion {
+            path: path.to_path_buf(),
+            version,
+            supported,
+        });
+    }
+    Ok(version)
+}
+
+/// 将文档逐版本升级到最新版本
+///
+/// `migrations` 须从版本 0 开始连续排列，最新版本即迁移的数量。文档缺少 `version` 键时视为
+/// 版本 `missing`。
+///
+/// # 返回值
+/// 文档原来的版本号，以及每一步修改的描述
+pub(crate) fn migrate_document(
+    path: &Path,
+    document: &mut DocumentMut,
+    migrations: &[Migration],
+    missing: u32,
+) -> Result<(u32, Vec<String>), ConfigError> {
+    let current = migrations.len() as u32;
+    let from = version(path, document, current, missing)?;
+    let mut changes = Vec::new();
+    for migration in &migrations[from as usize..] {
+        changes.push(format!(
+            "版本{} → {}：{}",
+            migration.from,
+            migration.from + 1,
+            migration.description
+        ));
+        for step in migration.steps {
+            changes.extend(step.apply_all(document));
+        }
+    }
+    if from < current || !document.contains_key("version") {
+        document.insert("version", toml_edit::value(i64::from(current)));
+    }
+    Ok((from, changes))
+}
+
+/// 已输出过迁移日志的文件，重新加载时不再重复输出
+static LOGGED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// 读取配置文件并迁移到当前版本，文件缺少 `version` 键时视为版本 `missing`
+///
+/// # 返回值
+/// 去掉 `version` 键的配置表，以及文件原来的版本号
+pub(crate) fn load_file(
+    path: &Path,
+    options: &LoadOptions,
+    missing: u32,
+) -> Result<(Table, u32), ConfigError> {
+    let content = crate::layer::read_file(path)?;
+    let table: Table = toml::from_str(&content)
+        .map_err(|err| ConfigError::parse(path.to_path_buf(), &content, err))?;
+    let mut document: DocumentMut = content.parse().expect("TOML 语法已通过解析");
+
+    let (from, changes) = migrate_document(path, &mut document, MIGRATIONS, missing)?;
+    let mut table = if from < CURRENT_VERSION {
+        let message = format!(
+            "[配置] {}已从版本{}迁移到版本{}：\n    {}",
+            path.display(),
+            from,
+            CURRENT_VERSION,
+            changes.join("\n    ")
+        );
+        if LOGGED.lock().expect("锁中毒").insert(path.to_path_buf()) {
+            info!("{}", message);
+        } else {
+            debug!("{}", message);
+        }
+        if options.write_migrated {
+            save::write_with_backup(path, &document.to_string(), options.backups)?;
+            info!("[配置] 已将迁移结果写回{}", path.display());
+        }
+        toml::from_str(&document.to_string()).expect("迁移后的文档应当可以解析")
+    } else {
+        table
+    };
+    table.remove("version");
+    Ok((table, from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            from: 0,
+            description: "重命名曝光",
+            steps: &[
+                Step::Move {
+                    from: "camera.exposure",
+                    to: "camera.exposure_time",
+                },
+                Step::Remove {
+                    path: "gui.video_fps",
+                },
+            ],
+        },
+        Migration {
+            from: 1,
+            description: "移动自瞄配置",
+            steps: &[
+                Step::Move {
+                    from: "aim",
+                    to: "detect.aim",
+                },
+                Step::Default {
+                    path: "track.max_lost",
+                    value: "5",
+                },
+            ],
+        },
+    ];
+
+    #[test]
+    fn test_current_version() {
+        assert_eq!(CURRENT_VERSION as usize, super::MIGRATIONS.len());
+        for (version, migration) in super::MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.from as usize, version);
+        }
+    }
+
+    #[test]
+    fn test_migrate_steps() {
+        let mut document: DocumentMut =
+            "[camera]\nexposure = 2000.0 # us(微秒)\n\n[aim]\nfov = 30\n\n[gui]\nvideo_fps = 30\n"
+                .parse()
+                .unwrap();
+        let (from, changes) =
+            migrate_document(Path::new("Param.toml"), &mut document, MIGRATIONS, 0).unwrap();
+        assert_eq!(from, 0);
+        // 两条迁移说明与四个步骤
+        assert_eq!(changes.len(), 6);
+
+        let content = document.to_string();
+        assert!(content.contains("exposure_time = 2000.0 # us(微秒)"));
+        assert!(!content.contains("video_fps"));
+        let table: Table = toml::from_str(&content).unwrap();
+        assert_eq!(table["detect"]["aim"]["fov"].as_integer(), Some(30));
+        assert_eq!(table["track"]["max_lost"].as_integer(), Some(5));
+        assert_eq!(table["version"].as_integer(), Some(2));
+    }
+
+    #[test]
+    fn test_migrate_profiles() {
+        let mut document: DocumentMut =
+            "[profiles.hero.camera]\nexposure = 3000.0\n\n[profiles.hero.gui]\nvideo_fps = 60\n"
+                .parse()
+                .unwrap();
+        let (_, changes) =
+            migrate_document(Path::new("Param.toml"), &mut document, MIGRATIONS, 0).unwrap();
+        assert!(changes.contains(&"删除 `profiles.hero.gui.video_fps`".to_string()));
+
+        let table: Table = toml::from_str(&document.to_string()).unwrap();
+        let hero = &table["profiles"]["hero"];
+        assert_eq!(hero["camera"]["exposure_time"].as_float(), Some(3000.0));
+        assert!(hero["gui"].as_table().unwrap().is_empty());
+        // 默认值只写入顶层，不覆盖配置档
+        assert!(hero.get("track").is_none());
+        assert_eq!(table["track"]["max_lost"].as_integer(), Some(5));
+    }
+
+    #[test]
+    fn test_migrate_version() {
+        // 已是最新版本时不做修改
+        let content = "version = 2\n[camera]\nexposure = 1.0\n";
+        let mut document: DocumentMut = content.parse().unwrap();
+        let (from, changes) =
+            migrate_document(Path::new("Param.toml"), &mut document, MIGRATIONS, 0).unwrap();
+        assert_eq!(from, 2);
+        assert!(changes.is_empty());
+        assert_eq!(document.to_string(), content);
+
+        // 缺少版本号时按给定的版本处理，如覆盖文件视为与基础配置文件相同
+        let content = "[camera]\nexposure_time = 1.0\n";
+        let mut document: DocumentMut = content.parse().unwrap();
+        let (from, changes) =
+            migrate_document(Path::new("overlay.toml"), &mut document, MIGRATIONS, 2).unwrap();
+        assert_eq!(from, 2);
+        assert!(changes.is_empty());
+
+        let mut document: DocumentMut = "version = 99".parse().unwrap();
+        assert!(matches!(
+            migrate_document(Path::new("Param.toml"), &mut document, MIGRATIONS, 0),
+            Err(ConfigError::UnsupportedVersion { version: 99, .. })
+        ));
+        let mut document: DocumentMut = "version = \"1\"".parse().unwrap();
+        assert!(matches!(
+            migrate_document(Path::new("Param.toml"), &mut document, MIGRATIONS, 0),
+            Err(ConfigError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_file_write_back() {
+        let path = std::env::temp_dir().join(format!("quasar_{}_migrate.toml", std::process::id()));
+        std::fs::write(&path, "# 旧版配置\n[gui]\nvideo_fps = 30\n").unwrap();
+        let options = LoadOptions {
+            write_migrated: true,
+            backups: 0,
+            ..Default::default()
+        };
+        let (table, from) = load_file(&path, &options, 0).unwrap();
+        assert_eq!(from, 0);
+        assert!(!table.contains_key("version"));
+        assert!(table["gui"].as_table().unwrap().is_empty());
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("# 旧版配置"));
+        assert!(content.contains(&format!("version = {}", CURRENT_VERSION)));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,493 @@
+//! 保存配置
+//!
+//! 保存时在原有的文档上就地修改取值发生变化的字段，保留注释、字段顺序以及未知字段。运行时修改
+//! 的字段写入当前提供该字段取值的层：基础配置文件的 `[<section>]`、启用的配置档
+//! `[profiles.<name>.<section>]` 或覆盖文件，重新加载后依然生效。由环境变量或命令行覆盖的字段
+//! 无法写回文件，此时返回 [`ConfigError::Overridden`] 且不写入任何文件。
+//!
+//! 写入过程先写临时文件并 `fsync`，再通过 `rename` 原子地替换原文件，即使中途断电也不会
+//! 留下被截断的配置文件。替换前将原文件复制为 `Param.toml.<时间戳>.bak` 形式的备份，
+//! 保留的备份数量由 [`LoadOptions::backups`](crate::LoadOptions::backups) 决定。
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use toml::{Table, Value};
+use toml_edit::{DocumentMut, Item, TableLike};
+
+use crate::{
+    layer::{self, Effective},
+    migrate, Config, ConfigError, Origin,
+};
+
+impl Config {
+    /// 将运行时修改的字段保存到提供其取值的配置文件，详见[模块文档](self)
+    pub fn save(&self) -> Result<(), ConfigError> {
+        let current: Table = Value::try_from(self.try_get()?)
+            .map_err(ConfigError::Serialize)?
+            .try_into()
+            .expect("配置序列化的结果应当是表");
+        let effective = self.effective();
+        let options = self.options();
+        // 按文件的当前内容重新解析各层，确定每个字段由哪一层提供
+        let layered = layer::resolve(&options)?;
+        let edits = collect_edits(&current, &effective, &layered)?;
+
+        let mut base = Document::open(&effective.base, 0)?;
+        let mut overlay = None;
+        let mut changed = 0;
+        for edit in edits {
+            let table = match &edit.layer {
+                Origin::Profile(name) => base.table(&["profiles", name, edit.section]),
+                Origin::Overlay(path) => {
+                    let overlay = match &mut overlay {
+                        Some(overlay) => overlay,
+                        // 覆盖文件缺少版本号时视为与基础配置文件相同
+                        None => overlay.insert(Document::open(path, base.version)?),
+                    };
+                    overlay.table(&[edit.section])
+                }
+                _ => base.table(&[edit.section]),
+            };
+            set_value(table, edit.key, edit.value);
+            changed += 1;
+        }
+
+        if changed == 0 && !base.modified() {
+            info!("[配置] 配置未发生变化，无需保存");
+        } else {
+            for document in [Some(&base), overlay.as_ref()].into_iter().flatten() {
+                if document.modified() {
+                    write_with_backup(
+                        &document.path,
+                        &document.document.to_string(),
+                        options.backups,
+                    )?;
+                }
+            }
+            info!("[配置] 已保存{}个字段", changed);
+        }
+        // 运行时修改的值已写入文件，之后重新加载时以文件为准
+        if let Some(effective) = self.effective.lock().expect("锁中毒").as_mut() {
+            effective.mark_saved(&layered);
+        }
+        Ok(())
+    }
+}
+
+/// 迁移到当前版本的配置文件文档
+struct Document {
+    path: PathBuf,
+    /// 文件原有的内容
+    content: String,
+    document: DocumentMut,
+    /// 文件原来的版本号
+    version: u32,
+}
+
+impl Document {
+    /// 读取并迁移配置文件，文件不存在时视为空文件，缺少版本号时视为版本 `missing`
+    fn open(path: &Path, missing: u32) -> Result<Self, ConfigError> {
+        let content = if path.exists() {
+            layer::read_file(path)?
+        } else {
+            String::new()
+        };
+        toml::from_str::<Table>(&content)
+            .map_err(|err| ConfigError::parse(path.to_path_buf(), &content, err))?;
+        let mut document: DocumentMut = content.parse().expect("TOML 语法已通过解析");
+        // 保存前先将旧版本的文档迁移到当前版本，避免新字段写入旧的位置
+        let (version, _) =
+            migrate::migrate_document(path, &mut document, migrate::MIGRATIONS, missing)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            content,
+            document,
+            version,
+        })
+    }
+
+    /// 按键的路径取出表，缺失的表依次创建
+    fn table(&mut self, keys: &[&str]) -> &mut dyn TableLike {
+        let mut table: &mut dyn TableLike = self.document.as_table_mut();
+        for key in keys {
+            table = table
+                .entry(key)
+                .or_insert_with(toml_edit::table)
+                .as_table_like_mut()
+                .expect("配置段应当是表");
+        }
+        table
+    }
+
+    /// 文档是否与文件原有的内容不同
+    fn modified(&self) -> bool {
+        self.document.to_string() != self.content
+    }
+}
+
+/// 需要写入文件的单个字段
+struct Edit<'a> {
+    section: &'a str,
+    key: &'a str,
+    value: &'a Value,
+    /// 提供该字段取值的层
+    layer: Origin,
+}
+
+/// 找出 `current` 中需要保存的字段，以及各字段应写入的层
+///
+/// 以下字段不会写入：
+/// - 取值与各层配置合并后的取值相同的字段；
+/// - 取值仍等于加载时的值，且不是运行时修改的字段。
+///
+/// # 返回值
+/// 字段由环境变量或命令行覆盖、无法写入文件时返回 [`ConfigError::Overridden`]
+fn collect_edits<'a>(
+    current: &'a Table,
+    effective: &Effective,
+    layered: &Effective,
+) -> Result<Vec<Edit<'a>>, ConfigError> {
+    let mut edits = Vec::new();
+    for (section, fields) in current {
+        let Value::Table(fields) = fields else {
+            continue;
+        };
+        for (key, value) in fields {
+            let get = |table: &Table| table.get(section)?.get(key).cloned();
+            if get(&layered.table).as_ref() == Some(value) {
+                continue;
+            }
+            let path = format!("{}.{}", section, key);
+            let runtime = matches!(effective.origin(&path), Some(Origin::Runtime));
+            if !runtime && get(&effective.table).as_ref() == Some(value) {
+                continue;
+            }
+
+            let layer = match layered.origin(&path) {
+                Some(origin @ (Origin::Env(_) | Origin::Cli)) => {
+                    return Err(ConfigError::Overridden {
+                        field: path,
+                        origin: origin.clone(),
+                    })
+                }
+                Some(origin) => origin.clone(),
+                None => Origin::Default,
+            };
+            edits.push(Edit {
+                section,
+                key,
+                value,
+                layer,
+            });
+        }
+    }
+    Ok(edits)
+}
+
+/// 设置字段的取值，已有字段保留行尾注释等修饰
+fn set_value(table: &mut dyn TableLike, key: &str, value: &Value) {
+    let mut new: toml_edit::Value = value
+        .to_string()
+        .parse()
+        .expect("TOML 值的序列化结果应当可以重新解析");
+    match table.get_mut(key).and_then(Item::as_value_mut) {
+        Some(old) => {
+            *new.decor_mut() = old.decor().clone();
+            *old = new;
+        }
+        None => {
+            new.decor_mut().clear();
+            table.insert(key, Item::Value(new));
+        }
+    }
+}
+
+/// 备份原文件后以原子方式写入新内容
+pub(crate) fn write_with_backup(
+    path: &Path,
+    content: &str,
+    backups: usize,
+) -> Result<(), ConfigError> {
+    let io_error = |source| ConfigError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    if backups > 0 && path.exists() {
+        backup(path, backups).map_err(io_error)?;
+    }
+    write_atomic(path, content.as_bytes()).map_err(io_error)
+}
+
+/// 先写入同目录下的临时文件并同步到磁盘，再重命名替换目标文件
+fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    // 同一进程内可能同时保存（如远程修改与本地保存），以序号区分各次写入的临时文件
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let temp = dir.join(format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        SEQUENCE.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+
+    // 同步目录项，确保重命名在断电后依然生效
+    if let Err(err) = File::open(dir).and_then(|dir| dir.sync_all()) {
+        warn!("[配置] 同步目录{}失败：{}", dir.display(), err);
+    }
+    Ok(())
+}
+
+/// 将原文件复制为带时间戳的备份，并删除超出数量的旧备份
+fn backup(path: &Path, keep: usize) -> io::Result<PathBuf> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let backup = path.with_file_name(format!(
+        "{}.{}.bak",
+        file_name,
+        timestamp(SystemTime::now())
+    ));
+    fs::copy(path, &backup)?;
+
+    let mut backups = list_backups(path)?;
+    if backups.len() > keep {
+        for old in backups.drain(..backups.len() - keep) {
+            fs::remove_file(old)?;
+        }
+    }
+    Ok(backup)
+}
+
+/// 列出配置文件的全部备份，按时间从旧到新排序
+fn list_backups(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+    let mut backups: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|backup| {
+            backup.file_name().is_some_and(|name| {
+                let name = name.to_string_lossy();
+                name.starts_with(&prefix) && name.ends_with(".bak")
+            })
+        })
+        .collect();
+    // 时间戳定长，按文件名排序即按时间排序
+    backups.sort();
+    Ok(backups)
+}
+
+/// 以 `YYYYMMDDTHHMMSS.mmmZ` 格式（UTC）表示时间
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // 由 1970-01-01 起的天数计算公历日期
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{temp_config, write_camera},
+        Camera, LoadOptions,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_760_789_412_345);
+        assert_eq!(timestamp(time), "20251018T121012.345Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101T000000.000Z");
+    }
+
+    #[test]
+    fn test_save_preserves_comments() {
+        let config = temp_config("save.toml", "gain = 5.0 # dB(分贝)\n# 未知字段\nfoo = 1");
+        let path = config.effective().base;
+
+        config
+            .update(|camera: &mut Camera| {
+                camera.gain = 12.0;
+                camera.width = 1280;
+            })
+            .unwrap();
+        config.save().unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("gain = 12.0 # dB(分贝)\n"));
+        assert!(content.contains("# 未知字段\nfoo = 1\n"));
+        assert!(content.contains("width = 1280\n"));
+        // 未修改的默认值不会写入
+        assert!(!content.contains("exposure_time"));
+
+        let backups = list_backups(&path).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert!(fs::read_to_string(&backups[0])
+            .unwrap()
+            .contains("gain = 5.0"));
+
+        for backup in backups {
+            fs::remove_file(backup).unwrap();
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    /// 删除配置文件及其全部备份
+    fn remove_with_backups(path: &Path) {
+        for backup in list_backups(path).unwrap() {
+            fs::remove_file(backup).unwrap();
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_save_to_profile() {
+        let config = temp_config(
+            "save_profile.toml",
+            "exposure_time = 3000.0\n[profiles.hero.camera]\nexposure_time = 5000.0",
+        );
+        let path = config.effective().base;
+        config.set_profiles(&["hero"]).unwrap();
+        config
+            .update(|camera: &mut Camera| camera.exposure_time = 6000.0)
+            .unwrap();
+        config.save().unwrap();
+
+        // 写入提供该字段取值的配置档，基础配置保持不变
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("[camera]\ncamera_matrix"));
+        assert!(content.contains("exposure_time = 3000.0\n"));
+        assert!(content.contains("[profiles.hero.camera]\nexposure_time = 6000.0\n"));
+        assert_eq!(
+            config.effective().origin("camera.exposure_time"),
+            Some(&Origin::Profile("hero".into()))
+        );
+
+        config.reload().unwrap();
+        assert_eq!(config.camera.load().exposure_time, 6000.0);
+        remove_with_backups(&path);
+    }
+
+    #[test]
+    fn test_save_to_overlay() {
+        let base = std::env::temp_dir().join(format!(
+            "quasar_{}_save_overlay_base.toml",
+            std::process::id()
+        ));
+        let overlay =
+            base.with_file_name(format!("quasar_{}_save_overlay.toml", std::process::id()));
+        write_camera(&base, "gain = 5.0");
+        fs::write(&overlay, "[camera]\ngain = 8.0 # 覆盖\n").unwrap();
+        let config = Box::leak(Box::new(Config::new()));
+        config.init(LoadOptions {
+            base: Some(base.clone()),
+            overlay: Some(overlay.clone()),
+            overrides: vec![("camera.width".into(), "1280".into())],
+            ..Default::default()
+        });
+
+        config
+            .update(|camera: &mut Camera| camera.gain = 9.0)
+            .unwrap();
+        config.save().unwrap();
+        assert!(fs::read_to_string(&overlay)
+            .unwrap()
+            .contains("gain = 9.0 # 覆盖\n"));
+        assert!(fs::read_to_string(&base).unwrap().contains("gain = 5.0\n"));
+
+        // 由命令行覆盖的字段无法保存，且不写入任何文件
+        config
+            .update(|camera: &mut Camera| {
+                camera.gain = 10.0;
+                camera.width = 1024;
+            })
+            .unwrap();
+        assert!(matches!(
+            config.save(),
+            Err(ConfigError::Overridden { field, origin: Origin::Cli }) if field == "camera.width"
+        ));
+        assert!(fs::read_to_string(&overlay)
+            .unwrap()
+            .contains("gain = 9.0 # 覆盖\n"));
+
+        remove_with_backups(&base);
+        remove_with_backups(&overlay);
+    }
+
+    #[test]
+    fn test_concurrent_write() {
+        let path = std::env::temp_dir().join(format!("quasar_{}_atomic.toml", std::process::id()));
+        let contents: Vec<Vec<u8>> = (0..8u8).map(|i| vec![b'a' + i; 1 << 16]).collect();
+        std::thread::scope(|scope| {
+            for content in &contents {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..8 {
+                        write_atomic(path, content).unwrap();
+                    }
+                });
+            }
+        });
+        // 最终内容是某一次完整的写入
+        assert!(contents.contains(&fs::read(&path).unwrap()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_backup_rotation() {
+        let path = std::env::temp_dir().join(format!("quasar_{}_rotate.toml", std::process::id()));
+        fs::write(&path, "").unwrap();
+        for _ in 0..4 {
+            backup(&path, 2).unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let backups = list_backups(&path).unwrap();
+        assert_eq!(backups.len(), 2);
+
+        for backup in backups {
+            fs::remove_file(backup).unwrap();
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,560 @@
+//! 配置字段的声明式约束与校验
+//!
+//! 各配置段通过 [`config_section!`] 声明，宏根据字段声明生成结构体、`Default` 实现以及
+//! 每个字段的 [`FieldSpec`]（类型、单位、取值范围、是否必填、文档注释）。加载配置与运行时修改
+//! 配置时均依据这些约束进行校验，所有错误一并报告。
+//!
+//! ```ignore
+//! config_section! {
+//!     Section::Camera => camera;
+//!     /// 相机配置
+//!     #[derive(Copy)]
+//!     pub struct Camera {
+//!         /// 曝光时间
+//!         pub exposure_time: f32 = 1000.0 => { unit: "us(微秒)", range: 1.0..=1_000_000.0 },
+//!         /// 相机内参矩阵
+//!         pub camera_matrix: [f64; 9] = [0.0; 9] => { required: true },
+//!     }
+//! }
+//! ```
+use std::{collections::BTreeMap, fmt, ops::RangeInclusive};
+
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+use toml::{Table, Value};
+
+use crate::{
+    Camera, ConfigError, ConfigInner, Effective, FieldError, Origin, Section, Slot, UndistortMode,
+    SENSOR_HEIGHT, SENSOR_WIDTH,
+};
+
+/// 字段的取值类型
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Bool,
+    Integer,
+    Float,
+    String,
+    /// 取值为给定字符串之一
+    Enum(&'static [&'static str]),
+    /// 定长数组
+    Array(Box<Kind>, usize),
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Bool => write!(f, "布尔值"),
+            Kind::Integer => write!(f, "整数"),
+            Kind::Float => write!(f, "浮点数"),
+            Kind::String => write!(f, "字符串"),
+            Kind::Enum(variants) => write!(f, "{} 之一", variants.join("/")),
+            Kind::Array(item, len) => write!(f, "长度为{}的{}数组", len, item),
+        }
+    }
+}
+
+/// 可作为配置字段的类型
+pub trait FieldType {
+    fn kind() -> Kind;
+}
+
+macro_rules! impl_field_type {
+    ($kind:expr => $($ty:ty),*) => {
+        $(impl FieldType for $ty {
+            fn kind() -> Kind {
+                $kind
+            }
+        })*
+    };
+}
+
+impl_field_type!(Kind::Bool => bool);
+impl_field_type!(Kind::Integer => u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+impl_field_type!(Kind::Float => f32, f64);
+impl_field_type!(Kind::String => String);
+
+impl<T: FieldType, const N: usize> FieldType for [T; N] {
+    fn kind() -> Kind {
+        Kind::Array(Box::new(T::kind()), N)
+    }
+}
+
+/// 单个配置字段的描述与约束
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldSpec {
+    pub name: &'static str,
+    pub kind: Kind,
+    /// 字段的文档注释
+    pub description: String,
+    pub unit: Option<&'static str>,
+    /// 闭区间取值范围
+    pub range: Option<(f64, f64)>,
+    /// 必填字段必须由配置文件、环境变量或命令行显式给出，不能仅依赖默认值
+    pub required: bool,
+    pub default: Value,
+}
+
+impl FieldSpec {
+    pub fn new<T>(name: &'static str, docs: &[&str], default: T) -> Self
+    where
+        T: FieldType + Serialize,
+    {
+        Self {
+            name,
+            kind: T::kind(),
+            description: doc_text(docs),
+            unit: None,
+            range: None,
+            required: false,
+            default: Value::try_from(default).expect("默认值无法序列化为TOML"),
+        }
+    }
+
+    pub fn unit(mut self, unit: &'static str) -> Self {
+        self.unit = Some(unit);
+        self
+    }
+
+    pub fn range<T: Into<f64>>(mut self, range: RangeInclusive<T>) -> Self {
+        let (min, max) = range.into_inner();
+        self.range = Some((min.into(), max.into()));
+        self
+    }
+
+    pub fn required(mut self, required: bool) -> Self {
+        self.required = required;
+        self
+    }
+
+    /// 检查单个值是否满足类型与取值范围约束
+    pub fn check(&self, value: &Value) -> Result<(), String> {
+        check_kind(&self.kind, value)?;
+        if let Some((min, max)) = self.range {
+            let number = match value {
+                Value::Integer(value) => *value as f64,
+                Value::Float(value) => *value,
+                _ => return Ok(()),
+            };
+            if !(min..=max).contains(&number) {
+                return Err(format!("取值{}超出范围[{}, {}]", value, min, max));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 将文档注释的各行合并为一段文本
+pub fn doc_text(docs: &[&str]) -> String {
+    docs.iter()
+        .map(|line| line.trim())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+fn check_kind(kind: &Kind, value: &Value) -> Result<(), String> {
+    match (kind, value) {
+        (Kind::Bool, Value::Boolean(_))
+        | (Kind::Integer, Value::Integer(_))
+        | (Kind::Float, Value::Float(_) | Value::Integer(_))
+        | (Kind::String, Value::String(_)) => Ok(()),
+        (Kind::Enum(variants), Value::String(variant)) if variants.contains(&variant.as_str()) => {
+            Ok(())
+        }
+        (Kind::Array(item, len), Value::Array(values)) => {
+            if values.len() != *len {
+                return Err(format!("应为{}，实际长度为{}", kind, values.len()));
+            }
+            values.iter().enumerate().try_for_each(|(index, value)| {
+                check_kind(item, value).map_err(|err| format!("第{}个元素：{}", index, err))
+            })
+        }
+        _ => Err(format!("应为{}，实际为{}", kind, value)),
+    }
+}
+
+/// 由 [`config_section!`] 声明的配置段
+pub trait SectionSchema:
+    Serialize + DeserializeOwned + Default + Clone + PartialEq + Send + 'static
+{
+    const SECTION: Section;
+
+    /// 配置段的说明
+    fn description() -> String;
+
+    /// 配置段中全部字段的描述与约束
+    fn fields() -> Vec<FieldSpec>;
+
+    /// 该配置段在全局配置中的存储位置
+    fn slot(inner: &ConfigInner) -> &Slot<Self>;
+}
+
+/// 未知字段的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownKeys {
+    /// 视为校验错误
+    Deny,
+    /// 输出警告日志
+    #[default]
+    Warn,
+    /// 忽略
+    Allow,
+}
+
+/// 校验单个配置段
+///
+/// # 参数
+/// - `origins`: 各值的来源，为 `None` 时不检查必填字段（如运行时修改）
+pub(crate) fn check_section(
+    section: Section,
+    table: &Table,
+    origins: Option<&BTreeMap<String, Origin>>,
+    unknown: UnknownKeys,
+    errors: &mut Vec<FieldError>,
+) {
+    let fields = section.fields();
+    for spec in &fields {
+        let path = format!("{}.{}", section, spec.name);
+        if let Some(value) = table.get(spec.name) {
+            if let Err(message) = spec.check(value) {
+                errors.push(FieldError {
+                    field: path.clone(),
+                    message,
+                });
+            }
+        }
+        let Some(origins) = origins.filter(|_| spec.required) else {
+            continue;
+        };
+        if matches!(origins.get(&path), None | Some(Origin::Default)) {
+            errors.push(FieldError {
+                field: path,
+                message: "必填字段未在配置中给出".to_string(),
+            });
+        }
+    }
+    for key in table.keys() {
+        if !fields.iter().any(|spec| spec.name == key) {
+            unknown_key(&format!("{}.{}", section, key), unknown, errors);
+        }
+    }
+    check_relations(section, table, errors);
+}
+
+/// 校验字段之间的约束，涉及的字段缺失或类型不符时跳过，由单个字段的校验报告
+fn check_relations(section: Section, table: &Table, errors: &mut Vec<FieldError>) {
+    if section == Section::Camera {
+        check_roi(table, errors);
+        check_undistort(table, errors);
+    }
+}
+
+/// 取出字段的值，字段缺失或类型不符时为 `None`
+fn field<T: DeserializeOwned>(table: &Table, key: &str) -> Option<T> {
+    table.get(key)?.clone().try_into().ok()
+}
+
+/// ROI 的偏移与尺寸之和不能超出传感器的分辨率，偏移或尺寸本身已出错时不再重复报告
+fn check_roi(table: &Table, errors: &mut Vec<FieldError>) {
+    for (offset, size, sensor) in [
+        ("offset_x", "width", SENSOR_WIDTH),
+        ("offset_y", "height", SENSOR_HEIGHT),
+    ] {
+        let reported = |key: &str| {
+            errors
+                .iter()
+                .any(|err| err.field.strip_prefix("camera.") == Some(key))
+        };
+        if reported(offset) || reported(size) {
+            continue;
+        }
+        let (Some(start), Some(length)) = (field::<u32>(table, offset), field::<u32>(table, size))
+        else {
+            continue;
+        };
+        if start.saturating_add(length) > sensor {
+            errors.push(FieldError {
+                field: format!("camera.{}", offset),
+                message: format!(
+                    "ROI超出传感器范围：{} + {} = {}，超过传感器的{}",
+                    offset,
+                    size,
+                    start.saturating_add(length),
+                    sensor
+                ),
+            });
+        }
+    }
+}
+
+/// 畸变校正需要标定得到的内参，内参仍为默认的单位矩阵时不允许开启
+fn check_undistort(table: &Table, errors: &mut Vec<FieldError>) {
+    let (Some(undistort), Some(camera_matrix)) = (
+        field::<UndistortMode>(table, "undistort"),
+        field::<[f64; 9]>(table, "camera_matrix"),
+    ) else {
+        return;
+    };
+    if undistort != UndistortMode::None && camera_matrix == Camera::default().camera_matrix {
+        errors.push(FieldError {
+            field: "camera.undistort".to_string(),
+            message: "畸变校正需要标定的相机内参，camera_matrix 仍为默认的单位矩阵，\
+                      请填写标定结果或设为\"none\""
+                .to_string(),
+        });
+    }
+}
+
+fn unknown_key(path: &str, unknown: UnknownKeys, errors: &mut Vec<FieldError>) {
+    match unknown {
+        UnknownKeys::Deny => errors.push(FieldError {
+            field: path.to_string(),
+            message: "未知字段".to_string(),
+        }),
+        UnknownKeys::Warn => warn!("[配置] 未知字段 `{}` 已被忽略", path),
+        UnknownKeys::Allow => {}
+    }
+}
+
+/// 校验合并后的全部配置
+pub(crate) fn validate(effective: &Effective, unknown: UnknownKeys) -> Result<(), ConfigError> {
+    let mut errors = Vec::new();
+    for (key, value) in &effective.table {
+        match (Section::from_name(key), value) {
+            (Some(section), Value::Table(table)) => check_section(
+                section,
+                table,
+                Some(&effective.origins),
+                unknown,
+                &mut errors,
+            ),
+            (Some(_), _) => errors.push(FieldError {
+                field: key.clone(),
+                message: format!("应为表，实际为{}", value),
+            }),
+            (None, _) => unknown_key(key, unknown, &mut errors),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigError::Validation(errors))
+    }
+}
+
+/// 收集属性列表中的文档注释
+macro_rules! collect_docs {
+    (@acc [$($acc:expr),*]) => { [$($acc),*] };
+    (@acc [$($acc:expr),*] [doc = $doc:literal] $($rest:tt)*) => {
+        collect_docs!(@acc [$($acc,)* $doc] $($rest)*)
+    };
+    (@acc [$($acc:expr),*] [$($other:tt)*] $($rest:tt)*) => {
+        collect_docs!(@acc [$($acc),*] $($rest)*)
+    };
+}
+
+/// 声明配置段：生成结构体、`Default` 实现与 [`SectionSchema`] 实现
+///
+/// 每个字段的形式为 `pub name: Type = default`，其后可跟 `=> { unit: ..., range: ..., required: ... }`
+/// 给出约束，字段的文档注释作为其说明。
+macro_rules! config_section {
+    (
+        $section:expr => $slot:ident;
+        $(#[$($meta:tt)*])*
+        pub struct $name:ident {
+            $(
+                $(#[doc = $doc:literal])*
+                pub $field:ident: $ty:ty = $default:expr
+                $(=> { $($key:ident: $value:expr),* $(,)? })?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$($meta)*])*
+        #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+        pub struct $name {
+            $(
+                $(#[doc = $doc])*
+                pub $field: $ty,
+            )*
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self {
+                    $($field: $default,)*
+                }
+            }
+        }
+
+        impl $crate::SectionSchema for $name {
+            const SECTION: $crate::Section = $section;
+
+            fn description() -> String {
+                $crate::schema::doc_text(&collect_docs!(@acc [] $([$($meta)*])*))
+            }
+
+            fn fields() -> Vec<$crate::FieldSpec> {
+                vec![$(
+                    $crate::FieldSpec::new::<$ty>(stringify!($field), &[$($doc),*], $default)
+                        $($(.$key($value))*)?
+                ),*]
+            }
+
+            fn slot(inner: &$crate::ConfigInner) -> &$crate::Slot<Self> {
+                &inner.$slot
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_spec() {
+        let fields = Camera::fields();
+        let exposure = fields
+            .iter()
+            .find(|spec| spec.name == "exposure_time")
+            .unwrap();
+        assert_eq!(exposure.kind, Kind::Float);
+        assert_eq!(exposure.unit, Some("us(微秒)"));
+        assert!(!exposure.description.is_empty());
+        assert!(exposure.check(&Value::Float(-1.0)).is_err());
+        assert!(exposure.check(&Value::Integer(2000)).is_ok());
+        assert!(exposure.check(&Value::String("2000".into())).is_err());
+
+        let undistort = fields.iter().find(|spec| spec.name == "undistort").unwrap();
+        assert!(undistort.check(&Value::String("frame".into())).is_ok());
+        assert!(undistort.check(&Value::String("fisheye".into())).is_err());
+        assert_eq!(
+            undistort.default,
+            Value::try_from(UndistortMode::Points).unwrap()
+        );
+
+        let dist = fields
+            .iter()
+            .find(|spec| spec.name == "dist_coeffs")
+            .unwrap();
+        assert!(dist
+            .check(&Value::Array(vec![Value::Float(0.0); 4]))
+            .is_err());
+        assert!(!Camera::description().is_empty());
+    }
+
+    #[test]
+    fn test_check_section_reports_all() {
+        let table: Table = toml::from_str(
+            "exposure_time = -1.0\nwidth = 100000\nundistort = \"fisheye\"\nvideo_fps = 30",
+        )
+        .unwrap();
+        let mut errors = Vec::new();
+        check_section(
+            Section::Camera,
+            &table,
+            None,
+            UnknownKeys::Deny,
+            &mut errors,
+        );
+        let fields: Vec<_> = errors.iter().map(|err| err.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            [
+                "camera.exposure_time",
+                "camera.width",
+                "camera.undistort",
+                "camera.video_fps"
+            ]
+        );
+
+        // 警告模式下未知字段不视为错误
+        let mut errors = Vec::new();
+        check_section(
+            Section::Camera,
+            &table,
+            None,
+            UnknownKeys::Warn,
+            &mut errors,
+        );
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn test_roi_within_sensor() {
+        let check = |content: &str| {
+            let table: Table = toml::from_str(content).unwrap();
+            let mut errors = Vec::new();
+            check_section(
+                Section::Camera,
+                &table,
+                None,
+                UnknownKeys::Deny,
+                &mut errors,
+            );
+            errors
+        };
+        let calibrated =
+            "camera_matrix = [1800.0, 0.0, 720.0, 0.0, 1800.0, 540.0, 0.0, 0.0, 1.0]\n";
+
+        assert!(check(&format!("{}width = 640\noffset_x = 800", calibrated)).is_empty());
+        let errors = check(&format!("{}width = 640\noffset_x = 801", calibrated));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "camera.offset_x");
+        // 尺寸为传感器的全分辨率时任何偏移都会超出
+        let errors = check(&format!("{}height = 1080\noffset_y = 8", calibrated));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "camera.offset_y");
+    }
+
+    #[test]
+    fn test_undistort_requires_calibration() {
+        let check = |content: &str| {
+            let table: Table = toml::from_str(content).unwrap();
+            let mut errors = Vec::new();
+            check_section(
+                Section::Camera,
+                &table,
+                None,
+                UnknownKeys::Deny,
+                &mut errors,
+            );
+            errors
+        };
+        let identity = "camera_matrix = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0]\n";
+        let calibrated =
+            "camera_matrix = [1800.0, 0.0, 720.0, 0.0, 1800.0, 540.0, 0.0, 0.0, 1.0]\n";
+
+        let errors = check(&format!("{}undistort = \"points\"", identity));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "camera.undistort");
+        assert_eq!(
+            check(&format!("{}undistort = \"frame\"", identity)).len(),
+            1
+        );
+        assert!(check(&format!("{}undistort = \"none\"", identity)).is_empty());
+        assert!(check(&format!("{}undistort = \"points\"", calibrated)).is_empty());
+    }
+
+    #[test]
+    fn test_required() {
+        let table: Table = toml::from_str("gain = 1.0").unwrap();
+        let origins = BTreeMap::from([
+            ("camera.gain".to_string(), Origin::Cli),
+            ("camera.camera_matrix".to_string(), Origin::Default),
+        ]);
+        let mut errors = Vec::new();
+        check_section(
+            Section::Camera,
+            &table,
+            Some(&origins),
+            UnknownKeys::Deny,
+            &mut errors,
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "camera.camera_matrix");
+    }
+}
@@ -0,0 +1,199 @@
+//! 配置段的无锁快照
+//!
+//! 每个配置段存放在一个 [`Slot`] 中。读者通过 [`Slot::load`] 获取不可变的 `Arc` 快照，读取过程
+//! 无锁，不会与写者竞争；写者（运行时修改、热重载、切换配置档）构造新的值后整体发布，并递增
+//! 该配置段的版本号。热循环中可使用 [`Reader`]，每帧仅读取一次原子变量即可判断配置是否变化：
+//!
+//! ```rust,no_run
+//! # use config::CONFIG;
+//! let mut camera = CONFIG.camera.reader();
+//! loop {
+//!     if camera.refresh() {
+//!         // 配置发生变化，重新配置相机
+//!     }
+//!     let exposure_time = camera.get().exposure_time;
+//!     # break;
+//! }
+//! ```
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// 存放单个配置段的快照及其版本号
+pub struct Slot<T> {
+    value: ArcSwap<T>,
+    /// 每次发布新值时加一
+    generation: AtomicU64,
+    /// 串行化写者，读者不使用
+    write: Mutex<()>,
+}
+
+impl<T> Slot<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value: ArcSwap::from_pointee(value),
+            generation: AtomicU64::new(0),
+            write: Mutex::new(()),
+        }
+    }
+
+    /// 获取当前值的快照
+    pub fn load(&self) -> Arc<T> {
+        self.value.load_full()
+    }
+
+    /// 当前值的版本号，每次发布新值时加一
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// 创建仅在版本号变化时才重新获取快照的读者
+    pub fn reader(&self) -> Reader<'_, T> {
+        let generation = self.generation();
+        Reader {
+            slot: self,
+            generation,
+            value: self.load(),
+        }
+    }
+
+    /// 获取写锁，持有期间其他写者无法发布新值
+    pub(crate) fn write(&self) -> Writer<'_, T> {
+        Writer {
+            slot: self,
+            _guard: self.write.lock().expect("锁中毒"),
+        }
+    }
+}
+
+impl<T: Default> Default for Slot<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Slot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Slot")
+            .field("value", &self.load())
+            .field("generation", &self.generation())
+            .finish()
+    }
+}
+
+impl<T: Serialize> Serialize for Slot<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.load().serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Slot<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// 配置段的写锁
+pub(crate) struct Writer<'a, T> {
+    slot: &'a Slot<T>,
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl<T> Writer<'_, T> {
+    /// 持有写锁期间当前值不会被其他写者修改
+    pub(crate) fn current(&self) -> Arc<T> {
+        self.slot.load()
+    }
+
+    /// 发布新值并递增版本号
+    pub(crate) fn publish(&self, value: impl Into<Arc<T>>) {
+        self.slot.value.store(value.into());
+        self.slot.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// 缓存快照的读者，适用于每帧读取配置的热循环
+pub struct Reader<'a, T> {
+    slot: &'a Slot<T>,
+    generation: u64,
+    value: Arc<T>,
+}
+
+impl<T> Reader<'_, T> {
+    /// 配置段发布了新值时更新缓存的快照
+    ///
+    /// # 返回值
+    /// 自上次刷新以来配置段是否发生变化
+    pub fn refresh(&mut self) -> bool {
+        // 先读版本号再读值，保证缓存的值不旧于记录的版本号
+        let generation = self.slot.generation();
+        if generation == self.generation {
+            return false;
+        }
+        self.value = self.slot.load();
+        self.generation = generation;
+        true
+    }
+
+    /// 刷新并返回最新的快照
+    pub fn get(&mut self) -> &T {
+        self.refresh();
+        &self.value
+    }
+
+    /// 缓存快照对应的版本号
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader() {
+        let slot = Slot::new(1);
+        let mut reader = slot.reader();
+        assert!(!reader.refresh());
+        assert_eq!(*reader.get(), 1);
+
+        let snapshot = slot.load();
+        slot.write().publish(2);
+        assert_eq!(slot.generation(), 1);
+        // 已获取的快照不受新值影响
+        assert_eq!(*snapshot, 1);
+        assert!(reader.refresh());
+        assert_eq!(*reader.get(), 2);
+        assert_eq!(reader.generation(), 1);
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let slot = Arc::new(Slot::new(0));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let slot = slot.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let writer = slot.write();
+                        let value = *writer.current() + 1;
+                        writer.publish(value);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*slot.load(), 4000);
+        assert_eq!(slot.generation(), 4000);
+    }
+}
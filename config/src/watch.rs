@@ -0,0 +1,200 @@
+//! 配置文件热重载
+//!
+//! 后台线程按固定间隔检查基础配置文件与覆盖文件的修改时间和大小，发生变化时调用
+//! [`Config::reload`] 重新加载。
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, error, info};
+
+use crate::Config;
+
+/// 配置文件监视器，离开作用域时停止监视线程
+pub struct Watcher {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// 文件的修改时间与大小，文件不存在时为 `None`
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &PathBuf) -> Stamp {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+impl Config {
+    /// 被监视的配置文件：基础配置文件与覆盖文件
+    fn watched_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.effective().base];
+        if let Some(overlay) = self.options().overlay {
+            files.push(overlay);
+        }
+        files
+    }
+
+    /// 启动后台线程监视配置文件，文件变化时自动重新加载
+    ///
+    /// 重新加载的规则同 [`Config::reload`]：运行时修改且尚未保存的字段仍以运行时的值为准，
+    /// 文件中对这些字段的修改被忽略，直到运行时的修改被保存。
+    ///
+    /// # 参数
+    /// - `interval`: 检查文件变化的间隔
+    ///
+    /// # 返回值
+    /// 监视器句柄，需保持存活，离开作用域时停止监视
+    pub fn watch(&'static self, interval: Duration) -> Watcher {
+        let files = self.watched_files();
+        let mut stamps: Vec<Stamp> = files.iter().map(stamp).collect();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = thread::spawn({
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    thread::sleep(interval);
+                    let current: Vec<Stamp> = files.iter().map(stamp).collect();
+                    if current == stamps {
+                        continue;
+                    }
+                    stamps = current;
+                    match self.reload() {
+                        Ok(changed) if changed.is_empty() => {
+                            debug!("[配置] 配置文件已变化，但生效配置未改变")
+                        }
+                        Ok(changed) => info!("[配置] 已重新加载配置，变化的配置段：{:?}", changed),
+                        Err(err) => error!("[配置] 重新加载配置失败，继续使用原配置：{:#}", err),
+                    }
+                }
+            }
+        });
+        Watcher {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{temp_config, write_camera},
+        Camera, ConfigChange, ConfigError, Origin, Section,
+    };
+
+    #[test]
+    fn test_reload_notifies_changed_sections() {
+        let config = temp_config("reload.toml", "gain = 5.0");
+        let changes = config.subscribe();
+        let path = config.effective().base;
+
+        // 未发生变化时不通知
+        assert!(config.reload().unwrap().is_empty());
+        assert!(changes.try_recv().is_err());
+
+        write_camera(&path, "gain = 12.0");
+        assert_eq!(config.reload().unwrap(), vec![Section::Camera]);
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            ConfigChange {
+                section: Section::Camera
+            }
+        );
+        assert_eq!(config.camera.load().gain, 12.0);
+
+        // 解析失败时保留原配置
+        write_camera(&path, "gain = \"high\"");
+        assert!(matches!(
+            config.reload(),
+            Err(ConfigError::Validation(errors)) if errors[0].field == "camera.gain"
+        ));
+        assert_eq!(config.camera.load().gain, 12.0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_keeps_runtime_changes() {
+        let config = temp_config("reload_runtime.toml", "gain = 5.0");
+        let path = config.effective().base;
+        config
+            .update(|camera: &mut Camera| camera.gain = 8.0)
+            .unwrap();
+
+        // 尚未保存的运行时修改覆盖文件中的取值，其余字段随文件更新
+        write_camera(&path, "gain = 6.0\nexposure_time = 3000.0");
+        assert_eq!(config.reload().unwrap(), vec![Section::Camera]);
+        assert_eq!(config.camera.load().gain, 8.0);
+        assert_eq!(config.camera.load().exposure_time, 3000.0);
+        assert_eq!(
+            config.effective().origin("camera.gain"),
+            Some(&Origin::Runtime)
+        );
+
+        // 保存后以文件为准
+        config.save().unwrap();
+        write_camera(&path, "gain = 6.0");
+        config.reload().unwrap();
+        assert_eq!(config.camera.load().gain, 6.0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_concurrent_update() {
+        let config = temp_config("reload_concurrent.toml", "gain = 5.0");
+        let updater = thread::spawn(|| {
+            for step in 1..=200 {
+                config
+                    .update(|camera: &mut Camera| camera.gain = step as f32 * 0.1)
+                    .unwrap();
+            }
+        });
+        while !updater.is_finished() {
+            config.reload().unwrap();
+        }
+        updater.join().unwrap();
+
+        // 与重新加载交错的运行时修改均已记入来源，再次重新加载后仍然保留
+        config.reload().unwrap();
+        assert_eq!(config.camera.load().gain, 20.0);
+        assert_eq!(
+            config.effective().origin("camera.gain"),
+            Some(&Origin::Runtime)
+        );
+
+        std::fs::remove_file(config.effective().base).unwrap();
+    }
+
+    #[test]
+    fn test_watch() {
+        let config = temp_config("watch.toml", "");
+        let changes = config.subscribe();
+        let path = config.effective().base;
+        let watcher = config.watch(Duration::from_millis(10));
+
+        write_camera(&path, "exposure_time = 5000.0");
+        let change = changes.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(change.section, Section::Camera);
+        assert_eq!(config.camera.load().exposure_time, 5000.0);
+
+        drop(watcher);
+        std::fs::remove_file(path).unwrap();
+    }
+}
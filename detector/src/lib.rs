@@ -307,6 +307,13 @@ use utility::{new_tube, TubeRecv, TubeSend};
 use opencv::{self as cv, core::*};
 use rand::{rngs::ThreadRng, Rng};
 
+mod preprocess;
+mod undistort;
+
+pub use preprocess::Preprocessor;
+pub use camera::Roi;
+pub use undistort::Undistorter;
+
 // use tungstenite::WebSocket;
 
 // #[cfg(feature = "gui")]
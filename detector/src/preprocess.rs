@@ -0,0 +1,89 @@
+//! 检测前的预处理
+//!
+//! [`Preprocessor`] 供检测阶段持有：每帧检测前调用 [`frame`](Preprocessor::frame)，检测得到
+//! 关键点后调用 [`points`](Preprocessor::points)，按 `camera.undistort` 完成畸变校正。相机配置
+//! 通过 `CONFIG` 修改时（文件监视、远程修改或切换配置档案）在下一帧重建校正参数。
+//!
+//! 目前尚未接入相机到检测的流水线，采集与检测阶段实现后由检测阶段构造并调用。
+
+use anyhow::Result;
+use config::{ConfigChange, Section, CONFIG};
+use crossbeam_channel::Receiver;
+use opencv::core::*;
+
+use crate::{Roi, Undistorter};
+
+/// 配置中的 ROI，相机应用配置后以 [`camera::query_roi`] 读取的实际取值为准
+fn configured_roi(camera: &config::Camera) -> Roi {
+    Roi {
+        width: camera.width as i32,
+        height: camera.height as i32,
+        offset_x: camera.offset_x as i32,
+        offset_y: camera.offset_y as i32,
+    }
+}
+
+/// 检测前的预处理，目前只包括畸变校正
+pub struct Preprocessor {
+    undistorter: Undistorter,
+    /// 当前帧在传感器上的区域，宽高以实际图像为准
+    roi: Roi,
+    changes: Receiver<ConfigChange>,
+}
+
+impl Preprocessor {
+    /// 使用全局配置中的相机参数构造，并订阅之后的配置变化
+    pub fn new() -> Result<Self> {
+        let changes = CONFIG.subscribe();
+        let camera = CONFIG.camera.load();
+        Ok(Self {
+            undistorter: Undistorter::new(&camera)?,
+            roi: configured_roi(&camera),
+            changes,
+        })
+    }
+
+    /// 设置相机实际生效的 ROI，相机应用配置后调用（见 [`camera::query_roi`]）
+    pub fn set_roi(&mut self, roi: Roi) {
+        self.roi = roi;
+    }
+
+    pub fn roi(&self) -> Roi {
+        self.roi
+    }
+
+    /// 处理尚未读取的配置变化，相机配置段被替换时更新校正参数与 ROI
+    fn refresh(&mut self) -> Result<()> {
+        // 读取全部通知，连续多次修改只更新一次
+        let changed = self
+            .changes
+            .try_iter()
+            .filter(|change| change.section == Section::Camera)
+            .count()
+            > 0;
+        if changed {
+            let camera = CONFIG.camera.load();
+            self.undistorter.reconfigure(&camera)?;
+            // 相机重新应用配置后会通过 set_roi 报告实际取值
+            self.roi = configured_roi(&camera);
+        }
+        Ok(())
+    }
+
+    /// 检测前处理整帧图像，校正方式为 `frame` 时进行重映射
+    pub fn frame(&mut self, frame: &mut Mat) -> Result<()> {
+        self.refresh()?;
+        self.roi = Roi {
+            width: frame.cols(),
+            height: frame.rows(),
+            ..self.roi
+        };
+        self.undistorter.process_frame(self.roi, frame)
+    }
+
+    /// 校正检测得到的关键点，校正方式为 `points` 时生效
+    pub fn points(&mut self, points: &mut Vector<Point2f>) -> Result<()> {
+        self.refresh()?;
+        self.undistorter.process_points(self.roi, points)
+    }
+}
@@ -0,0 +1,235 @@
+//! 镜头畸变校正预处理
+//!
+//! 根据 `Param.toml` 中 `[camera]` 的内参（`camera_matrix`）与畸变系数（`dist_coeffs`）对采集结果进行畸变校正，
+//! 校正方式由 `camera.undistort` 选择：
+//! - `frame`：使用 `initUndistortRectifyMap` 生成重映射表，对整帧图像执行 `remap`；
+//! - `points`：图像保持原样，仅对检测得到的关键点执行 `undistortPoints`；
+//! - `none`：不做任何处理。
+//!
+//! 内参按传感器全分辨率标定，ROI 偏移会平移主点。重映射表按分辨率与 ROI 缓存，
+//! 相机的 Width/Height/OffsetX/OffsetY 发生变化时自动重建。相机配置修改时的更新由
+//! [`Preprocessor`](crate::Preprocessor) 负责。
+
+use anyhow::Result;
+use camera::Roi;
+use config::{UndistortMode, CONFIG};
+use log::info;
+use opencv::{self as cv, core::*};
+
+/// 畸变校正器，缓存当前 ROI 下的内参矩阵与重映射表
+pub struct Undistorter {
+    mode: UndistortMode,
+    /// 全分辨率下的内参矩阵（行优先）
+    intrinsics: [f64; 9],
+    dist_coeffs: Mat,
+    /// 当前缓存对应的 ROI，为 `None` 时表示尚未生成
+    roi: Option<Roi>,
+    /// 平移主点后的内参矩阵
+    camera_matrix: Mat,
+    map1: Mat,
+    map2: Mat,
+    /// 整帧重映射的输出缓冲区，与输入帧交换以避免每帧分配
+    buffer: Mat,
+}
+
+impl Undistorter {
+    pub fn new(camera: &config::Camera) -> Result<Self> {
+        Ok(Self {
+            mode: camera.undistort,
+            intrinsics: camera.camera_matrix,
+            dist_coeffs: Mat::from_slice_2d(&[camera.dist_coeffs])?,
+            roi: None,
+            camera_matrix: Mat::default(),
+            map1: Mat::default(),
+            map2: Mat::default(),
+            buffer: Mat::default(),
+        })
+    }
+
+    /// 使用全局配置中的相机参数构造
+    pub fn from_config() -> Result<Self> {
+        Self::new(&CONFIG.camera.load())
+    }
+
+    pub fn mode(&self) -> UndistortMode {
+        self.mode
+    }
+
+    /// 按新的相机配置更新校正方式、内参与畸变系数，发生变化时在下一帧重建校正参数
+    pub fn reconfigure(&mut self, camera: &config::Camera) -> Result<()> {
+        let dist_coeffs = Mat::from_slice_2d(&[camera.dist_coeffs])?;
+        let unchanged = self.mode == camera.undistort
+            && self.intrinsics == camera.camera_matrix
+            && self.dist_coeffs.data_typed::<f64>()? == dist_coeffs.data_typed::<f64>()?;
+        if !unchanged {
+            self.mode = camera.undistort;
+            self.intrinsics = camera.camera_matrix;
+            self.dist_coeffs = dist_coeffs;
+            self.roi = None;
+        }
+        Ok(())
+    }
+
+    /// 确保缓存与给定 ROI 一致，ROI 变化时重建内参矩阵与重映射表
+    fn prepare(&mut self, roi: Roi) -> Result<()> {
+        if self.roi == Some(roi) {
+            return Ok(());
+        }
+        let k = self.intrinsics;
+        self.camera_matrix = Mat::from_slice_2d(&[
+            [k[0], k[1], k[2] - roi.offset_x as f64],
+            [k[3], k[4], k[5] - roi.offset_y as f64],
+            [k[6], k[7], k[8]],
+        ])?;
+        if self.mode == UndistortMode::Frame {
+            cv::calib3d::init_undistort_rectify_map(
+                &self.camera_matrix,
+                &self.dist_coeffs,
+                &no_array(),
+                &self.camera_matrix,
+                Size::new(roi.width, roi.height),
+                CV_16SC2,
+                &mut self.map1,
+                &mut self.map2,
+            )?;
+        }
+        info!(
+            "[畸变校正] 已按ROI重建校正参数：{}x{}，偏移({}, {})",
+            roi.width, roi.height, roi.offset_x, roi.offset_y
+        );
+        self.roi = Some(roi);
+        Ok(())
+    }
+
+    /// 校正方式为 `frame` 时对整帧图像进行重映射，其余方式下不做处理
+    pub fn process_frame(&mut self, roi: Roi, frame: &mut Mat) -> Result<()> {
+        if self.mode != UndistortMode::Frame {
+            return Ok(());
+        }
+        self.prepare(roi)?;
+        cv::imgproc::remap(
+            frame,
+            &mut self.buffer,
+            &self.map1,
+            &self.map2,
+            cv::imgproc::INTER_LINEAR,
+            BORDER_CONSTANT,
+            Scalar::default(),
+        )?;
+        std::mem::swap(frame, &mut self.buffer);
+        Ok(())
+    }
+
+    /// 校正方式为 `points` 时对关键点（像素坐标）进行校正，其余方式下不做处理
+    pub fn process_points(&mut self, roi: Roi, points: &mut Vector<Point2f>) -> Result<()> {
+        if self.mode != UndistortMode::Points || points.is_empty() {
+            return Ok(());
+        }
+        self.prepare(roi)?;
+        let mut undistorted = Vector::<Point2f>::with_capacity(points.len());
+        cv::calib3d::undistort_points(
+            points,
+            &mut undistorted,
+            &self.camera_matrix,
+            &self.dist_coeffs,
+            &no_array(),
+            &self.camera_matrix,
+        )?;
+        *points = undistorted;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(undistort: UndistortMode) -> config::Camera {
+        config::Camera {
+            camera_matrix: [800.0, 0.0, 720.0, 0.0, 800.0, 540.0, 0.0, 0.0, 1.0],
+            dist_coeffs: [0.0; 5],
+            undistort,
+            ..Default::default()
+        }
+    }
+
+    fn roi(offset_x: i32, offset_y: i32) -> Roi {
+        Roi {
+            width: 64,
+            height: 48,
+            offset_x,
+            offset_y,
+        }
+    }
+
+    #[test]
+    fn test_identity_frame() {
+        let mut undistorter = Undistorter::new(&camera(UndistortMode::Frame)).unwrap();
+        let mut frame = Mat::new_rows_cols_with_default(48, 64, CV_8UC1, Scalar::all(0.0)).unwrap();
+        for row in 0..48 {
+            for col in 0..64 {
+                *frame.at_2d_mut::<u8>(row, col).unwrap() = ((row * 64 + col) % 251) as u8;
+            }
+        }
+        let original = frame.try_clone().unwrap();
+        undistorter.process_frame(roi(0, 0), &mut frame).unwrap();
+        assert_eq!(frame.size().unwrap(), original.size().unwrap());
+        assert_eq!(frame.data_bytes().unwrap(), original.data_bytes().unwrap());
+    }
+
+    #[test]
+    fn test_identity_points() {
+        let mut undistorter = Undistorter::new(&camera(UndistortMode::Points)).unwrap();
+        let original = Vector::<Point2f>::from_iter([
+            Point2f::new(0.0, 0.0),
+            Point2f::new(12.5, 30.25),
+            Point2f::new(63.0, 47.0),
+        ]);
+        let mut points = original.clone();
+        undistorter
+            .process_points(roi(100, 80), &mut points)
+            .unwrap();
+        for (point, expected) in points.iter().zip(original.iter()) {
+            assert!((point.x - expected.x).abs() < 1e-3);
+            assert!((point.y - expected.y).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_roi_change_rebuilds_map() {
+        let mut camera = camera(UndistortMode::Frame);
+        camera.dist_coeffs = [-0.2, 0.05, 0.0, 0.0, 0.0];
+        let mut undistorter = Undistorter::new(&camera).unwrap();
+        let mut frame = Mat::new_rows_cols_with_default(48, 64, CV_8UC1, Scalar::all(1.0)).unwrap();
+
+        undistorter.process_frame(roi(0, 0), &mut frame).unwrap();
+        let map = undistorter.map1.try_clone().unwrap();
+        assert_eq!(undistorter.roi, Some(roi(0, 0)));
+
+        // ROI 不变时复用重映射表
+        undistorter.process_frame(roi(0, 0), &mut frame).unwrap();
+        assert_eq!(
+            undistorter.map1.data_bytes().unwrap(),
+            map.data_bytes().unwrap()
+        );
+
+        // 偏移变化后主点平移，重映射表随之重建
+        undistorter
+            .process_frame(roi(200, 100), &mut frame)
+            .unwrap();
+        assert_eq!(undistorter.roi, Some(roi(200, 100)));
+        assert_eq!(
+            *undistorter.camera_matrix.at_2d::<f64>(0, 2).unwrap(),
+            720.0 - 200.0
+        );
+        assert_ne!(
+            undistorter.map1.data_bytes().unwrap(),
+            map.data_bytes().unwrap()
+        );
+
+        // 相机配置变化时同样重建
+        camera.dist_coeffs = [0.0; 5];
+        undistorter.reconfigure(&camera).unwrap();
+        assert_eq!(undistorter.roi, None);
+    }
+}
@@ -1,108 +1,321 @@
+mod remote;
+
+use anyhow::Context;
 use config::CONFIG;
-use crossbeam_channel::{select, unbounded, RecvTimeoutError};
+use crossbeam_channel::unbounded;
+use log::{error, info, warn};
 use opencv::{self as cv, core::*};
 use rand::{rngs::ThreadRng, Rng};
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use std::{
     io,
-    marker::PhantomData,
-    net::TcpListener,
+    net::{TcpListener, TcpStream},
     sync::OnceLock,
     thread,
     time::{Duration, Instant},
 };
-use ultraviolet::Vec3;
-use utility::{ensure_or_stop, expect_or_stop, is_stopped, stop_all, unwrap_or_stop};
+use tungstenite::WebSocket;
+use utility::{metrics, rt, Backoff, RestartPolicy, Supervisor};
+
+/// 延迟统计广播的 identifier
+pub const LATENCY: &str = "trace.latency";
+/// 延迟统计的广播间隔，每次广播后重新统计
+const LATENCY_INTERVAL: Duration = Duration::from_secs(1);
+/// 看门狗事件的 identifier
+pub const WATCHDOG: &str = "watchdog.event";
+/// 指标快照的 identifier，推送间隔由 `gui.metrics_interval` 配置
+pub const METRICS: &str = "metrics.snapshot";
+/// 日志记录的 identifier
+pub const LOG: &str = "log.record";
+/// 尚未转发的日志记录的上限，超出时丢弃新的日志
+const LOG_BACKLOG: usize = 1024;
+/// 等待客户端发送 WebSocket 握手请求的时间
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 可视化服务，监督者随服务一直存活
+struct Server {
+    sender: crossbeam_channel::Sender<tungstenite::Message>,
+    _supervisor: Supervisor,
+}
+
+static SERVER: OnceLock<Server> = OnceLock::new();
 
-static SENDER: OnceLock<crossbeam_channel::Sender<tungstenite::Message>> = OnceLock::new();
+/// 启动可视化服务，已启动时不做任何事
+///
+/// 远程配置、看门狗事件、指标快照、日志与延迟统计都经由可视化服务发送给客户端，应在
+/// `CONFIG.init` 之后尽早调用。各模块首次发送数据时同样会启动服务。
+pub fn start() {
+    sender();
+}
+
+fn sender() -> &'static crossbeam_channel::Sender<tungstenite::Message> {
+    &SERVER
+        .get_or_init(|| {
+            let (sender, receiver) = unbounded();
+            let supervisor =
+                launch_server(receiver).expect("[可视化][ERR-07] 无法创建可视化服务线程");
+            Server {
+                sender,
+                _supervisor: supervisor,
+            }
+        })
+        .sender
+}
 
 fn send(msg: tungstenite::Message) -> anyhow::Result<()> {
-    let sender = SENDER.get_or_init(|| {
-        let (sender, receiver) = unbounded();
-        launch_server(receiver);
-        sender
-    });
-    Ok(sender.send(msg)?)
-}
-
-fn launch_server(receiver: crossbeam_channel::Receiver<tungstenite::Message>) {
-    let server = unwrap_or_stop!(TcpListener::bind("0.0.0.0:25801"));
-    server
-        .set_nonblocking(true)
-        .expect("[可视化][ERR-01] 无法设置TCP Server为非阻塞");
-
-    thread::spawn(move || {
-        for res in server.incoming() {
-            if matches!(res, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock) {
-                thread::sleep(Duration::from_millis(100));
-                continue;
+    Ok(sender().send(msg)?)
+}
+
+/// 在监督者下运行可视化服务，出错或 panic 时断开全部客户端并重新监听
+fn launch_server(
+    receiver: crossbeam_channel::Receiver<tungstenite::Message>,
+) -> io::Result<Supervisor> {
+    let changes = CONFIG.subscribe();
+    let stalls = utility::watchdog::subscribe();
+    let logs = utility::logging::subscribe(LOG_BACKLOG);
+    let mut supervisor = Supervisor::new("可视化服务");
+    // 线程名称与实时配置的登记名称一致，以应用 rt.gui_core 与 rt.gui_priority
+    supervisor.spawn(
+        rt::GUI,
+        RestartPolicy::OnFailure(Backoff::default()),
+        move |token| {
+            let server =
+                TcpListener::bind("0.0.0.0:25801").context("[可视化][ERR-01] 无法监听端口")?;
+            server
+                .set_nonblocking(true)
+                .context("[可视化][ERR-01] 无法设置TCP Server为非阻塞")?;
+            let mut clients: Vec<WebSocket<TcpStream>> = Vec::new();
+            let mut latency_sent = Instant::now();
+            let mut metrics_sent = Instant::now();
+            while !token.is_cancelled() {
+                // 单个连接失败时只丢弃该连接，不影响其他客户端
+                match server.accept() {
+                    Ok((stream, addr)) => {
+                        if let Some(websocket) = handshake(stream) {
+                            info!("[可视化] 客户端{}已连接", addr);
+                            clients.push(websocket);
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => warn!("[可视化][ERR-02] 建立TCP连接时出错：{}", e),
+                }
+
+                // 转发各模块发出的数据、配置变化、看门狗事件、日志、延迟统计与指标快照
+                let mut messages: Vec<_> = receiver.try_iter().collect();
+                for change in changes.try_iter() {
+                    match remote::changed(change.section) {
+                        Ok(message) => messages.push(message),
+                        Err(e) => error!("[可视化] 编码配置变化失败：{}", e),
+                    }
+                }
+                for event in stalls.try_iter() {
+                    match encode_named(WATCHDOG, event) {
+                        Ok(message) => messages.push(message),
+                        Err(e) => error!("[可视化] 编码看门狗事件失败：{}", e),
+                    }
+                }
+                for record in logs.try_iter() {
+                    match encode_named(LOG, record) {
+                        Ok(message) => messages.push(message),
+                        Err(e) => error!("[可视化] 编码日志失败：{}", e),
+                    }
+                }
+                if latency_sent.elapsed() >= LATENCY_INTERVAL {
+                    latency_sent = Instant::now();
+                    let summary = utility::trace::take_summary();
+                    if summary.end_to_end.count > 0 {
+                        match encode_named(LATENCY, summary) {
+                            Ok(message) => messages.push(message),
+                            Err(e) => error!("[可视化] 编码延迟统计失败：{}", e),
+                        }
+                    }
+                }
+                let metrics_interval =
+                    Duration::from_millis(CONFIG.gui.load().metrics_interval as u64);
+                if metrics_sent.elapsed() >= metrics_interval {
+                    metrics_sent = Instant::now();
+                    let snapshot = metrics::snapshot();
+                    if !snapshot.is_empty() {
+                        match encode_named(METRICS, snapshot) {
+                            Ok(message) => messages.push(message),
+                            Err(e) => error!("[可视化] 编码指标快照失败：{}", e),
+                        }
+                    }
+                }
+                let idle = messages.is_empty();
+                for message in messages {
+                    clients.retain_mut(|client| send_to(client, message.clone()));
+                }
+
+                // 处理客户端的配置请求
+                clients.retain_mut(poll_client);
+                if idle {
+                    thread::sleep(Duration::from_millis(10));
+                }
             }
-            let stream = expect_or_stop!(res, "[可视化][ERR-02] 建立TCP连接时出错");
-            let mut websocket = expect_or_stop!(
-                tungstenite::accept(stream),
-                "[可视化][ERR-03] 无法建立WebSocket连接"
+            Ok(())
+        },
+    )?;
+    Ok(supervisor)
+}
+
+/// 与新连接进行 WebSocket 握手，完成后将连接设置为非阻塞
+///
+/// # 返回值
+/// 握手失败时输出警告并返回 `None`
+fn handshake(stream: TcpStream) -> Option<WebSocket<TcpStream>> {
+    let addr = stream.peer_addr().ok();
+    // 握手期间阻塞读取，设置超时以免不发送握手请求的连接阻塞服务
+    let result = stream
+        .set_nonblocking(false)
+        .and_then(|()| stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)));
+    if let Err(e) = result {
+        warn!("[可视化][ERR-06] 无法设置客户端{:?}的连接：{}", addr, e);
+        return None;
+    }
+    let websocket = match tungstenite::accept(stream) {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            warn!(
+                "[可视化][ERR-03] 无法与客户端{:?}建立WebSocket连接：{}",
+                addr, e
             );
+            return None;
+        }
+    };
+    let result = websocket
+        .get_ref()
+        .set_read_timeout(None)
+        .and_then(|()| websocket.get_ref().set_nonblocking(true));
+    if let Err(e) = result {
+        warn!(
+            "[可视化][ERR-06] 无法设置客户端{:?}的连接为非阻塞：{}",
+            addr, e
+        );
+        return None;
+    }
+    Some(websocket)
+}
 
-            while !is_stopped() {
-                let res = receiver.recv_timeout(Duration::from_millis(10));
-                if matches!(res, Err(RecvTimeoutError::Timeout)) {
-                    continue;
-                }
-                let message = expect_or_stop!(res, "[可视化][ERR-04] Channel接收消息失败");
+/// 以具名字段编码消息
+fn encode_named<T: Serialize>(
+    identifier: &str,
+    data: T,
+) -> Result<tungstenite::Message, rmp_serde::encode::Error> {
+    let payload = rmp_serde::to_vec_named(&Message { identifier, data })?;
+    Ok(tungstenite::Message::Binary(payload.into()))
+}
 
-                let res = websocket.send(message);
-                if matches!(res, Err(tungstenite::Error::ConnectionClosed)) {
-                    break;
+/// 向客户端发送消息
+///
+/// # 返回值
+/// 连接已断开时返回 `false`
+fn send_to(client: &mut WebSocket<TcpStream>, message: tungstenite::Message) -> bool {
+    match client.send(message) {
+        Ok(()) => true,
+        // 消息已进入发送缓冲区，之后的循环中继续发送
+        Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => true,
+        Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => false,
+        Err(e) => {
+            error!("[可视化][ERR-05] Websocket 发送消息失败，断开连接：{}", e);
+            false
+        }
+    }
+}
+
+/// 读取并处理客户端发来的全部消息，并继续发送缓冲区中的数据
+///
+/// # 返回值
+/// 连接已断开时返回 `false`
+fn poll_client(client: &mut WebSocket<TcpStream>) -> bool {
+    loop {
+        match client.read() {
+            Ok(tungstenite::Message::Binary(payload)) => match remote::handle(&payload) {
+                Ok(response) => {
+                    if !send_to(client, response) {
+                        return false;
+                    }
                 }
-                expect_or_stop!(res, "[可视化][ERR-05] Websocket 发送消息失败");
+                Err(e) => error!("[可视化] 编码配置响应失败：{}", e),
+            },
+            Ok(tungstenite::Message::Text(_)) => {
+                warn!("[可视化] 忽略文本消息，配置请求须以MessagePack编码的二进制消息发送")
+            }
+            // Ping、Close 等控制帧由 tungstenite 自动回复
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                info!("[可视化] 客户端已断开连接");
+                return false;
             }
-            if is_stopped() {
-                break;
+            Err(e) => {
+                warn!("[可视化] 读取客户端消息失败，断开连接：{}", e);
+                return false;
             }
         }
-    });
+    }
+    match client.flush() {
+        Ok(()) => true,
+        Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => true,
+        Err(_) => false,
+    }
 }
 
+/// 估计数据流的帧率并按 `max_fps` 随机限流
+///
+/// 估计的帧率与实际发送的数量以 `stream` 标签登记为 `server_stream_fps` 仪表与
+/// `server_stream_sent_total` 计数器。
 pub struct FPSMonitor {
     rng: ThreadRng,
     cnt: u8,
     fps: Option<f32>,
     start: Instant,
     max_fps: u8,
+    fps_gauge: metrics::Gauge,
+    sent: metrics::Counter,
 }
 
 impl FPSMonitor {
     const ESTIMATE_CNT: u8 = 100;
 
-    pub fn new(max_fps: u8) -> Self {
+    pub fn new(identifier: &str, max_fps: u8) -> Self {
+        let labels = [("stream", identifier)];
         Self {
             rng: rand::rng(),
             cnt: 0,
             fps: None,
             start: Instant::now(),
             max_fps,
+            fps_gauge: metrics::gauge("server_stream_fps", &labels),
+            sent: metrics::counter("server_stream_sent_total", &labels),
         }
     }
 
     pub fn limit(&mut self) -> bool {
         self.cnt += 1;
         if self.cnt >= Self::ESTIMATE_CNT {
-            self.fps = Some(Self::ESTIMATE_CNT as f32 / self.start.elapsed().as_secs_f32());
+            let fps = Self::ESTIMATE_CNT as f32 / self.start.elapsed().as_secs_f32();
+            self.fps = Some(fps);
+            self.fps_gauge.set(fps as f64);
             self.cnt = 0;
             self.start = Instant::now();
         }
-        if let Some(fps) = self.fps {
+        let pass = if let Some(fps) = self.fps {
             let probability = self.max_fps as f32 / fps;
             self.rng.random::<f32>() < probability
         } else {
             false
+        };
+        if pass {
+            self.sent.inc();
         }
+        pass
     }
 }
 
 #[derive(Serialize)]
 struct Message<'a, T> {
-    identifier: &'a String,
+    identifier: &'a str,
     data: T,
 }
 
@@ -137,7 +350,7 @@ pub struct PeriodicSender {
 impl PeriodicSender {
     pub fn new(identifier: String, max_fps: u8) -> Self {
         Self {
-            fps_monitor: FPSMonitor::new(max_fps),
+            fps_monitor: FPSMonitor::new(&identifier, max_fps),
             identifier,
         }
     }
@@ -168,7 +381,7 @@ pub struct ImageSender {
 impl ImageSender {
     pub fn new(identifier: String, max_fps: u8) -> Self {
         Self {
-            fps_monitor: FPSMonitor::new(max_fps),
+            fps_monitor: FPSMonitor::new(&identifier, max_fps),
             identifier,
             buffer: Vector::new(),
         }
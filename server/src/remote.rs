@@ -0,0 +1,208 @@
+//! 远程读写配置
+//!
+//! 客户端通过 WebSocket 发送 MessagePack 编码的 [`Request`]（以 `type` 字段区分请求类型），
+//! 服务端以 identifier 为 [`RESPONSE`] 的消息回复同一 `id` 的 [`Response`]。字段的修改与运行时
+//! 调用 `CONFIG.update` 一样经过校验后才会生效；任何配置段发生变化（远程修改、热重载、切换
+//! 配置档等）时，服务端向全部客户端广播 identifier 为 [`CHANGED`] 的消息，内容为该配置段的
+//! 最新取值。
+//!
+//! 客户端可通过 `history` 请求读取配置的修改历史，并通过 `revert` 请求撤销某条记录之后的全部
+//! 修改，撤销后的变化同样会广播给全部客户端。
+//!
+//! 修改 `log.filter` 字段即可在运行时调整日志的过滤规则。
+//!
+//! 与其他可视化数据不同，配置相关的消息以具名字段（MessagePack map）编码，便于客户端按名称读取。
+use config::{ChangeRecord, ChangeSource, ConfigError, FieldError, FieldSpec, Section, CONFIG};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::encode_named;
+
+/// 配置请求响应的 identifier
+pub const RESPONSE: &str = "config.response";
+/// 配置变化广播的 identifier
+pub const CHANGED: &str = "config.changed";
+
+/// 客户端发送的配置请求
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    /// 获取全部配置段的字段、取值、来源与约束
+    Get { id: u64 },
+    /// 修改单个字段，`persist` 为真时修改成功后保存到配置文件
+    ///
+    /// 修改生效但保存失败时响应的 `ok` 为真，并在 `warning` 中说明原因。
+    Set {
+        id: u64,
+        section: Section,
+        field: String,
+        value: toml::Value,
+        #[serde(default)]
+        persist: bool,
+    },
+    /// 将当前配置保存到配置文件
+    Save { id: u64 },
+    /// 获取配置的修改历史
+    History { id: u64 },
+    /// 撤销序号大于 `seq` 的全部修改
+    Revert { id: u64, seq: u64 },
+}
+
+/// 配置段的描述与当前取值
+#[derive(Serialize, Debug)]
+pub struct SectionTree {
+    pub section: Section,
+    pub description: String,
+    pub fields: Vec<FieldNode>,
+}
+
+/// 单个字段的约束、当前取值与来源
+#[derive(Serialize, Debug)]
+pub struct FieldNode {
+    #[serde(flatten)]
+    pub spec: FieldSpec,
+    pub value: Option<toml::Value>,
+    pub origin: Option<String>,
+}
+
+/// 对配置请求的响应
+#[derive(Serialize, Debug, Default)]
+pub struct Response {
+    /// 对应请求的 `id`，请求无法解析时为 0
+    pub id: u64,
+    pub ok: bool,
+    /// 失败原因
+    pub error: Option<String>,
+    /// 请求已生效但未完全完成时的说明，如修改已生效但未能保存到配置文件
+    pub warning: Option<String>,
+    /// 校验失败的全部字段
+    pub errors: Vec<FieldError>,
+    /// `get` 请求返回的配置树
+    pub tree: Option<Vec<SectionTree>>,
+    /// `history` 请求返回的修改历史，按时间从旧到新排列
+    pub history: Option<Vec<ChangeRecord>>,
+}
+
+impl Response {
+    fn ok(id: u64) -> Self {
+        Self {
+            id,
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    fn err(id: u64, err: ConfigError) -> Self {
+        let errors = match &err {
+            ConfigError::Validation(errors) => errors.clone(),
+            _ => Vec::new(),
+        };
+        Self {
+            id,
+            ok: false,
+            error: Some(err.to_string()),
+            errors,
+            ..Default::default()
+        }
+    }
+}
+
+/// 读取某个配置段的描述与当前取值
+pub fn section_tree(section: Section) -> SectionTree {
+    let effective = CONFIG.effective();
+    let values = effective
+        .table
+        .get(section.name())
+        .and_then(toml::Value::as_table);
+    let fields = section
+        .fields()
+        .into_iter()
+        .map(|spec| {
+            let path = format!("{}.{}", section, spec.name);
+            FieldNode {
+                value: values.and_then(|values| values.get(spec.name)).cloned(),
+                origin: effective.origin(&path).map(ToString::to_string),
+                spec,
+            }
+        })
+        .collect();
+    SectionTree {
+        section,
+        description: section.description(),
+        fields,
+    }
+}
+
+fn handle_request(request: Request) -> Response {
+    match request {
+        Request::Get { id } => Response {
+            tree: Some(Section::ALL.into_iter().map(section_tree).collect()),
+            ..Response::ok(id)
+        },
+        Request::Set {
+            id,
+            section,
+            field,
+            value,
+            persist,
+        } => {
+            if let Err(err) = CONFIG.set_field(section, &field, value, ChangeSource::Websocket) {
+                warn!(
+                    "[可视化] 远程修改配置 `{}.{}` 失败：{}",
+                    section, field, err
+                );
+                return Response::err(id, err);
+            }
+            info!("[可视化] 远程修改配置 `{}.{}`", section, field);
+            if persist {
+                // 修改已经生效，保存失败时仍然成功，以警告告知客户端修改尚未保存
+                if let Err(err) = config::try_save_config() {
+                    warn!(
+                        "[可视化] 远程修改配置 `{}.{}` 已生效，但保存失败：{}",
+                        section, field, err
+                    );
+                    return Response {
+                        warning: Some(format!("修改已生效，但未能保存到配置文件：{}", err)),
+                        ..Response::ok(id)
+                    };
+                }
+            }
+            Response::ok(id)
+        }
+        Request::Save { id } => match config::try_save_config() {
+            Ok(()) => Response::ok(id),
+            Err(err) => Response::err(id, err),
+        },
+        Request::History { id } => Response {
+            history: Some(CONFIG.history()),
+            ..Response::ok(id)
+        },
+        Request::Revert { id, seq } => match CONFIG.revert(seq) {
+            Ok(_) => {
+                info!("[可视化] 远程撤销配置到序号{}", seq);
+                Response::ok(id)
+            }
+            Err(err) => {
+                warn!("[可视化] 远程撤销配置失败：{}", err);
+                Response::err(id, err)
+            }
+        },
+    }
+}
+
+/// 处理客户端发来的二进制消息，返回编码后的响应
+pub(crate) fn handle(payload: &[u8]) -> anyhow::Result<tungstenite::Message> {
+    let response = match rmp_serde::from_slice::<Request>(payload) {
+        Ok(request) => handle_request(request),
+        Err(err) => Response {
+            error: Some(format!("无法解析配置请求：{}", err)),
+            ..Default::default()
+        },
+    };
+    Ok(encode_named(RESPONSE, response)?)
+}
+
+/// 编码配置段变化的广播消息
+pub(crate) fn changed(section: Section) -> anyhow::Result<tungstenite::Message> {
+    Ok(encode_named(CHANGED, section_tree(section))?)
+}
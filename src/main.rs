@@ -1,18 +1,65 @@
 use std::{
+    net::SocketAddr,
     sync::{atomic::AtomicBool, Arc},
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
+use config::{LoadOptions, Section, CONFIG};
 use log::{debug, error, info, warn};
 
-use utility::stop_all;
+use utility::{
+    logging, metrics, root,
+    rt::{self, ThreadConfig},
+    trace, CancelReason,
+};
+
+/// 启动后检查实时配置是否都有同名线程的等待时间
+const RT_CHECK_DELAY: Duration = Duration::from_secs(5);
 
 fn main() {
-    env_logger::init();
+    logging::init();
+    if let Err(err) = logging::install_signal_handler() {
+        warn!("无法安装日志级别切换信号：{}", err);
+    }
+
+    let mut options = LoadOptions::from_env();
+    let args = options
+        .parse_args(std::env::args().skip(1))
+        .unwrap_or_else(|err| panic!("{}", err));
+    // 子命令 schema：打印配置的 JSON Schema，无需读取配置文件
+    if args.first().map(String::as_str) == Some("schema") {
+        println!("{:#}", config::json_schema());
+        return;
+    }
+    CONFIG.init(options);
+    if args.iter().any(|arg| arg == "--print-config") {
+        print!("{}", CONFIG.effective());
+        return;
+    }
+    debug!("生效配置：\n{}", CONFIG.effective());
+    // --trace <path>：将逐帧的延迟时间线写入 Chrome trace 格式的文件
+    if let Some(index) = args.iter().position(|arg| arg == "--trace") {
+        let path = args.get(index + 1).expect("--trace 缺少文件路径");
+        trace::dump_to(path).unwrap_or_else(|err| panic!("无法创建追踪文件{}：{}", path, err));
+    }
+    configure_logging();
+    configure_rt();
+    // 远程配置、看门狗事件、指标与日志均经由可视化服务发送
+    #[cfg(feature = "gui")]
+    server::start();
+    let prometheus_port = CONFIG.gui.load().prometheus_port;
+    if prometheus_port != 0 {
+        let addr = SocketAddr::from(([127, 0, 0, 1], prometheus_port));
+        if let Err(err) = metrics::serve_prometheus(addr, root().child("指标")) {
+            warn!("无法在{}提供指标：{}", addr, err);
+        }
+    }
+    let _watcher = CONFIG.watch(Duration::from_millis(500));
 
     ctrlc::set_handler({
         move || {
-            stop_all();
+            root().cancel(CancelReason::Signal);
         }
     })
     .expect("Error setting Ctrl-C handler");
@@ -21,4 +68,73 @@ fn main() {
     // handle.join().unwrap().unwrap_or_else(|err| {
     //     error!("检测器异常退出: {}", err);
     // });
+    if let Err(err) = trace::stop_dump() {
+        warn!("写入追踪文件失败：{}", err);
+    }
+}
+
+/// 按配置锁定进程内存，并登记各线程的实时配置
+fn configure_rt() {
+    let config = CONFIG.rt.load();
+    if config.lock_memory {
+        rt::lock_memory();
+    }
+    let threads = [
+        (rt::CAMERA, config.camera_core, config.camera_priority),
+        (rt::DETECT, config.detect_core, config.detect_priority),
+        (rt::TRACK, config.track_core, config.track_priority),
+        (rt::GUI, config.gui_core, config.gui_priority),
+    ];
+    for (name, core, priority) in threads {
+        let mut thread = ThreadConfig::new();
+        if core >= 0 {
+            thread = thread.cores(&[core as usize]);
+        }
+        if priority > 0 {
+            thread = thread.priority(priority);
+        }
+        rt::configure(name, thread);
+    }
+
+    // 启动完成后检查登记的名称是否都有同名线程，避免配置因名称不符而静默失效
+    thread::spawn(|| {
+        thread::sleep(RT_CHECK_DELAY);
+        for name in rt::unmatched() {
+            warn!("线程{}的实时配置未生效：启动后没有同名的线程", name);
+        }
+    });
+}
+
+/// 按配置写入日志文件并设置过滤规则，之后配置中的过滤规则修改时随之更新
+fn configure_logging() {
+    let config = CONFIG.log.load();
+    if !config.file.is_empty() {
+        let max_bytes = config.max_file_size as u64 * 1024 * 1024;
+        if let Err(err) = logging::log_to_file(&config.file, max_bytes, config.max_files as usize) {
+            warn!("无法写入日志文件{}：{}", config.file, err);
+        }
+    }
+    // 设置了 RUST_LOG 时启动时以环境变量为准
+    if std::env::var_os("RUST_LOG").is_none() {
+        if let Err(err) = logging::set_filter(&config.filter) {
+            warn!("日志过滤规则{}不合法：{}", config.filter, err);
+        }
+    }
+
+    let changes = CONFIG.subscribe();
+    thread::spawn(move || {
+        for change in changes {
+            if change.section != Section::Log {
+                continue;
+            }
+            let filter = CONFIG.log.load().filter.clone();
+            if filter == logging::filter() {
+                continue;
+            }
+            match logging::set_filter(&filter) {
+                Ok(()) => info!("日志过滤规则已修改为{}", filter),
+                Err(err) => warn!("日志过滤规则{}不合法：{}", filter, err),
+            }
+        }
+    });
 }
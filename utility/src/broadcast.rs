@@ -0,0 +1,493 @@
+//! 广播管道
+//!
+//! 同一帧数据需要送往多个消费者（检测、录像、图传）时使用。发送端从缓冲池中取出缓冲区写入
+//! 数据后，以引用计数的只读 [`Frame`] 分发给全部消费者，所有消费者都释放后缓冲区才回到池中
+//! 复用。每个消费者拥有独立的有界队列与 [`DropPolicy`]，慢速的消费者只会丢失自己的帧，不会
+//! 拖慢发送端与其他消费者。
+//!
+//! ```rust
+//! use utility::{BroadcastBuilder, DropPolicy};
+//!
+//! let mut sender = BroadcastBuilder::<Vec<u8>>::new().pool(6).build();
+//! let detector = sender.subscribe("检测", 1, DropPolicy::DropOldest);
+//! let recorder = sender.subscribe("录像", 2, DropPolicy::DropNewest);
+//!
+//! sender.get_send_buffer().push(1);
+//! sender.send().unwrap();
+//! assert_eq!(*detector.recv().unwrap(), [1]);
+//! assert_eq!(*recorder.recv().unwrap(), [1]);
+//! ```
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use crossbeam_channel::{
+    bounded, unbounded as channel, Receiver, RecvTimeoutError, Sender, TryRecvError, TrySendError,
+};
+
+use crate::{metrics, Timestamp};
+
+/// 消费者队列已满时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// 丢弃队列中最旧的帧，适用于只关心最新数据的消费者
+    DropOldest,
+    /// 丢弃新到达的帧，适用于需要连续数据的消费者
+    DropNewest,
+    /// 阻塞发送端直到队列有空位，会拖慢全部消费者，慎用
+    Block,
+}
+
+/// 只读的共享帧，全部克隆都被丢弃后缓冲区回到池中
+pub struct Frame<T> {
+    lease: Arc<Lease<T>>,
+}
+
+struct Lease<T> {
+    dish: Option<Box<T>>,
+    refund: Sender<Box<T>>,
+}
+
+impl<T> Drop for Lease<T> {
+    fn drop(&mut self) {
+        if let Some(dish) = self.dish.take() {
+            // 发送端已关闭时直接释放
+            let _ = self.refund.send(dish);
+        }
+    }
+}
+
+impl<T> Clone for Frame<T> {
+    fn clone(&self) -> Self {
+        Self {
+            lease: self.lease.clone(),
+        }
+    }
+}
+
+impl<T> Deref for Frame<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.lease.dish.as_deref().expect("[Tube] 数据结构逻辑错误")
+    }
+}
+
+/// 消费者的统计信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumerStats {
+    pub name: String,
+    /// 已接收的帧数
+    pub received: u64,
+    /// 因队列已满而丢弃的帧数
+    pub dropped: u64,
+}
+
+/// 发送端的统计信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BroadcastStats {
+    /// 成功发送的帧数
+    pub sent: u64,
+    /// 因缓冲池耗尽而未能发送的帧数
+    pub dropped: u64,
+}
+
+struct ConsumerShared {
+    name: String,
+    received: metrics::Counter,
+    dropped: metrics::Counter,
+    /// 接收端是否已关闭
+    closed: AtomicBool,
+}
+
+impl ConsumerShared {
+    fn stats(&self) -> ConsumerStats {
+        ConsumerStats {
+            name: self.name.clone(),
+            received: self.received.get(),
+            dropped: self.dropped.get(),
+        }
+    }
+}
+
+struct Consumer<T> {
+    supply: Sender<Frame<T>>,
+    /// 用于丢弃最旧的帧
+    stale: Receiver<Frame<T>>,
+    policy: DropPolicy,
+    shared: Arc<ConsumerShared>,
+}
+
+/// 广播管道的构建器
+pub struct BroadcastBuilder<T, F = fn() -> T> {
+    pool: usize,
+    init: F,
+    metrics: Option<String>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Default> BroadcastBuilder<T> {
+    /// 以 `Default` 初始化缓冲区
+    pub fn new() -> Self {
+        Self::with_init(T::default)
+    }
+}
+
+impl<T: Default> Default for BroadcastBuilder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, F: FnMut() -> T> BroadcastBuilder<T, F> {
+    /// 以 `init` 的返回值初始化缓冲区
+    pub fn with_init(init: F) -> Self {
+        Self {
+            pool: 4,
+            init,
+            metrics: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// 缓冲区数量，默认为 4，至少为 2
+    ///
+    /// 应不少于各消费者队列容量之和加 2，否则消费者处理较慢时发送端会因缓冲池耗尽而丢帧。
+    pub fn pool(mut self, pool: usize) -> Self {
+        assert!(pool >= 2, "[Tube] 缓冲区数量至少为2，实际为{}", pool);
+        self.pool = pool;
+        self
+    }
+
+    /// 将统计信息登记到全局指标注册表，名称作为 `broadcast` 标签
+    ///
+    /// 发送端登记为 `broadcast_sent_total` 与 `broadcast_dropped_total`，各消费者以
+    /// `consumer` 标签登记为 `broadcast_received_total` 与 `broadcast_consumer_dropped_total`。
+    /// 同名的广播管道共享同一组计数器。
+    pub fn metrics(mut self, name: &str) -> Self {
+        self.metrics = Some(name.to_string());
+        self
+    }
+
+    /// 创建广播管道的发送端，通过 [`BroadcastSend::subscribe`] 添加消费者
+    pub fn build(mut self) -> BroadcastSend<T> {
+        let (refund, recycle) = channel();
+        let (sent, dropped) = match &self.metrics {
+            Some(name) => {
+                let labels = [("broadcast", name.as_str())];
+                (
+                    metrics::counter("broadcast_sent_total", &labels),
+                    metrics::counter("broadcast_dropped_total", &labels),
+                )
+            }
+            None => Default::default(),
+        };
+        BroadcastSend {
+            dishes: (0..self.pool).map(|_| Box::new((self.init)())).collect(),
+            refund,
+            recycle,
+            consumers: Vec::new(),
+            metrics: self.metrics,
+            sent,
+            dropped,
+        }
+    }
+}
+
+/// 广播管道的发送端
+pub struct BroadcastSend<T> {
+    /// 空闲的缓冲区，第一个用于写入
+    dishes: VecDeque<Box<T>>,
+    /// 交给帧用于归还缓冲区
+    refund: Sender<Box<T>>,
+    recycle: Receiver<Box<T>>,
+    consumers: Vec<Consumer<T>>,
+    /// 登记到全局指标注册表时使用的名称
+    metrics: Option<String>,
+    sent: metrics::Counter,
+    /// 因缓冲池耗尽而未能发送的帧数
+    dropped: metrics::Counter,
+}
+
+impl<T> BroadcastSend<T> {
+    /// 添加消费者
+    ///
+    /// # 参数
+    /// - `name`: 消费者名称，用于统计
+    /// - `capacity`: 队列容量，至少为 1
+    /// - `policy`: 队列已满时的处理方式
+    pub fn subscribe(
+        &mut self,
+        name: &str,
+        capacity: usize,
+        policy: DropPolicy,
+    ) -> BroadcastRecv<T> {
+        let (supply, fetch) = bounded(capacity.max(1));
+        let (received, dropped) = match &self.metrics {
+            Some(broadcast) => {
+                let labels = [("broadcast", broadcast.as_str()), ("consumer", name)];
+                (
+                    metrics::counter("broadcast_received_total", &labels),
+                    metrics::counter("broadcast_consumer_dropped_total", &labels),
+                )
+            }
+            None => Default::default(),
+        };
+        let shared = Arc::new(ConsumerShared {
+            name: name.to_string(),
+            received,
+            dropped,
+            closed: AtomicBool::new(false),
+        });
+        self.consumers.push(Consumer {
+            supply,
+            stale: fetch.clone(),
+            policy,
+            shared: shared.clone(),
+        });
+        BroadcastRecv { fetch, shared }
+    }
+
+    /// 获取用于写入的缓冲区
+    pub fn get_send_buffer(&mut self) -> &mut Box<T> {
+        self.dishes.front_mut().expect("[Tube] 数据结构逻辑错误")
+    }
+
+    /// 将缓冲区中的数据分发给全部消费者
+    ///
+    /// 缓冲池耗尽时丢弃本次数据，下次发送前将覆盖同一缓冲区。已关闭的消费者会被移除。
+    ///
+    /// # 返回值
+    /// - `Ok(())` 表示成功发送或已丢弃。
+    /// - `Err(anyhow::Error)` 表示全部消费者均已关闭。
+    pub fn send(&mut self) -> Result<(), anyhow::Error> {
+        self.consumers
+            .retain(|consumer| !consumer.shared.closed.load(Ordering::Relaxed));
+        if self.consumers.is_empty() {
+            return Err(anyhow!("[utility] 管道已关闭，发送被阻止"));
+        }
+        self.dishes.extend(self.recycle.try_iter());
+        if self.dishes.len() == 1 {
+            self.dropped.inc();
+            return Ok(());
+        }
+
+        let frame = Frame {
+            lease: Arc::new(Lease {
+                dish: self.dishes.pop_front(),
+                refund: self.refund.clone(),
+            }),
+        };
+        for consumer in &self.consumers {
+            consumer.push(frame.clone());
+        }
+        self.sent.inc();
+        Ok(())
+    }
+
+    /// 发送端的统计信息
+    pub fn stats(&self) -> BroadcastStats {
+        BroadcastStats {
+            sent: self.sent.get(),
+            dropped: self.dropped.get(),
+        }
+    }
+
+    /// 各消费者的统计信息
+    pub fn consumers(&self) -> Vec<ConsumerStats> {
+        self.consumers
+            .iter()
+            .map(|consumer| consumer.shared.stats())
+            .collect()
+    }
+}
+
+impl<T> Consumer<T> {
+    /// 按策略将帧放入队列
+    fn push(&self, mut frame: Frame<T>) {
+        loop {
+            match self.supply.try_send(frame) {
+                Ok(()) => return,
+                Err(TrySendError::Disconnected(_)) => return,
+                Err(TrySendError::Full(returned)) => frame = returned,
+            }
+            match self.policy {
+                DropPolicy::DropOldest => {
+                    // 接收端可能恰好取走了旧帧，此时直接重试
+                    if self.stale.try_recv().is_ok() {
+                        self.shared.dropped.inc();
+                    }
+                }
+                DropPolicy::DropNewest => {
+                    self.shared.dropped.inc();
+                    return;
+                }
+                DropPolicy::Block => {
+                    // 接收端关闭后不再阻塞
+                    while !self.shared.closed.load(Ordering::Relaxed) {
+                        match self.supply.send_timeout(frame, Duration::from_millis(10)) {
+                            Ok(()) => return,
+                            Err(err) => frame = err.into_inner(),
+                        }
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// 广播管道的接收端，丢弃收到的 [`Frame`] 即释放缓冲区
+pub struct BroadcastRecv<T> {
+    fetch: Receiver<Frame<T>>,
+    shared: Arc<ConsumerShared>,
+}
+
+impl<T> BroadcastRecv<T> {
+    /// 阻塞直到收到数据
+    ///
+    /// # 返回值
+    /// - `Ok(Frame<T>)` 表示成功接收到数据。
+    /// - `Err(anyhow::Error)` 表示发送端已关闭。
+    pub fn recv(&self) -> Result<Frame<T>, anyhow::Error> {
+        let frame = self
+            .fetch
+            .recv()
+            .map_err(|_| anyhow!("[utility] 管道已关闭，接收被阻止"))?;
+        Ok(self.received(frame))
+    }
+
+    /// 不阻塞地获取数据，暂无数据时返回 `Ok(None)`
+    pub fn try_recv(&self) -> Result<Option<Frame<T>>, anyhow::Error> {
+        match self.fetch.try_recv() {
+            Ok(frame) => Ok(Some(self.received(frame))),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(anyhow!("[utility] 管道已关闭，接收被阻止")),
+        }
+    }
+
+    /// 最多等待 `timeout` 获取数据，超时返回 `Ok(None)`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Frame<T>>, anyhow::Error> {
+        self.recv_deadline(Timestamp::now() + timeout)
+    }
+
+    /// 最多等待到 `deadline` 获取数据，超时返回 `Ok(None)`
+    pub fn recv_deadline(&self, deadline: Timestamp) -> Result<Option<Frame<T>>, anyhow::Error> {
+        match self.fetch.recv_deadline(deadline.to_instant()) {
+            Ok(frame) => Ok(Some(self.received(frame))),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(anyhow!("[utility] 管道已关闭，接收被阻止")),
+        }
+    }
+
+    /// 该消费者的统计信息
+    pub fn stats(&self) -> ConsumerStats {
+        self.shared.stats()
+    }
+
+    fn received(&self, frame: Frame<T>) -> Frame<T> {
+        self.shared.received.inc();
+        frame
+    }
+}
+
+impl<T> Drop for BroadcastRecv<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+        // 释放队列中的帧，使缓冲区尽快回到池中
+        while self.fetch.try_recv().is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(sender: &mut BroadcastSend<u32>, value: u32) {
+        **sender.get_send_buffer() = value;
+        sender.send().unwrap();
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let mut sender = BroadcastBuilder::<u32>::new()
+            .pool(3)
+            .metrics("测试广播")
+            .build();
+        let first = sender.subscribe("一", 1, DropPolicy::DropOldest);
+        let second = sender.subscribe("二", 1, DropPolicy::DropOldest);
+
+        send(&mut sender, 1);
+        let a = first.recv().unwrap();
+        let b = second.recv().unwrap();
+        assert_eq!((*a, *b), (1, 1));
+        let text = metrics::render_prometheus();
+        assert!(text.contains("broadcast_sent_total{broadcast=\"测试广播\"} 1"));
+        assert!(text.contains("broadcast_received_total{broadcast=\"测试广播\",consumer=\"一\"} 1"));
+
+        // 两个消费者都释放后缓冲区才回到池中
+        drop(a);
+        assert!(sender.recycle.is_empty());
+        drop(b);
+        assert_eq!(sender.recycle.len(), 1);
+    }
+
+    #[test]
+    fn test_drop_policy() {
+        let mut sender = BroadcastBuilder::<u32>::new().pool(6).build();
+        let detector = sender.subscribe("检测", 1, DropPolicy::DropOldest);
+        let recorder = sender.subscribe("录像", 2, DropPolicy::DropNewest);
+
+        for value in 1..=3 {
+            send(&mut sender, value);
+        }
+        // 检测只保留最新的帧，录像保留最早的两帧
+        assert_eq!(*detector.recv().unwrap(), 3);
+        assert_eq!(detector.stats().dropped, 2);
+        assert_eq!(*recorder.recv().unwrap(), 1);
+        assert_eq!(*recorder.recv().unwrap(), 2);
+        assert_eq!(recorder.stats().dropped, 1);
+        assert_eq!(
+            sender.stats(),
+            BroadcastStats {
+                sent: 3,
+                dropped: 0
+            }
+        );
+
+        // 关闭的消费者被移除，全部关闭后发送失败
+        drop(recorder);
+        send(&mut sender, 4);
+        assert_eq!(sender.consumers().len(), 1);
+        drop(detector);
+        assert!(sender.send().is_err());
+    }
+
+    #[test]
+    fn test_pool_exhausted() {
+        let mut sender = BroadcastBuilder::<u32>::new().pool(2).build();
+        let consumer = sender.subscribe("一", 2, DropPolicy::DropNewest);
+        send(&mut sender, 1);
+        let frame = consumer.recv().unwrap();
+        // 消费者持有唯一可发送的缓冲区，只能丢弃
+        send(&mut sender, 2);
+        assert_eq!(
+            sender.stats(),
+            BroadcastStats {
+                sent: 1,
+                dropped: 1
+            }
+        );
+        drop(frame);
+        send(&mut sender, 3);
+        assert_eq!(*consumer.recv().unwrap(), 3);
+    }
+}
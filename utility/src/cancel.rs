@@ -0,0 +1,297 @@
+//! 层级取消令牌
+//!
+//! 取消令牌组成一棵树：进程拥有一个根令牌 [`root`]，各子系统、各线程从上级令牌派生子令牌。
+//! 取消某个令牌会同时取消其全部后代，但不影响上级与兄弟令牌，因此单个子系统出错时只需停止
+//! 该子系统，之后可以派生新的子令牌重新启动。
+//!
+//! 每个线程有一个当前作用域，[`ensure_or_stop!`](crate::ensure_or_stop) 等宏出错时取消的正是
+//! 该作用域的令牌。通过 [`CancelToken::enter`] 进入作用域，未进入任何作用域时为根令牌。
+//!
+//! ```rust
+//! use utility::{root, CancelReason};
+//!
+//! let token = root().child("检测器");
+//! std::thread::spawn(move || {
+//!     let _scope = token.enter();
+//!     while !utility::is_stopped() {
+//!         // 工作
+//!         # break;
+//!     }
+//! });
+//! ```
+use std::{
+    cell::RefCell,
+    fmt,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, LazyLock, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+/// 取消的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CancelReason {
+    /// 收到 Ctrl-C 等外部信号
+    Signal,
+    /// 程序主动要求停止
+    Requested,
+    /// 出现无法恢复的错误
+    Error(String),
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CancelReason::Signal => write!(f, "收到停止信号"),
+            CancelReason::Requested => write!(f, "主动停止"),
+            CancelReason::Error(message) => write!(f, "出错：{}", message),
+        }
+    }
+}
+
+struct Node {
+    /// 自根令牌起的完整名称，如 `根/可视化`
+    name: String,
+    /// 供无锁查询是否已取消
+    cancelled: AtomicBool,
+    state: Mutex<State>,
+    condvar: Condvar,
+}
+
+struct State {
+    reason: Option<CancelReason>,
+    children: Vec<Weak<Node>>,
+}
+
+impl Node {
+    fn new(name: String, reason: Option<CancelReason>) -> Self {
+        Self {
+            name,
+            cancelled: AtomicBool::new(reason.is_some()),
+            state: Mutex::new(State {
+                reason,
+                children: Vec::new(),
+            }),
+            condvar: Condvar::new(),
+        }
+    }
+
+    fn cancel(&self, reason: &CancelReason) -> bool {
+        let children = {
+            let mut state = self.state.lock().expect("锁中毒");
+            if state.reason.is_some() {
+                return false;
+            }
+            state.reason = Some(reason.clone());
+            self.cancelled.store(true, Ordering::Release);
+            self.condvar.notify_all();
+            std::mem::take(&mut state.children)
+        };
+        debug!("[utility] {}已取消：{}", self.name, reason);
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel(reason);
+        }
+        true
+    }
+}
+
+/// 取消令牌，克隆得到的令牌与原令牌共享取消状态
+#[derive(Clone)]
+pub struct CancelToken {
+    node: Arc<Node>,
+}
+
+impl CancelToken {
+    /// 创建新的根令牌
+    pub fn new(name: &str) -> Self {
+        Self {
+            node: Arc::new(Node::new(name.to_string(), None)),
+        }
+    }
+
+    /// 派生子令牌，上级已取消时子令牌以同样的原因处于取消状态
+    pub fn child(&self, name: &str) -> Self {
+        let mut state = self.node.state.lock().expect("锁中毒");
+        let node = Arc::new(Node::new(
+            format!("{}/{}", self.node.name, name),
+            state.reason.clone(),
+        ));
+        if state.reason.is_none() {
+            state.children.retain(|child| child.strong_count() > 0);
+            state.children.push(Arc::downgrade(&node));
+        }
+        Self { node }
+    }
+
+    /// 自根令牌起的完整名称
+    pub fn name(&self) -> &str {
+        &self.node.name
+    }
+
+    /// 取消该令牌及其全部后代
+    ///
+    /// # 返回值
+    /// 若令牌此前已被取消，返回 `false`，原先的取消原因保持不变
+    pub fn cancel(&self, reason: CancelReason) -> bool {
+        self.node.cancel(&reason)
+    }
+
+    /// 是否已被取消
+    pub fn is_cancelled(&self) -> bool {
+        self.node.cancelled.load(Ordering::Acquire)
+    }
+
+    /// 取消的原因，未取消时为 `None`
+    pub fn reason(&self) -> Option<CancelReason> {
+        self.node.state.lock().expect("锁中毒").reason.clone()
+    }
+
+    /// 阻塞直到令牌被取消，返回取消的原因
+    pub fn wait(&self) -> CancelReason {
+        let state = self.node.state.lock().expect("锁中毒");
+        let state = self
+            .node
+            .condvar
+            .wait_while(state, |state| state.reason.is_none())
+            .expect("锁中毒");
+        state.reason.clone().expect("等待结束时令牌应已取消")
+    }
+
+    /// 阻塞直到令牌被取消或超时
+    ///
+    /// # 返回值
+    /// 超时前被取消时返回取消的原因，否则返回 `None`
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<CancelReason> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.node.state.lock().expect("锁中毒");
+        while state.reason.is_none() {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = self
+                .node
+                .condvar
+                .wait_timeout(state, deadline - now)
+                .expect("锁中毒")
+                .0;
+        }
+        state.reason.clone()
+    }
+
+    /// 将该令牌设为当前线程的作用域，直到返回的守卫被丢弃
+    pub fn enter(&self) -> ScopeGuard {
+        SCOPE.with_borrow_mut(|scope| scope.push(self.clone()));
+        ScopeGuard {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("name", &self.node.name)
+            .field("reason", &self.reason())
+            .finish()
+    }
+}
+
+/// 作用域守卫，丢弃时恢复进入前的作用域
+pub struct ScopeGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        SCOPE.with_borrow_mut(|scope| scope.pop());
+    }
+}
+
+static ROOT: LazyLock<CancelToken> = LazyLock::new(|| CancelToken::new("根"));
+
+thread_local! {
+    static SCOPE: RefCell<Vec<CancelToken>> = const { RefCell::new(Vec::new()) };
+}
+
+/// 进程的根令牌，取消后全部子系统都将停止
+pub fn root() -> &'static CancelToken {
+    &ROOT
+}
+
+/// 当前线程作用域的令牌，未进入任何作用域时为根令牌
+pub fn current() -> CancelToken {
+    SCOPE
+        .with_borrow(|scope| scope.last().cloned())
+        .unwrap_or_else(|| root().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_tree() {
+        let root = CancelToken::new("根");
+        let detector = root.child("检测器");
+        let camera = detector.child("相机");
+        let server = root.child("可视化");
+        assert_eq!(camera.name(), "根/检测器/相机");
+
+        // 取消子令牌不影响上级与兄弟
+        assert!(detector.cancel(CancelReason::Error("相机断开".into())));
+        assert!(camera.is_cancelled());
+        assert!(!root.is_cancelled());
+        assert!(!server.is_cancelled());
+        assert!(!detector.cancel(CancelReason::Requested));
+        assert_eq!(
+            camera.reason(),
+            Some(CancelReason::Error("相机断开".into()))
+        );
+
+        // 可以重新派生子令牌
+        let detector = root.child("检测器");
+        assert!(!detector.is_cancelled());
+        root.cancel(CancelReason::Signal);
+        assert_eq!(detector.reason(), Some(CancelReason::Signal));
+        assert!(server.is_cancelled());
+        // 上级已取消时派生的子令牌同样处于取消状态
+        assert!(root.child("追踪器").is_cancelled());
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let token = CancelToken::new("根");
+        assert_eq!(token.wait_timeout(Duration::from_millis(10)), None);
+
+        let child = token.child("线程");
+        let handle = std::thread::spawn(move || child.wait());
+        std::thread::sleep(Duration::from_millis(10));
+        token.cancel(CancelReason::Requested);
+        assert_eq!(handle.join().unwrap(), CancelReason::Requested);
+        assert_eq!(
+            token.wait_timeout(Duration::from_secs(1)),
+            Some(CancelReason::Requested)
+        );
+    }
+
+    #[test]
+    fn test_scope() {
+        let token = CancelToken::new("根").child("线程");
+        fn work() {
+            crate::ensure_or_stop!(false, "出错");
+        }
+        {
+            let _scope = token.enter();
+            assert_eq!(current().name(), "根/线程");
+            work();
+        }
+        assert_eq!(token.reason(), Some(CancelReason::Error("出错".into())));
+        // 离开作用域后恢复为根令牌
+        assert_eq!(current().name(), "根");
+    }
+}
@@ -0,0 +1,309 @@
+//! 按时间索引的传感器样本历史
+//!
+//! [`HistoryWriter`] 按时间顺序写入固定数量的最新样本，超出容量时丢弃最旧的样本；由其派生的
+//! [`HistoryReader`] 可以在其他线程中按时间查询，例如取得相机曝光时刻的云台姿态：
+//!
+//! - [`before`](HistoryReader::before)、[`after`](HistoryReader::after)、
+//!   [`nearest`](HistoryReader::nearest) 查找相邻的样本；
+//! - [`interpolate`](HistoryReader::interpolate) 在相邻两个样本之间插值，向量线性插值，
+//!   姿态（[`Rotor3`]）球面线性插值，自定义的样本类型可实现 [`Interpolate`]。
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use ultraviolet::Vec3;
+//! use utility::{HistoryWriter, Timestamp};
+//!
+//! let mut writer = HistoryWriter::new(256);
+//! let reader = writer.reader();
+//! let start = Timestamp::now();
+//! writer.push(start, Vec3::zero());
+//! writer.push(start + Duration::from_millis(10), Vec3::unit_x());
+//!
+//! let middle = reader.interpolate(start + Duration::from_millis(5)).unwrap();
+//! assert!((middle.x - 0.5).abs() < 1e-6);
+//! ```
+use std::{
+    collections::VecDeque,
+    sync::{Arc, RwLock},
+};
+
+use ultraviolet::{Rotor3, Vec2, Vec3, Vec4};
+
+use crate::Timestamp;
+
+/// 可在两个样本之间插值的类型
+pub trait Interpolate {
+    /// `t` 为 0 时返回 `self`，为 1 时返回 `other`
+    fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Interpolate for f64 {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t as f64
+    }
+}
+
+macro_rules! impl_lerp {
+    ($($ty:ty),*) => {
+        $(impl Interpolate for $ty {
+            fn interpolate(&self, other: &Self, t: f32) -> Self {
+                *self + (*other - *self) * t
+            }
+        })*
+    };
+}
+
+impl_lerp!(Vec2, Vec3, Vec4);
+
+impl Interpolate for Rotor3 {
+    /// 沿最短路径的球面线性插值
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        use ultraviolet::Slerp;
+        // 两者几乎重合时退化为未归一化的线性插值
+        self.slerp(*other, t).normalized()
+    }
+}
+
+impl<A: Interpolate, B: Interpolate> Interpolate for (A, B) {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        (
+            self.0.interpolate(&other.0, t),
+            self.1.interpolate(&other.1, t),
+        )
+    }
+}
+
+struct Inner<T> {
+    capacity: usize,
+    samples: RwLock<VecDeque<(Timestamp, T)>>,
+}
+
+/// 样本历史的写入端，每个历史只有一个写入端
+pub struct HistoryWriter<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> HistoryWriter<T> {
+    /// 最多保留 `capacity` 个样本的历史
+    ///
+    /// # Panics
+    /// `capacity` 为零时 panic
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "[utility] 历史容量不能为零");
+        Self {
+            inner: Arc::new(Inner {
+                capacity,
+                samples: RwLock::new(VecDeque::with_capacity(capacity)),
+            }),
+        }
+    }
+
+    /// 派生读取端
+    pub fn reader(&self) -> HistoryReader<T> {
+        HistoryReader {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// 写入时刻为 `at` 的样本，超出容量时丢弃最旧的样本
+    ///
+    /// 与最新样本时刻相同时替换该样本。
+    ///
+    /// # 返回值
+    /// 样本早于最新样本时不写入并返回 `false`
+    pub fn push(&mut self, at: Timestamp, value: T) -> bool {
+        let mut samples = self.inner.samples.write().expect("锁中毒");
+        match samples.back_mut() {
+            Some((last, _)) if at < *last => return false,
+            Some((last, slot)) if at == *last => {
+                *slot = value;
+                return true;
+            }
+            _ => {}
+        }
+        if samples.len() == self.inner.capacity {
+            samples.pop_front();
+        }
+        samples.push_back((at, value));
+        true
+    }
+
+    /// 清空全部样本，如传感器重新连接时
+    pub fn clear(&mut self) {
+        self.inner.samples.write().expect("锁中毒").clear();
+    }
+}
+
+/// 样本历史的读取端，可以克隆并在多个线程中使用
+pub struct HistoryReader<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for HistoryReader<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Clone> HistoryReader<T> {
+    /// 当前的样本数量
+    pub fn len(&self) -> usize {
+        self.inner.samples.read().expect("锁中毒").len()
+    }
+
+    /// 是否没有样本
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 最旧与最新样本的时刻
+    pub fn span(&self) -> Option<(Timestamp, Timestamp)> {
+        let samples = self.inner.samples.read().expect("锁中毒");
+        Some((samples.front()?.0, samples.back()?.0))
+    }
+
+    /// 最新的样本
+    pub fn latest(&self) -> Option<(Timestamp, T)> {
+        self.inner.samples.read().expect("锁中毒").back().cloned()
+    }
+
+    /// 时刻不晚于 `at` 的最新样本
+    pub fn before(&self, at: Timestamp) -> Option<(Timestamp, T)> {
+        let samples = self.inner.samples.read().expect("锁中毒");
+        let index = samples.partition_point(|(time, _)| *time <= at);
+        index.checked_sub(1).map(|index| samples[index].clone())
+    }
+
+    /// 时刻不早于 `at` 的最旧样本
+    pub fn after(&self, at: Timestamp) -> Option<(Timestamp, T)> {
+        let samples = self.inner.samples.read().expect("锁中毒");
+        let index = samples.partition_point(|(time, _)| *time < at);
+        samples.get(index).cloned()
+    }
+
+    /// 时刻与 `at` 最接近的样本，距离相同时取较早的样本
+    pub fn nearest(&self, at: Timestamp) -> Option<(Timestamp, T)> {
+        let samples = self.inner.samples.read().expect("锁中毒");
+        let index = samples.partition_point(|(time, _)| *time < at);
+        let before = index.checked_sub(1).map(|index| &samples[index]);
+        match (before, samples.get(index)) {
+            (Some(before), Some(after)) if at - before.0 > after.0 - at => Some(after.clone()),
+            (Some(before), _) => Some(before.clone()),
+            (None, after) => after.cloned(),
+        }
+    }
+}
+
+impl<T: Clone + Interpolate> HistoryReader<T> {
+    /// 在 `at` 前后两个样本之间插值
+    ///
+    /// # 返回值
+    /// `at` 不在最旧与最新样本的时刻之间时返回 `None`，不进行外推
+    pub fn interpolate(&self, at: Timestamp) -> Option<T> {
+        let samples = self.inner.samples.read().expect("锁中毒");
+        let index = samples.partition_point(|(time, _)| *time < at);
+        let (after_time, after) = samples.get(index)?;
+        if *after_time == at {
+            return Some(after.clone());
+        }
+        let (before_time, before) = &samples[index.checked_sub(1)?];
+        let t = (at - *before_time).as_secs_f64() / (*after_time - *before_time).as_secs_f64();
+        Some(before.interpolate(after, t as f32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{f32::consts::FRAC_PI_2, time::Duration};
+
+    fn at(ms: u64) -> Timestamp {
+        Timestamp::from_nanos(ms * 1_000_000)
+    }
+
+    #[test]
+    fn test_queries() {
+        let mut writer = HistoryWriter::new(3);
+        let reader = writer.reader();
+        assert_eq!(reader.nearest(at(0)), None);
+        for ms in [10, 20, 30, 40] {
+            assert!(writer.push(at(ms), ms));
+        }
+        // 超出容量丢弃最旧的样本，乱序的样本被拒绝
+        assert_eq!(reader.span(), Some((at(20), at(40))));
+        assert!(!writer.push(at(35), 35));
+        assert!(writer.push(at(40), 41));
+        assert_eq!(reader.len(), 3);
+
+        assert_eq!(reader.before(at(25)), Some((at(20), 20)));
+        assert_eq!(reader.before(at(30)), Some((at(30), 30)));
+        assert_eq!(reader.before(at(15)), None);
+        assert_eq!(reader.after(at(25)), Some((at(30), 30)));
+        assert_eq!(reader.after(at(45)), None);
+        assert_eq!(reader.nearest(at(26)), Some((at(30), 30)));
+        assert_eq!(reader.nearest(at(25)), Some((at(20), 20)));
+        assert_eq!(reader.nearest(at(100)), Some((at(40), 41)));
+        assert_eq!(reader.latest(), Some((at(40), 41)));
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let mut writer = HistoryWriter::new(8);
+        let reader = writer.reader();
+        writer.push(at(0), (Vec3::zero(), Rotor3::identity()));
+        writer.push(
+            at(10),
+            (
+                Vec3::new(2.0, 0.0, 0.0),
+                Rotor3::from_rotation_xy(FRAC_PI_2),
+            ),
+        );
+
+        let (position, attitude) = reader.interpolate(at(5)).unwrap();
+        assert!((position.x - 1.0).abs() < 1e-6);
+        let expected = Rotor3::from_rotation_xy(FRAC_PI_2 / 2.0);
+        assert!(attitude.dot(expected).abs() > 1.0 - 1e-6);
+        assert!((attitude.mag() - 1.0).abs() < 1e-6);
+
+        assert_eq!(
+            reader.interpolate(at(10)).unwrap().0,
+            Vec3::new(2.0, 0.0, 0.0)
+        );
+        // 不进行外推
+        assert!(reader.interpolate(at(11)).is_none());
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let mut writer = HistoryWriter::new(64);
+        let reader = writer.reader();
+        let handle = std::thread::spawn(move || {
+            for ms in 0..1000u64 {
+                writer.push(at(ms), ms as f64);
+            }
+        });
+        let mut last = 0.0;
+        while !handle.is_finished() {
+            if let Some((time, value)) = reader.latest() {
+                // 读到的样本总是完整的，且不会倒退
+                assert_eq!(time, at(value as u64));
+                assert!(value >= last);
+                last = value;
+            }
+        }
+        handle.join().unwrap();
+        assert_eq!(reader.len(), 64);
+        assert_eq!(
+            reader.interpolate(at(999) - Duration::from_micros(500)),
+            Some(998.5)
+        );
+    }
+}
@@ -1,30 +1,57 @@
+mod broadcast;
+mod cancel;
+mod history;
+pub mod logging;
+pub mod metrics;
+mod pipeline;
+pub mod rt;
+mod supervisor;
+pub mod time;
+pub mod trace;
+pub mod watchdog;
+
 use anyhow::anyhow;
 
-use crossbeam_channel::{unbounded as channel, Receiver, Sender, TryRecvError};
+use crossbeam_channel::{unbounded as channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
 
 use std::{
     collections::VecDeque,
-    sync::atomic::{AtomicBool, Ordering},
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+pub use broadcast::{
+    BroadcastBuilder, BroadcastRecv, BroadcastSend, BroadcastStats, ConsumerStats, DropPolicy,
+    Frame,
+};
+pub use cancel::{current, root, CancelReason, CancelToken, ScopeGuard};
+pub use history::{HistoryReader, HistoryWriter, Interpolate};
 pub use log::error;
+pub use pipeline::{Pipeline, PipelineBuilder, Stage, StageReport};
+pub use supervisor::{
+    Backoff, Health, RestartPolicy, Supervisor, WorkerStatus, DEFAULT_JOIN_TIMEOUT,
+};
+pub use time::Timestamp;
+pub use trace::TraceContext;
 
-static STOP_SIG: AtomicBool = AtomicBool::new(false);
-
-/// 检查是否已发出停止信号
+/// 检查当前线程的作用域是否已被取消
 ///
 /// # 返回值
-/// 如果已发出停止信号，返回 `true`，否则返回 `false`
+/// 如果当前作用域或其上级已被取消，返回 `true`，否则返回 `false`
 pub fn is_stopped() -> bool {
-    STOP_SIG.load(Ordering::Relaxed)
+    current().is_cancelled()
 }
 
-/// 发出停止信号，通知所有线程停止工作
+/// 取消根令牌，通知所有线程停止工作
 pub fn stop_all() {
-    STOP_SIG.store(true, Ordering::Relaxed);
+    root().cancel(CancelReason::Requested);
 }
 
-/// 确保条件为真，否则记录错误日志并发出停止信号
+/// 确保条件为真，否则记录错误日志并取消当前作用域
 ///
 /// # 参数
 /// - `$cond`: 要检查的条件表达式
@@ -33,13 +60,15 @@ pub fn stop_all() {
 macro_rules! ensure_or_stop {
     ($cond:expr, $log:expr) => {
         if !$cond {
-            $crate::error!("{}", $log);
-            $crate::stop_all();
+            let log = $log;
+            $crate::error!("{}", log);
+            $crate::current().cancel($crate::CancelReason::Error(log.to_string()));
             return;
         }
     };
 }
 
+/// 取出 `Ok` 中的值，否则记录错误日志并取消当前作用域
 #[macro_export]
 macro_rules! unwrap_or_stop {
     ($wrap_value:expr) => {
@@ -47,28 +76,82 @@ macro_rules! unwrap_or_stop {
             Ok(val) => val,
             Err(e) => {
                 $crate::error!("{}", e);
-                $crate::stop_all();
+                $crate::current().cancel($crate::CancelReason::Error(e.to_string()));
                 return;
             }
         }
     };
 }
 
+/// 取出 `Ok` 中的值，否则记录错误与说明并取消当前作用域
 #[macro_export]
 macro_rules! expect_or_stop {
     ($wrap_value:expr, $log:expr) => {
         match $wrap_value {
             Ok(val) => val,
             Err(e) => {
+                let log = $log;
                 $crate::error!("{}", e);
-                $crate::error!("{}", $log);
-                $crate::stop_all();
+                $crate::error!("{}", log);
+                $crate::current().cancel($crate::CancelReason::Error(format!("{}：{}", log, e)));
                 return;
             }
         }
     };
 }
 
+/// 管道的工作模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TubeMode {
+    /// 按发送顺序接收，没有空闲缓冲区时丢弃新数据
+    #[default]
+    Fifo,
+    /// 只接收最新的数据，未被接收的旧数据自动回收复用
+    Latest,
+}
+
+/// 管道的统计信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TubeStats {
+    /// 成功发送的数量
+    pub sent: u64,
+    /// 未被接收即被新数据覆盖的数量，仅出现在 [`TubeMode::Latest`] 模式
+    pub overwritten: u64,
+    /// 因没有空闲缓冲区而未能发送的数量
+    pub dropped: u64,
+}
+
+/// 发送端与接收端共享的状态
+#[derive(Default)]
+struct Shared {
+    sent: metrics::Counter,
+    overwritten: metrics::Counter,
+    dropped: metrics::Counter,
+    /// 接收端是否已关闭
+    closed: AtomicBool,
+}
+
+impl Shared {
+    /// 统计数量登记在全局指标注册表中，以 `tube` 标签区分
+    fn registered(name: &str) -> Self {
+        let labels = [("tube", name)];
+        Self {
+            sent: metrics::counter("tube_sent_total", &labels),
+            overwritten: metrics::counter("tube_overwritten_total", &labels),
+            dropped: metrics::counter("tube_dropped_total", &labels),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn stats(&self) -> TubeStats {
+        TubeStats {
+            sent: self.sent.get(),
+            overwritten: self.overwritten.get(),
+            dropped: self.dropped.get(),
+        }
+    }
+}
+
 /// 管道的发送端结构
 /// 管理数据的发送缓冲区和发送逻辑
 pub struct TubeSend<T> {
@@ -78,6 +161,9 @@ pub struct TubeSend<T> {
     supply: Sender<Box<T>>,
     /// 用于回收缓冲区的通道
     recycle: Receiver<Box<T>>,
+    /// [`TubeMode::Latest`] 模式下用于取回未被接收的旧数据
+    stale: Option<Receiver<Box<T>>>,
+    shared: Arc<Shared>,
 }
 
 /// 管道的接收端结构
@@ -87,6 +173,107 @@ pub struct TubeRecv<T> {
     fetch: Receiver<Box<T>>,
     /// 用于回收缓冲区的发送端
     refund: Sender<Box<T>>,
+    mode: TubeMode,
+    shared: Arc<Shared>,
+}
+
+/// 管道的构建器
+///
+/// ```rust
+/// use utility::{TubeBuilder, TubeMode};
+///
+/// // 三个预先分配好容量的缓冲区，只接收最新数据
+/// let (mut tube_send, tube_recv) = TubeBuilder::with_init(|| Vec::<u8>::with_capacity(1440 * 1080))
+///     .pool(3)
+///     .mode(TubeMode::Latest)
+///     .build();
+/// ```
+pub struct TubeBuilder<T, F = fn() -> T> {
+    pool: usize,
+    mode: TubeMode,
+    init: F,
+    metrics: Option<String>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Default> TubeBuilder<T> {
+    /// 以 `Default` 初始化缓冲区
+    pub fn new() -> Self {
+        Self::with_init(T::default)
+    }
+}
+
+impl<T: Default> Default for TubeBuilder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, F: FnMut() -> T> TubeBuilder<T, F> {
+    /// 以 `init` 的返回值初始化缓冲区，适用于需要预先设定尺寸的数据
+    pub fn with_init(init: F) -> Self {
+        Self {
+            pool: 2,
+            mode: TubeMode::Fifo,
+            init,
+            metrics: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// 预先分配的缓冲区数量，默认为 2，至少为 2
+    pub fn pool(mut self, pool: usize) -> Self {
+        assert!(pool >= 2, "[Tube] 缓冲区数量至少为2，实际为{}", pool);
+        self.pool = pool;
+        self
+    }
+
+    /// 管道的工作模式，默认为 [`TubeMode::Fifo`]
+    pub fn mode(mut self, mode: TubeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// 将统计信息登记到全局指标注册表，名称作为 `tube` 标签
+    ///
+    /// 登记为 `tube_sent_total`、`tube_overwritten_total` 与 `tube_dropped_total` 三个计数器。
+    /// 同名的管道共享同一组计数器，[`TubeSend::stats`] 同样返回合计的数量。
+    pub fn metrics(mut self, name: &str) -> Self {
+        self.metrics = Some(name.to_string());
+        self
+    }
+
+    /// 创建管道，返回发送端和接收端
+    pub fn build(mut self) -> (TubeSend<T>, TubeRecv<T>) {
+        // 创建用于数据传输的通道
+        let (supply, fetch) = channel();
+        // 创建用于缓冲区回收的通道
+        let (refund, recycle) = channel();
+
+        // 初始化缓冲区，全部由发送端持有
+        let dishes = (0..self.pool).map(|_| Box::new((self.init)())).collect();
+
+        // 构造发送端和接收端
+        let shared = Arc::new(match &self.metrics {
+            Some(name) => Shared::registered(name),
+            None => Shared::default(),
+        });
+        let tube_send = TubeSend {
+            dishes,
+            supply,
+            recycle,
+            stale: (self.mode == TubeMode::Latest).then(|| fetch.clone()),
+            shared: shared.clone(),
+        };
+        let tube_recv = TubeRecv {
+            fetch,
+            refund,
+            mode: self.mode,
+            shared,
+        };
+
+        (tube_send, tube_recv)
+    }
 }
 
 /// 创建一个管道（Tube），包括发送端和接收端
@@ -100,25 +287,18 @@ pub fn new_tube<T>() -> (TubeSend<T>, TubeRecv<T>)
 where
     T: Default,
 {
-    // 创建用于数据传输的通道
-    let (supply, fetch) = channel();
-    // 创建用于缓冲区回收的通道
-    let (refund, recycle) = channel();
-
-    // 初始化缓冲区，包含两个默认的数据项
-    let mut dishes = VecDeque::new();
-    dishes.push_back(Box::new(Default::default()));
-    dishes.push_back(Box::new(Default::default()));
-
-    // 构造发送端和接收端
-    let tube_send = TubeSend {
-        dishes,
-        supply,
-        recycle,
-    };
-    let tube_recv = TubeRecv { fetch, refund };
+    TubeBuilder::new().build()
+}
 
-    (tube_send, tube_recv)
+/// 创建一个只接收最新数据的管道
+///
+/// 发送时若没有空闲缓冲区，发送端取回尚未被接收的最旧数据作为缓冲区复用；接收时跳过除最新
+/// 数据外的全部数据并自动回收。接收端始终拿到最新的数据，且不会分配新的缓冲区。
+pub fn new_latest_tube<T>() -> (TubeSend<T>, TubeRecv<T>)
+where
+    T: Default,
+{
+    TubeBuilder::new().mode(TubeMode::Latest).build()
 }
 
 impl<T> TubeSend<T> {
@@ -133,16 +313,37 @@ impl<T> TubeSend<T> {
 
     /// 将缓冲区中的数据发送到接收端
     ///
+    /// 没有空闲缓冲区时，[`TubeMode::Fifo`] 模式丢弃本次数据，下次发送前将覆盖同一缓冲区；
+    /// [`TubeMode::Latest`] 模式则取回未被接收的旧数据复用，仅在接收端持有其余全部缓冲区时
+    /// 丢弃本次数据。
+    ///
     /// # 返回值
-    /// - `Ok(())` 表示成功发送。
+    /// - `Ok(())` 表示成功发送或已丢弃。
     /// - `Err(anyhow::Error)` 表示管道已关闭。
     pub fn send(&mut self) -> Result<(), anyhow::Error> {
+        // 取回旧数据的接收端使通道不会自动断开，需单独检查
+        if self.shared.closed.load(Ordering::Relaxed) {
+            return Err(anyhow!("[utility] 管道已关闭，发送被阻止"));
+        }
         // 如果缓冲区只剩一个数据，尝试从回收通道中获取空缓冲区
         // 保证发送端至少有一个缓冲区可用
         if self.dishes.len() == 1 {
             match self.recycle.try_recv() {
                 Ok(empty_dish) => self.dishes.push_back(empty_dish),
-                Err(TryRecvError::Empty) => return Ok(()), // 无可用缓冲区
+                Err(TryRecvError::Empty) => {
+                    // 取回未被接收的旧数据
+                    match self.stale.as_ref().map(Receiver::try_recv) {
+                        Some(Ok(stale_dish)) => {
+                            self.shared.overwritten.inc();
+                            self.dishes.push_back(stale_dish);
+                        }
+                        _ => {
+                            // 无可用缓冲区
+                            self.shared.dropped.inc();
+                            return Ok(());
+                        }
+                    }
+                }
                 Err(TryRecvError::Disconnected) => {
                     return Err(anyhow!("[utility] 管道已关闭，发送被阻止"))
                 }
@@ -151,20 +352,80 @@ impl<T> TubeSend<T> {
         // 发送缓冲区中的第一个数据
         self.supply
             .send(self.dishes.pop_front().expect("[Tube] 数据结构逻辑错误"))
-            .map_err(|_| anyhow!("[utility] 管道已关闭，发送被阻止"))
+            .map_err(|_| anyhow!("[utility] 管道已关闭，发送被阻止"))?;
+        self.shared.sent.inc();
+        Ok(())
+    }
+
+    /// 管道的统计信息
+    pub fn stats(&self) -> TubeStats {
+        self.shared.stats()
     }
 }
 
 impl<T> TubeRecv<T> {
     /// 从接收通道中获取数据
     ///
+    /// [`TubeMode::Latest`] 模式下返回当前最新的数据，更早的数据自动回收。
+    ///
     /// # 返回值
     /// - `Ok(Box<T>)` 表示成功接收到数据。
     /// - `Err(anyhow::Error)` 表示发送端已关闭。
     pub fn recv(&self) -> Result<Box<T>, anyhow::Error> {
-        self.fetch
+        let dish = self
+            .fetch
             .recv()
-            .map_err(|_| anyhow!("[utility] 管道已关闭，接收被阻止"))
+            .map_err(|_| anyhow!("[utility] 管道已关闭，接收被阻止"))?;
+        Ok(self.skip_stale(dish))
+    }
+
+    /// 不阻塞地获取数据
+    ///
+    /// # 返回值
+    /// - `Ok(Some(Box<T>))` 表示成功接收到数据。
+    /// - `Ok(None)` 表示暂无数据。
+    /// - `Err(anyhow::Error)` 表示发送端已关闭。
+    pub fn try_recv(&self) -> Result<Option<Box<T>>, anyhow::Error> {
+        match self.fetch.try_recv() {
+            Ok(dish) => Ok(Some(self.skip_stale(dish))),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(anyhow!("[utility] 管道已关闭，接收被阻止")),
+        }
+    }
+
+    /// 最多等待 `timeout` 获取数据
+    ///
+    /// 在循环中配合停止信号使用，避免发送端停止发送时永远阻塞：
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # let (_tube_send, tube_recv) = utility::new_tube::<u8>();
+    /// while !utility::is_stopped() {
+    ///     let Some(dish) = tube_recv.recv_timeout(Duration::from_millis(100))? else {
+    ///         # break;
+    ///         continue;
+    ///     };
+    ///     // 处理数据
+    ///     tube_recv.recycle(dish)?;
+    /// }
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    ///
+    /// # 返回值
+    /// - `Ok(Some(Box<T>))` 表示成功接收到数据。
+    /// - `Ok(None)` 表示超时。
+    /// - `Err(anyhow::Error)` 表示发送端已关闭。
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Box<T>>, anyhow::Error> {
+        self.recv_deadline(Timestamp::now() + timeout)
+    }
+
+    /// 最多等待到 `deadline` 获取数据，返回值同 [`TubeRecv::recv_timeout`]
+    pub fn recv_deadline(&self, deadline: Timestamp) -> Result<Option<Box<T>>, anyhow::Error> {
+        match self.fetch.recv_deadline(deadline.to_instant()) {
+            Ok(dish) => Ok(Some(self.skip_stale(dish))),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(anyhow!("[utility] 管道已关闭，接收被阻止")),
+        }
     }
 
     /// 回收空缓冲区，将其返还给发送端
@@ -180,6 +441,39 @@ impl<T> TubeRecv<T> {
             .send(empty_dish)
             .map_err(|_| anyhow!("[utility] 管道已关闭，回收被阻止"))
     }
+
+    /// 管道的统计信息
+    pub fn stats(&self) -> TubeStats {
+        self.shared.stats()
+    }
+
+    /// 等待接收的数据数量
+    pub fn len(&self) -> usize {
+        self.fetch.len()
+    }
+
+    /// 是否没有等待接收的数据
+    pub fn is_empty(&self) -> bool {
+        self.fetch.is_empty()
+    }
+
+    /// [`TubeMode::Latest`] 模式下跳过并回收较旧的数据
+    fn skip_stale(&self, mut dish: Box<T>) -> Box<T> {
+        if self.mode == TubeMode::Latest {
+            while let Ok(newer) = self.fetch.try_recv() {
+                self.shared.overwritten.inc();
+                // 发送端已关闭时无需回收
+                let _ = self.refund.send(std::mem::replace(&mut dish, newer));
+            }
+        }
+        dish
+    }
+}
+
+impl<T> Drop for TubeRecv<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+    }
 }
 
 #[cfg(test)]
@@ -280,4 +574,89 @@ mod tests {
             Err(e) if e.to_string() == "[utility] 管道已关闭，回收被阻止"
         ));
     }
+
+    #[test]
+    fn test_latest_tube() {
+        let (mut tube_send, tube_recv) = new_latest_tube::<TestData>();
+
+        // 接收端未接收时持续发送，旧数据被取回复用
+        for value in 1..=5 {
+            tube_send.get_send_buffer().value = value;
+            assert!(tube_send.send().is_ok());
+        }
+        let recv_buffer = tube_recv.recv().unwrap();
+        assert_eq!(recv_buffer.value, 5);
+        let stats = tube_recv.stats();
+        assert_eq!(stats.sent, 5);
+        assert_eq!(stats.overwritten, 4);
+        assert_eq!(stats.dropped, 0);
+
+        // 接收端持有另一个缓冲区时只能丢弃，发送端继续覆盖自己的缓冲区
+        tube_send.get_send_buffer().value = 6;
+        assert!(tube_send.send().is_ok());
+        tube_send.get_send_buffer().value = 7;
+        assert!(tube_send.send().is_ok());
+        assert_eq!(tube_send.stats().dropped, 2);
+        assert!(tube_recv.recycle(recv_buffer).is_ok());
+        assert!(tube_send.send().is_ok());
+        assert_eq!(tube_recv.recv().unwrap().value, 7);
+
+        drop(tube_recv);
+        assert!(tube_send.send().is_err());
+    }
+
+    #[test]
+    fn test_stop_macros_evaluate_log_once() {
+        let count = std::cell::Cell::new(0);
+        let log = || {
+            count.set(count.get() + 1);
+            "出错"
+        };
+        let token = CancelToken::new("根");
+        {
+            let _scope = token.child("条件").enter();
+            (|| ensure_or_stop!(false, log()))();
+            let _scope = token.child("结果").enter();
+            (|| {
+                expect_or_stop!(Err::<(), _>("失败"), log());
+            })();
+        }
+        assert_eq!(count.get(), 2);
+    }
+
+    #[test]
+    fn test_tube_builder() {
+        let (mut tube_send, tube_recv) = TubeBuilder::with_init(|| vec![0u8; 16])
+            .pool(3)
+            .metrics("测试管道")
+            .build();
+
+        // 三个缓冲区时接收端未回收也能连续发送两次
+        for value in 1..=2 {
+            tube_send.get_send_buffer()[0] = value;
+            assert!(tube_send.send().is_ok());
+        }
+        assert_eq!(tube_send.stats().sent, 2);
+        assert!(metrics::render_prometheus().contains("tube_sent_total{tube=\"测试管道\"} 2"));
+        let first = tube_recv.try_recv().unwrap().unwrap();
+        assert_eq!((first[0], first.len()), (1, 16));
+        let second = tube_recv.recv_timeout(Duration::from_millis(10)).unwrap();
+        assert_eq!(second.unwrap()[0], 2);
+    }
+
+    #[test]
+    fn test_tube_recv_timeout() {
+        let (tube_send, tube_recv) = new_tube::<TestData>();
+        assert!(tube_recv.try_recv().unwrap().is_none());
+        assert!(tube_recv
+            .recv_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+
+        drop(tube_send);
+        assert!(tube_recv.try_recv().is_err());
+        assert!(tube_recv
+            .recv_deadline(Timestamp::now() + Duration::from_millis(10))
+            .is_err());
+    }
 }
@@ -0,0 +1,483 @@
+//! 结构化日志
+//!
+//! 替代 `env_logger` 的全局日志实现：
+//!
+//! - 过滤规则的格式与 `RUST_LOG` 相同（如 `info,server=debug,config::watch=trace`），可通过
+//!   [`set_filter`] 在运行时修改，或由 [`install_signal_handler`] 安装的 `SIGUSR1` 在当前规则与
+//!   `debug` 之间切换；
+//! - 日志输出到标准错误，调用 [`log_to_file`] 后同时写入按大小轮转的文件；
+//! - 每条日志解析为 [`LogRecord`]，消息开头的 `[可视化][ERR-02]` 等标记单独列出，可通过
+//!   [`subscribe`] 实时接收（可视化服务将其转发给客户端）。
+//!
+//! ```rust
+//! use utility::logging;
+//!
+//! logging::set_filter("info,server=debug").unwrap();
+//! assert_eq!(logging::filter(), "info,server=debug");
+//! assert!(logging::set_filter("info,server=loud").is_err());
+//! ```
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+
+/// 默认的过滤规则
+pub const DEFAULT_FILTER: &str = "info";
+
+/// 结构化的日志记录
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LogRecord {
+    /// UNIX 时间戳（毫秒）
+    pub time_ms: u64,
+    /// `ERROR`、`WARN`、`INFO`、`DEBUG` 或 `TRACE`
+    pub level: String,
+    /// 记录日志的模块，如 `server::remote`
+    pub target: String,
+    /// 消息开头方括号中的标记，如 `["可视化", "ERR-02"]`
+    pub tags: Vec<String>,
+    /// 去掉标记后的消息
+    pub message: String,
+    /// 线程名称
+    pub thread: Option<String>,
+}
+
+impl LogRecord {
+    fn new(record: &Record) -> Self {
+        let text = record.args().to_string();
+        let (tags, message) = split_tags(&text);
+        Self {
+            time_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_millis() as u64),
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            tags,
+            message: message.to_string(),
+            thread: thread::current().name().map(str::to_string),
+        }
+    }
+
+    /// 单行文本格式，如 `2025-01-01T00:00:00.000Z ERROR server [可视化][ERR-02] 消息`
+    pub fn format(&self) -> String {
+        let time = UNIX_EPOCH + Duration::from_millis(self.time_ms);
+        let tags: String = self.tags.iter().map(|tag| format!("[{}]", tag)).collect();
+        let separator = if tags.is_empty() { "" } else { " " };
+        format!(
+            "{} {:<5} {} {}{}{}",
+            humantime::format_rfc3339_millis(time),
+            self.level,
+            self.target,
+            tags,
+            separator,
+            self.message
+        )
+    }
+}
+
+/// 拆分消息开头的 `[标记]`
+fn split_tags(text: &str) -> (Vec<String>, &str) {
+    let mut tags = Vec::new();
+    let mut rest = text;
+    while let Some(tail) = rest.strip_prefix('[') {
+        let Some(end) = tail.find(']') else {
+            break;
+        };
+        tags.push(tail[..end].to_string());
+        rest = &tail[end + 1..];
+    }
+    (tags, rest.trim_start())
+}
+
+/// 解析后的过滤规则
+#[derive(Debug, Clone, PartialEq)]
+struct Filter {
+    spec: String,
+    default: LevelFilter,
+    /// 按模块前缀长度降序排列
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> anyhow::Result<Self> {
+        let mut default = LevelFilter::Error;
+        let mut directives = Vec::new();
+        for directive in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+        {
+            let parse_level = |level: &str| {
+                level
+                    .trim()
+                    .parse::<LevelFilter>()
+                    .map_err(|_| anyhow!("无法识别的日志级别：{}", level.trim()))
+            };
+            match directive.split_once('=') {
+                Some((target, level)) if !target.trim().is_empty() => {
+                    directives.push((target.trim().to_string(), parse_level(level)?));
+                }
+                Some(_) => bail!("缺少模块名：{}", directive),
+                // 单独的级别为默认级别，否则视为模块名，开启该模块的全部日志
+                None => match parse_level(directive) {
+                    Ok(level) => default = level,
+                    Err(_) => directives.push((directive.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        directives.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        Ok(Self {
+            spec: spec.trim().to_string(),
+            default,
+            directives,
+        })
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .find(|(prefix, _)| {
+                target
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .chain([self.default])
+            .max()
+            .unwrap_or(self.default)
+    }
+}
+
+/// 按大小轮转的日志文件：写满后 `app.log` 重命名为 `app.log.1`，原 `app.log.1` 重命名为
+/// `app.log.2`，以此类推，超出保留数量的最旧文件被删除
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            keep,
+            writer: BufWriter::new(file),
+            written,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        name.into()
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.writer, "{}", line)?;
+        // 出错时能看到最近的日志比写入效率更重要
+        self.writer.flush()?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let _ = fs::remove_file(self.rotated(self.keep));
+        for index in (1..self.keep).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                fs::rename(from, self.rotated(index + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+struct Logger {
+    filter: RwLock<Filter>,
+    /// 切换到 `debug` 前的过滤规则
+    saved: Mutex<Option<String>>,
+    file: Mutex<Option<RotatingFile>>,
+    subscribers: Mutex<Vec<Sender<LogRecord>>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level()
+            <= self
+                .filter
+                .read()
+                .expect("锁中毒")
+                .level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let record = LogRecord::new(record);
+        let line = record.format();
+        eprintln!("{}", line);
+
+        let mut file = self.file.lock().expect("锁中毒");
+        if let Some(writer) = file.as_mut() {
+            if let Err(err) = writer.write_line(&line) {
+                // 此处不能再记录日志
+                eprintln!("[utility] 写入日志文件失败，停止写入：{}", err);
+                *file = None;
+            }
+        }
+        drop(file);
+
+        self.subscribers.lock().expect("锁中毒").retain(|sender| {
+            match sender.try_send(record.clone()) {
+                // 接收端处理不及时时丢弃
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    fn flush(&self) {
+        if let Some(writer) = self.file.lock().expect("锁中毒").as_mut() {
+            let _ = writer.writer.flush();
+        }
+    }
+}
+
+static LOGGER: LazyLock<Logger> = LazyLock::new(|| Logger {
+    filter: RwLock::new(Filter::parse(DEFAULT_FILTER).expect("默认过滤规则应当合法")),
+    saved: Mutex::new(None),
+    file: Mutex::new(None),
+    subscribers: Mutex::new(Vec::new()),
+});
+
+/// 安装全局日志，初始过滤规则取自环境变量 `RUST_LOG`，未设置或不合法时为 [`DEFAULT_FILTER`]
+///
+/// # Panics
+/// 已安装其他日志实现时 panic
+pub fn init() {
+    let spec = std::env::var("RUST_LOG").unwrap_or_default();
+    let invalid = set_filter(&spec).is_err();
+    if spec.is_empty() || invalid {
+        set_filter(DEFAULT_FILTER).expect("默认过滤规则应当合法");
+    }
+    log::set_logger(&*LOGGER).expect("[utility] 已安装其他日志实现");
+    if invalid {
+        log::warn!(
+            "[utility] RUST_LOG 不合法，使用默认的过滤规则{}",
+            DEFAULT_FILTER
+        );
+    }
+}
+
+/// 修改过滤规则，规则不合法时保持原规则不变
+///
+/// 处于 [`toggle_verbose`] 切换的 `debug` 时，新规则同时结束该状态，下次切换从新规则开始。
+pub fn set_filter(spec: &str) -> anyhow::Result<()> {
+    apply_filter(spec)?;
+    LOGGER.saved.lock().expect("锁中毒").take();
+    Ok(())
+}
+
+fn apply_filter(spec: &str) -> anyhow::Result<()> {
+    let filter = Filter::parse(spec)?;
+    log::set_max_level(filter.max_level());
+    *LOGGER.filter.write().expect("锁中毒") = filter;
+    Ok(())
+}
+
+/// 当前的过滤规则
+pub fn filter() -> String {
+    LOGGER.filter.read().expect("锁中毒").spec.clone()
+}
+
+/// 在当前过滤规则与 `debug` 之间切换
+///
+/// # 返回值
+/// 切换后是否处于 `debug`
+pub fn toggle_verbose() -> bool {
+    let mut saved = LOGGER.saved.lock().expect("锁中毒");
+    let (spec, verbose) = match saved.take() {
+        Some(spec) => (spec, false),
+        None => {
+            *saved = Some(filter());
+            ("debug".to_string(), true)
+        }
+    };
+    apply_filter(&spec).expect("切换前的过滤规则应当合法");
+    log::info!("[utility] 日志过滤规则切换为{}", spec);
+    verbose
+}
+
+/// 同时将日志写入 `path`，单个文件超过 `max_bytes` 时轮转，最多保留 `keep` 个旧文件
+///
+/// 再次调用时替换先前的文件。
+pub fn log_to_file(path: impl AsRef<Path>, max_bytes: u64, keep: usize) -> io::Result<()> {
+    let file = RotatingFile::open(path.as_ref(), max_bytes, keep)?;
+    *LOGGER.file.lock().expect("锁中毒") = Some(file);
+    Ok(())
+}
+
+/// 停止写入日志文件
+pub fn stop_file() {
+    if let Some(mut file) = LOGGER.file.lock().expect("锁中毒").take() {
+        let _ = file.writer.flush();
+    }
+}
+
+/// 实时接收此后的日志，接收端积压超过 `capacity` 条时丢弃新的日志
+pub fn subscribe(capacity: usize) -> Receiver<LogRecord> {
+    let (sender, receiver) = bounded(capacity);
+    LOGGER.subscribers.lock().expect("锁中毒").push(sender);
+    receiver
+}
+
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+/// 检查信号标记的间隔
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// 安装 `SIGUSR1` 处理函数，收到信号时调用 [`toggle_verbose`]
+#[cfg(unix)]
+pub fn install_signal_handler() -> io::Result<()> {
+    extern "C" fn handle(_: libc::c_int) {
+        // 信号处理函数中只能进行异步信号安全的操作
+        SIGNALLED.store(true, Ordering::Release);
+    }
+
+    thread::Builder::new()
+        .name("日志信号".to_string())
+        .spawn(|| loop {
+            thread::sleep(SIGNAL_POLL_INTERVAL);
+            if SIGNALLED.swap(false, Ordering::AcqRel) {
+                toggle_verbose();
+            }
+        })?;
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: 处理函数只写入原子变量
+    if unsafe { libc::signal(libc::SIGUSR1, handler) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handler() -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::parse("warn, server=debug, server::remote=trace,config").unwrap();
+        assert_eq!(filter.level_for("detector"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("server"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("server::lib"), LevelFilter::Debug);
+        assert_eq!(filter.level_for("server::remote"), LevelFilter::Trace);
+        // 仅匹配完整的模块名
+        assert_eq!(filter.level_for("server_extra"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("config::watch"), LevelFilter::Trace);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+
+        assert_eq!(Filter::parse("").unwrap().max_level(), LevelFilter::Error);
+        assert!(Filter::parse("server=loud").is_err());
+        assert!(Filter::parse("=info").is_err());
+    }
+
+    #[test]
+    fn test_toggle_verbose() {
+        set_filter("info").unwrap();
+        assert!(toggle_verbose());
+        assert_eq!(filter(), "debug");
+        assert!(!toggle_verbose());
+        assert_eq!(filter(), "info");
+
+        // 切换到 debug 期间设置的规则不会被之后的切换覆盖
+        assert!(toggle_verbose());
+        set_filter("warn").unwrap();
+        assert!(toggle_verbose());
+        assert!(!toggle_verbose());
+        assert_eq!(filter(), "warn");
+    }
+
+    #[test]
+    fn test_record() {
+        let (tags, message) = split_tags("[可视化][ERR-02] 建立TCP连接时出错");
+        assert_eq!(tags, ["可视化", "ERR-02"]);
+        assert_eq!(message, "建立TCP连接时出错");
+        assert_eq!(split_tags("[未闭合 消息"), (vec![], "[未闭合 消息"));
+
+        let record = LogRecord {
+            time_ms: 1_700_000_000_123,
+            level: "ERROR".into(),
+            target: "server".into(),
+            tags: vec!["可视化".into()],
+            message: "出错".into(),
+            thread: None,
+        };
+        assert_eq!(
+            record.format(),
+            "2023-11-14T22:13:20.123Z ERROR server [可视化] 出错"
+        );
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("quasar_{}_log", std::process::id()));
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::open(&path, 16, 2).unwrap();
+        for line in ["第一行日志", "第二行日志", "第三行日志", "第四行日志"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "第四行日志\n");
+        assert_eq!(fs::read_to_string(file.rotated(1)).unwrap(), "第三行日志\n");
+        assert_eq!(fs::read_to_string(file.rotated(2)).unwrap(), "第二行日志\n");
+        assert!(!file.rotated(3).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,591 @@
+//! 指标
+//!
+//! 全局的 [`Registry`] 按名称与标签保存计数器、仪表与直方图。注册时得到的句柄可以克隆并在热点
+//! 循环中记录，记录只涉及原子操作。可视化服务定期将 [`snapshot`] 推送给客户端，
+//! [`serve_prometheus`] 则在本机以 Prometheus 文本格式提供全部指标。
+//!
+//! 同一名称与标签重复注册时返回同一个指标，因此可以在需要时再注册，无需集中声明。
+//!
+//! 目前登记指标的有：[`Pipeline`](crate::Pipeline) 各阶段的处理数量、耗时与队列长度，调用了
+//! `metrics` 的 [`TubeBuilder`](crate::TubeBuilder) 与 [`BroadcastBuilder`](crate::BroadcastBuilder)
+//! 的收发统计，[`trace`](crate::trace) 的端到端延迟，以及可视化服务各数据流的帧率。
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use utility::metrics;
+//!
+//! metrics::describe("detector_frames_total", "检测器处理的帧数");
+//! let frames = metrics::counter("detector_frames_total", &[("camera", "main")]);
+//! let latency = metrics::histogram("detector_latency_seconds", &[], metrics::LATENCY_BUCKETS);
+//! frames.inc();
+//! latency.observe_duration(Duration::from_millis(3));
+//! assert!(metrics::render_prometheus().contains("detector_frames_total{camera=\"main\"} 1"));
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::CancelToken;
+
+/// 以秒为单位的延迟直方图的默认分桶上界
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0,
+];
+
+/// 单调递增的计数器，通过 `Default` 创建的计数器不在注册表中
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// 可任意设置的仪表
+#[derive(Debug, Clone)]
+pub struct Gauge(Arc<AtomicF64>);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value);
+    }
+
+    pub fn add(&self, value: f64) {
+        self.0.add(value);
+    }
+
+    pub fn get(&self) -> f64 {
+        self.0.load()
+    }
+}
+
+/// 以 `f64` 的位模式保存在 [`AtomicU64`] 中
+#[derive(Debug, Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn store(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    fn add(&self, value: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+}
+
+#[derive(Debug)]
+struct HistogramCore {
+    /// 各分桶的上界，递增排列
+    bounds: Vec<f64>,
+    /// 各分桶（不累计）的数量，最后一个为超出全部上界的数量
+    counts: Vec<AtomicU64>,
+    sum: AtomicF64,
+}
+
+/// 按固定分桶统计的直方图
+#[derive(Debug, Clone)]
+pub struct Histogram(Arc<HistogramCore>);
+
+impl Histogram {
+    pub fn observe(&self, value: f64) {
+        let core = &self.0;
+        let bucket = core.bounds.partition_point(|bound| *bound < value);
+        core.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        core.sum.add(value);
+    }
+
+    /// 以秒为单位记录时长
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.0
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+impl Metric {
+    fn kind(&self) -> &'static str {
+        match self {
+            Metric::Counter(_) => "counter",
+            Metric::Gauge(_) => "gauge",
+            Metric::Histogram(_) => "histogram",
+        }
+    }
+
+    fn value(&self) -> MetricValue {
+        match self {
+            Metric::Counter(counter) => MetricValue::Counter(counter.get()),
+            Metric::Gauge(gauge) => MetricValue::Gauge(gauge.get()),
+            Metric::Histogram(histogram) => {
+                let core = &histogram.0;
+                let mut cumulative = 0;
+                let mut buckets = Vec::with_capacity(core.counts.len());
+                for (index, count) in core.counts.iter().enumerate() {
+                    cumulative += count.load(Ordering::Relaxed);
+                    let bound = core.bounds.get(index).copied().unwrap_or(f64::INFINITY);
+                    buckets.push((bound, cumulative));
+                }
+                MetricValue::Histogram {
+                    count: cumulative,
+                    sum: core.sum.load(),
+                    buckets,
+                }
+            }
+        }
+    }
+}
+
+/// 指标的取值
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricValue {
+    Counter(u64),
+    Gauge(f64),
+    Histogram {
+        count: u64,
+        sum: f64,
+        /// 各分桶的上界与不超过该上界的累计数量，最后一个分桶的上界为正无穷
+        buckets: Vec<(f64, u64)>,
+    },
+}
+
+/// 单个指标在某一时刻的取值
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricSnapshot {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: MetricValue,
+}
+
+type Labels = Vec<(String, String)>;
+
+/// 指标注册表
+#[derive(Default)]
+pub struct Registry {
+    /// 按名称排序，同名指标按标签排序
+    metrics: Mutex<BTreeMap<String, BTreeMap<Labels, Metric>>>,
+    help: Mutex<HashMap<String, String>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 设置指标的说明，输出为 Prometheus 的 `# HELP`
+    pub fn describe(&self, name: &str, help: &str) {
+        self.help
+            .lock()
+            .expect("锁中毒")
+            .insert(name.to_string(), help.to_string());
+    }
+
+    /// 注册或取得计数器
+    ///
+    /// # Panics
+    /// 名称或标签名不合法，或同名指标已注册为其他类型时 panic
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> Counter {
+        match self.get_or_insert(name, labels, "counter", || {
+            Metric::Counter(Counter(Default::default()))
+        }) {
+            Metric::Counter(counter) => counter,
+            _ => unreachable!(),
+        }
+    }
+
+    /// 注册或取得仪表
+    ///
+    /// # Panics
+    /// 同 [`counter`](Registry::counter)
+    pub fn gauge(&self, name: &str, labels: &[(&str, &str)]) -> Gauge {
+        match self.get_or_insert(name, labels, "gauge", || {
+            Metric::Gauge(Gauge(Default::default()))
+        }) {
+            Metric::Gauge(gauge) => gauge,
+            _ => unreachable!(),
+        }
+    }
+
+    /// 注册或取得直方图，`bounds` 为递增的分桶上界，已注册时忽略
+    ///
+    /// # Panics
+    /// 同 [`counter`](Registry::counter)，`bounds` 不是严格递增时同样 panic
+    pub fn histogram(&self, name: &str, labels: &[(&str, &str)], bounds: &[f64]) -> Histogram {
+        assert!(
+            bounds.windows(2).all(|pair| pair[0] < pair[1]),
+            "[utility] 直方图{}的分桶上界必须严格递增",
+            name
+        );
+        let metric = self.get_or_insert(name, labels, "histogram", || {
+            Metric::Histogram(Histogram(Arc::new(HistogramCore {
+                bounds: bounds.to_vec(),
+                counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+                sum: AtomicF64::default(),
+            })))
+        });
+        match metric {
+            Metric::Histogram(histogram) => histogram,
+            _ => unreachable!(),
+        }
+    }
+
+    fn get_or_insert(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        kind: &str,
+        create: impl FnOnce() -> Metric,
+    ) -> Metric {
+        assert!(valid_name(name), "[utility] 指标名称{:?}不合法", name);
+        let mut key: Labels = labels
+            .iter()
+            .map(|(label, value)| {
+                assert!(
+                    valid_name(label) && !label.contains(':'),
+                    "[utility] 指标{}的标签名{:?}不合法",
+                    name,
+                    label
+                );
+                (label.to_string(), value.to_string())
+            })
+            .collect();
+        key.sort();
+
+        let mut metrics = self.metrics.lock().expect("锁中毒");
+        let family = metrics.entry(name.to_string()).or_default();
+        if let Some(existing) = family.values().next() {
+            assert_eq!(
+                existing.kind(),
+                kind,
+                "[utility] 指标{}已注册为其他类型",
+                name
+            );
+        }
+        family.entry(key).or_insert_with(create).clone()
+    }
+
+    /// 全部指标当前的取值，按名称与标签排序
+    pub fn snapshot(&self) -> Vec<MetricSnapshot> {
+        let metrics = self.metrics.lock().expect("锁中毒");
+        metrics
+            .iter()
+            .flat_map(|(name, family)| {
+                family.iter().map(move |(labels, metric)| MetricSnapshot {
+                    name: name.clone(),
+                    labels: labels.iter().cloned().collect(),
+                    value: metric.value(),
+                })
+            })
+            .collect()
+    }
+
+    /// 以 Prometheus 文本格式输出全部指标
+    pub fn render_prometheus(&self) -> String {
+        let help = self.help.lock().expect("锁中毒").clone();
+        let metrics = self.metrics.lock().expect("锁中毒");
+        let mut text = String::new();
+        for (name, family) in metrics.iter() {
+            let Some(kind) = family.values().next().map(Metric::kind) else {
+                continue;
+            };
+            if let Some(help) = help.get(name) {
+                let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+                let _ = writeln!(text, "# HELP {} {}", name, help);
+            }
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            for (labels, metric) in family {
+                match metric.value() {
+                    MetricValue::Counter(value) => {
+                        let _ = writeln!(text, "{}{} {}", name, format_labels(labels, None), value);
+                    }
+                    MetricValue::Gauge(value) => {
+                        let _ = writeln!(
+                            text,
+                            "{}{} {}",
+                            name,
+                            format_labels(labels, None),
+                            format_float(value)
+                        );
+                    }
+                    MetricValue::Histogram {
+                        count,
+                        sum,
+                        buckets,
+                    } => {
+                        for (bound, cumulative) in buckets {
+                            let le = ("le", format_float(bound));
+                            let _ = writeln!(
+                                text,
+                                "{}_bucket{} {}",
+                                name,
+                                format_labels(labels, Some(le)),
+                                cumulative
+                            );
+                        }
+                        let labels = format_labels(labels, None);
+                        let _ = writeln!(text, "{}_sum{} {}", name, labels, format_float(sum));
+                        let _ = writeln!(text, "{}_count{} {}", name, labels, count);
+                    }
+                }
+            }
+        }
+        text
+    }
+}
+
+/// Prometheus 的指标名称与标签名只能包含字母、数字、下划线与冒号，且不能以数字开头
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn format_labels(labels: &Labels, extra: Option<(&str, String)>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(label, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", label, value)
+        })
+        .collect();
+    if let Some((label, value)) = extra {
+        pairs.push(format!("{}=\"{}\"", label, value));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn format_float(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+/// 全局的指标注册表
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+/// 设置全局注册表中指标的说明
+pub fn describe(name: &str, help: &str) {
+    REGISTRY.describe(name, help);
+}
+
+/// 在全局注册表中注册或取得计数器
+pub fn counter(name: &str, labels: &[(&str, &str)]) -> Counter {
+    REGISTRY.counter(name, labels)
+}
+
+/// 在全局注册表中注册或取得仪表
+pub fn gauge(name: &str, labels: &[(&str, &str)]) -> Gauge {
+    REGISTRY.gauge(name, labels)
+}
+
+/// 在全局注册表中注册或取得直方图
+pub fn histogram(name: &str, labels: &[(&str, &str)], bounds: &[f64]) -> Histogram {
+    REGISTRY.histogram(name, labels, bounds)
+}
+
+/// 全局注册表中全部指标当前的取值
+pub fn snapshot() -> Vec<MetricSnapshot> {
+    REGISTRY.snapshot()
+}
+
+/// 以 Prometheus 文本格式输出全局注册表中的全部指标
+pub fn render_prometheus() -> String {
+    REGISTRY.render_prometheus()
+}
+
+/// 等待连接时检查取消信号的间隔
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 在 `addr` 上以 HTTP 提供 Prometheus 文本格式的全局指标，直到 `token` 被取消
+///
+/// # 返回值
+/// 无法监听地址或创建线程时返回错误，之后单个连接的错误仅输出警告
+pub fn serve_prometheus(addr: SocketAddr, token: CancelToken) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let local = listener.local_addr()?;
+    thread::Builder::new()
+        .name("指标".to_string())
+        .spawn(move || {
+            info!("[utility] 在 http://{}/metrics 提供指标", local);
+            while !token.is_cancelled() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(err) = respond(stream) {
+                            warn!("[utility] 响应指标请求失败：{}", err);
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        token.wait_timeout(ACCEPT_INTERVAL);
+                    }
+                    Err(err) => warn!("[utility] 接受指标连接失败：{}", err),
+                }
+            }
+        })?;
+    Ok(local)
+}
+
+/// 读取请求头并返回全部指标，仅支持 `GET /metrics` 与 `GET /`
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics" | "/")) => ("200 OK", render_prometheus()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CancelReason;
+
+    #[test]
+    fn test_registry() {
+        let registry = Registry::new();
+        let frames = registry.counter("frames_total", &[("camera", "main")]);
+        frames.add(2);
+        // 重复注册得到同一个指标，标签顺序无关
+        registry
+            .counter("frames_total", &[("camera", "main")])
+            .inc();
+        assert_eq!(frames.get(), 3);
+        let gauge = registry.gauge("queue_depth", &[("stage", "检测"), ("a", "b")]);
+        gauge.set(1.5);
+        registry
+            .gauge("queue_depth", &[("a", "b"), ("stage", "检测")])
+            .add(1.0);
+        assert_eq!(gauge.get(), 2.5);
+
+        let latency = registry.histogram("latency_seconds", &[], &[0.01, 0.1]);
+        for value in [0.005, 0.01, 0.05, 1.0] {
+            latency.observe(value);
+        }
+        registry.describe("latency_seconds", "处理延迟");
+
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 3);
+        let MetricValue::Histogram {
+            count,
+            sum,
+            buckets,
+        } = &snapshot[1].value
+        else {
+            panic!("应为直方图");
+        };
+        assert_eq!(*count, 4);
+        assert!((sum - 1.065).abs() < 1e-9);
+        assert_eq!(*buckets, [(0.01, 2), (0.1, 3), (f64::INFINITY, 4)]);
+
+        let text = registry.render_prometheus();
+        assert!(text.contains("# TYPE frames_total counter\nframes_total{camera=\"main\"} 3\n"));
+        assert!(text.contains("queue_depth{a=\"b\",stage=\"检测\"} 2.5\n"));
+        assert!(
+            text.contains("# HELP latency_seconds 处理延迟\n# TYPE latency_seconds histogram\n")
+        );
+        assert!(text.contains("latency_seconds_bucket{le=\"+Inf\"} 4\n"));
+        assert!(text.contains("latency_seconds_count 4\n"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_kind_mismatch() {
+        let registry = Registry::new();
+        registry.counter("value", &[]);
+        registry.gauge("value", &[("a", "b")]);
+    }
+
+    #[test]
+    fn test_serve_prometheus() {
+        counter("test_requests_total", &[]).inc();
+        let token = CancelToken::new("指标");
+        let addr = serve_prometheus("127.0.0.1:0".parse().unwrap(), token.clone()).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("test_requests_total 1"));
+        token.cancel(CancelReason::Requested);
+    }
+}
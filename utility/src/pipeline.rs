@@ -0,0 +1,617 @@
+//! 流水线
+//!
+//! 将系统表示为 相机 → 预处理 → 检测 → 追踪 → 控制 等阶段，每个阶段实现 [`Stage`]，由
+//! [`PipelineBuilder`] 以管道首尾相连，并在各自的线程中运行（可绑定到指定 CPU 核心并使用实时调度，
+//! 见 [`rt`](crate::rt)）。线程由
+//! [`Supervisor`] 管理：任一阶段出错时取消整条流水线；某个阶段结束或 panic 后，下游在处理完
+//! 剩余数据后结束，上游在下次发送时结束。
+//!
+//! 各阶段的处理数量、处理耗时与输入队列长度同时登记在全局的 [`metrics`](crate::metrics)
+//! 注册表中，以 `pipeline` 与 `stage` 标签区分；各阶段的输出管道以 `流水线/阶段` 为 `tube` 标签登记。
+//!
+//! ```rust
+//! use utility::{Pipeline, Stage};
+//!
+//! struct Counter(u32);
+//! impl Stage for Counter {
+//!     type Input = ();
+//!     type Output = u32;
+//!     fn process(&mut self, _: &(), output: &mut u32) -> anyhow::Result<bool> {
+//!         self.0 += 1;
+//!         *output = self.0;
+//!         Ok(true)
+//!     }
+//! }
+//!
+//! struct Print;
+//! impl Stage for Print {
+//!     type Input = u32;
+//!     type Output = ();
+//!     fn process(&mut self, input: &u32, _: &mut ()) -> anyhow::Result<bool> {
+//!         println!("{}", input);
+//!         Ok(false)
+//!     }
+//! }
+//!
+//! let mut pipeline = Pipeline::builder("示例")
+//!     .source("计数", Counter(0))
+//!     .sink("打印", Print)
+//!     .build()
+//!     .unwrap();
+//! pipeline.shutdown(std::time::Duration::from_secs(1));
+//! ```
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    metrics, root,
+    rt::ThreadConfig,
+    watchdog::{self, Heartbeat, WatchdogAction},
+    CancelReason, CancelToken, RestartPolicy, Supervisor, Timestamp, TubeBuilder, TubeMode,
+    TubeRecv, TubeSend, WorkerStatus,
+};
+
+/// 等待上游数据时检查取消信号的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// 流水线中的一个阶段
+///
+/// 源阶段的 `Input` 为 `()`，末端阶段的 `Output` 为 `()`。
+pub trait Stage: Send + 'static {
+    type Input: Send + 'static;
+    type Output: Default + Send + 'static;
+
+    /// 在阶段的线程中、开始处理数据前调用一次，可用于打开设备等
+    fn setup(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// 处理一份输入，结果写入 `output`
+    ///
+    /// `output` 是复用的缓冲区，保留着之前写入的内容。源阶段应在没有数据时阻塞等待。需要结束
+    /// 本阶段时取消当前作用域，如 `utility::current().cancel(..)`。
+    ///
+    /// # 返回值
+    /// 是否将 `output` 发送给下游
+    fn process(&mut self, input: &Self::Input, output: &mut Self::Output) -> anyhow::Result<bool>;
+}
+
+/// 阶段的运行统计
+struct StageMetrics {
+    processed: AtomicU64,
+    /// 累计处理耗时
+    busy_ns: AtomicU64,
+    /// 上次报告以来的最大处理耗时
+    max_ns: AtomicU64,
+    /// 输入队列中等待处理的数据数量
+    queue_depth: AtomicUsize,
+    /// 登记在全局指标注册表中的处理数量、处理耗时与队列长度
+    processed_total: metrics::Counter,
+    latency: metrics::Histogram,
+    queue_gauge: metrics::Gauge,
+}
+
+impl StageMetrics {
+    fn new(pipeline: &str, stage: &str) -> Self {
+        let labels = [("pipeline", pipeline), ("stage", stage)];
+        Self {
+            processed: AtomicU64::new(0),
+            busy_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+            queue_depth: AtomicUsize::new(0),
+            processed_total: metrics::counter("pipeline_processed_total", &labels),
+            latency: metrics::histogram(
+                "pipeline_latency_seconds",
+                &labels,
+                metrics::LATENCY_BUCKETS,
+            ),
+            queue_gauge: metrics::gauge("pipeline_queue_depth", &labels),
+        }
+    }
+
+    fn record(&self, elapsed: Duration) {
+        let ns = elapsed.as_nanos() as u64;
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        self.busy_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+        self.processed_total.inc();
+        self.latency.observe_duration(elapsed);
+    }
+
+    fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth, Ordering::Relaxed);
+        self.queue_gauge.set(depth as f64);
+    }
+}
+
+/// 阶段自上次报告以来的运行情况
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageReport {
+    pub name: String,
+    /// 累计处理的数量
+    pub processed: u64,
+    /// 每秒处理的数量
+    pub throughput: f64,
+    /// 输入队列中等待处理的数据数量
+    pub queue_depth: usize,
+    /// 平均处理耗时
+    pub latency_avg: Duration,
+    /// 最大处理耗时
+    pub latency_max: Duration,
+}
+
+/// 获取上游数据的结果
+enum Fetch<T> {
+    Data(Box<T>),
+    /// 暂无数据
+    Idle,
+    /// 上游已结束
+    Closed,
+}
+
+/// 阶段的数据来源
+trait Upstream<T> {
+    fn fetch(&mut self, metrics: &StageMetrics) -> Fetch<T>;
+    fn release(&mut self, dish: Box<T>);
+}
+
+/// 源阶段没有上游
+struct NoUpstream;
+
+impl Upstream<()> for NoUpstream {
+    fn fetch(&mut self, _: &StageMetrics) -> Fetch<()> {
+        // 零大小类型的 Box 不分配内存
+        Fetch::Data(Box::new(()))
+    }
+
+    fn release(&mut self, _: Box<()>) {}
+}
+
+impl<T> Upstream<T> for TubeRecv<T> {
+    fn fetch(&mut self, metrics: &StageMetrics) -> Fetch<T> {
+        metrics.set_queue_depth(self.len());
+        match self.recv_timeout(POLL_INTERVAL) {
+            Ok(Some(dish)) => Fetch::Data(dish),
+            Ok(None) => Fetch::Idle,
+            Err(_) => Fetch::Closed,
+        }
+    }
+
+    fn release(&mut self, dish: Box<T>) {
+        // 上游已结束时无需回收
+        let _ = self.recycle(dish);
+    }
+}
+
+/// 在当前线程中运行阶段，直到作用域被取消或上下游结束
+fn drive<S: Stage>(
+    mut stage: S,
+    mut upstream: impl Upstream<S::Input>,
+    mut output: Option<TubeSend<S::Output>>,
+    metrics: &StageMetrics,
+    heartbeat: Option<&Heartbeat>,
+) -> anyhow::Result<()> {
+    stage.setup()?;
+    // 末端阶段没有下游，输出写入此处后丢弃
+    let mut spare = S::Output::default();
+    while !crate::is_stopped() {
+        // 等待上游数据时同样视为正常运行
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.beat();
+        }
+        let dish = match upstream.fetch(metrics) {
+            Fetch::Data(dish) => dish,
+            Fetch::Idle => continue,
+            Fetch::Closed => break,
+        };
+        let buffer = match output.as_mut() {
+            Some(output) => &mut **output.get_send_buffer(),
+            None => &mut spare,
+        };
+        let start = Timestamp::now();
+        let send = stage.process(&dish, buffer)?;
+        metrics.record(start.elapsed());
+        upstream.release(dish);
+
+        if let Some(output) = output.as_mut().filter(|_| send) {
+            if output.send().is_err() {
+                // 下游已结束
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+type StageRun = Box<dyn FnOnce(&StageMetrics, Option<&Heartbeat>) -> anyhow::Result<()> + Send>;
+
+/// 尚未启动的阶段
+struct PendingStage {
+    name: String,
+    /// 由构建器指定的实时配置，在登记的同名配置之后应用
+    thread: ThreadConfig,
+    /// 看门狗的截止时间与处理方式
+    watchdog: Option<(Duration, WatchdogAction)>,
+    run: StageRun,
+}
+
+/// 流水线的构建器，`O` 为最后一个阶段的输出类型
+pub struct PipelineBuilder<O> {
+    parent: CancelToken,
+    name: String,
+    mode: TubeMode,
+    pool: usize,
+    stages: Vec<PendingStage>,
+    tail: Option<TubeRecv<O>>,
+}
+
+impl PipelineBuilder<()> {
+    /// 创建指定令牌之下的流水线，上级令牌取消时流水线随之停止
+    pub fn with_parent(parent: &CancelToken, name: &str) -> Self {
+        Self {
+            parent: parent.clone(),
+            name: name.to_string(),
+            mode: TubeMode::Latest,
+            pool: 3,
+            stages: Vec::new(),
+            tail: None,
+        }
+    }
+
+    /// 添加源阶段
+    pub fn source<S: Stage<Input = ()>>(self, name: &str, stage: S) -> PipelineBuilder<S::Output> {
+        let (output, tail) = self.tube(name);
+        self.push(name, Some(tail), move |metrics, heartbeat| {
+            drive(stage, NoUpstream, Some(output), metrics, heartbeat)
+        })
+    }
+
+    /// 启动全部阶段
+    ///
+    /// # 返回值
+    /// 无法创建线程时返回错误，已启动的阶段随返回值一起被停止
+    pub fn build(self) -> io::Result<Pipeline> {
+        let mut supervisor = Supervisor::with_parent(&self.parent, &self.name);
+        let mut stages = Vec::new();
+        for stage in self.stages {
+            let metrics = Arc::new(StageMetrics::new(&self.name, &stage.name));
+            let token = supervisor.token().clone();
+            let mut run = Some((stage.run, metrics.clone()));
+            let (name, thread, watchdog) = (stage.name.clone(), stage.thread, stage.watchdog);
+            supervisor.spawn(&stage.name, RestartPolicy::Never, move |_| {
+                let Some((run, metrics)) = run.take() else {
+                    return Ok(());
+                };
+                if !thread.is_empty() {
+                    thread.apply(&name);
+                }
+                let heartbeat =
+                    watchdog.map(|(deadline, action)| watchdog::register(&name, deadline, action));
+                let result = run(&metrics, heartbeat.as_ref());
+                // 出错时取消整条流水线
+                let failure = match &result {
+                    Err(err) => Some(format!("{:#}", err)),
+                    Ok(()) => match crate::current().reason() {
+                        Some(CancelReason::Error(message)) => Some(message),
+                        _ => None,
+                    },
+                };
+                if let Some(message) = failure {
+                    token.cancel(CancelReason::Error(format!(
+                        "阶段{}出错：{}",
+                        name, message
+                    )));
+                }
+                result
+            })?;
+            stages.push((stage.name, metrics));
+        }
+        let last = vec![(0, 0); stages.len()];
+        Ok(Pipeline {
+            supervisor,
+            stages,
+            reported: Timestamp::now(),
+            last,
+        })
+    }
+}
+
+impl<O: Send + 'static> PipelineBuilder<O> {
+    /// 添加中间阶段
+    ///
+    /// # Panics
+    /// 前一个阶段为末端阶段时 panic
+    pub fn stage<S: Stage<Input = O>>(
+        mut self,
+        name: &str,
+        stage: S,
+    ) -> PipelineBuilder<S::Output> {
+        let input = self.tail.take().expect("[流水线] 缺少上游阶段");
+        let (output, tail) = self.tube(name);
+        self.push(name, Some(tail), move |metrics, heartbeat| {
+            drive(stage, input, Some(output), metrics, heartbeat)
+        })
+    }
+
+    /// 添加末端阶段
+    ///
+    /// # Panics
+    /// 前一个阶段为末端阶段时 panic
+    pub fn sink<S: Stage<Input = O, Output = ()>>(
+        mut self,
+        name: &str,
+        stage: S,
+    ) -> PipelineBuilder<()> {
+        let input = self.tail.take().expect("[流水线] 缺少上游阶段");
+        self.push(name, None, move |metrics, heartbeat| {
+            drive(stage, input, None, metrics, heartbeat)
+        })
+    }
+
+    /// 将最后添加的阶段绑定到指定的 CPU 核心
+    pub fn pin(mut self, core: usize) -> Self {
+        if let Some(stage) = self.stages.last_mut() {
+            stage.thread.cores = vec![core];
+        }
+        self
+    }
+
+    /// 以指定优先级的 `SCHED_FIFO` 调度运行最后添加的阶段
+    ///
+    /// # Panics
+    /// 优先级不在 [`PRIORITY_RANGE`](crate::rt::PRIORITY_RANGE) 内时 panic
+    pub fn priority(mut self, priority: i32) -> Self {
+        if let Some(stage) = self.stages.last_mut() {
+            stage.thread = std::mem::take(&mut stage.thread).priority(priority);
+        }
+        self
+    }
+
+    /// 由看门狗监视最后添加的阶段，单次处理（或等待上游数据）超过 `deadline` 时按 `action` 处理
+    ///
+    /// 源阶段的 [`Stage::process`] 会阻塞等待数据，`deadline` 应大于数据到达的间隔。
+    pub fn watchdog(mut self, deadline: Duration, action: WatchdogAction) -> Self {
+        if let Some(stage) = self.stages.last_mut() {
+            stage.watchdog = Some((deadline, action));
+        }
+        self
+    }
+
+    /// 此后添加的阶段输出所用管道的模式，默认为 [`TubeMode::Latest`]
+    pub fn mode(mut self, mode: TubeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// 此后添加的阶段输出所用管道的缓冲区数量，默认为 3
+    pub fn pool(mut self, pool: usize) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// 创建阶段 `stage` 的输出管道，以 `流水线/阶段` 为名登记统计信息
+    fn tube<T: Default>(&self, stage: &str) -> (TubeSend<T>, TubeRecv<T>) {
+        TubeBuilder::new()
+            .pool(self.pool)
+            .mode(self.mode)
+            .metrics(&format!("{}/{}", self.name, stage))
+            .build()
+    }
+
+    fn push<N>(
+        self,
+        name: &str,
+        tail: Option<TubeRecv<N>>,
+        run: impl FnOnce(&StageMetrics, Option<&Heartbeat>) -> anyhow::Result<()> + Send + 'static,
+    ) -> PipelineBuilder<N> {
+        let mut stages = self.stages;
+        stages.push(PendingStage {
+            name: name.to_string(),
+            thread: ThreadConfig::new(),
+            watchdog: None,
+            run: Box::new(run),
+        });
+        PipelineBuilder {
+            parent: self.parent,
+            name: self.name,
+            mode: self.mode,
+            pool: self.pool,
+            stages,
+            tail,
+        }
+    }
+}
+
+/// 运行中的流水线，丢弃时停止全部阶段
+pub struct Pipeline {
+    supervisor: Supervisor,
+    stages: Vec<(String, Arc<StageMetrics>)>,
+    /// 上次报告的时间
+    reported: Timestamp,
+    /// 上次报告时各阶段的处理数量与累计耗时
+    last: Vec<(u64, u64)>,
+}
+
+impl Pipeline {
+    /// 创建根令牌之下的流水线
+    pub fn builder(name: &str) -> PipelineBuilder<()> {
+        PipelineBuilder::with_parent(root(), name)
+    }
+
+    /// 流水线的令牌
+    pub fn token(&self) -> &CancelToken {
+        self.supervisor.token()
+    }
+
+    /// 各阶段线程的状态
+    pub fn status(&self) -> Vec<WorkerStatus> {
+        self.supervisor.status()
+    }
+
+    /// 各阶段自上次报告以来的吞吐量与处理耗时
+    pub fn report(&mut self) -> Vec<StageReport> {
+        let now = Timestamp::now();
+        let elapsed = now.saturating_duration_since(self.reported).as_secs_f64();
+        self.reported = now;
+        self.stages
+            .iter()
+            .zip(&mut self.last)
+            .map(|((name, metrics), last)| {
+                let processed = metrics.processed.load(Ordering::Relaxed);
+                let busy_ns = metrics.busy_ns.load(Ordering::Relaxed);
+                let (count, busy) = (processed - last.0, busy_ns - last.1);
+                *last = (processed, busy_ns);
+                StageReport {
+                    name: name.clone(),
+                    processed,
+                    throughput: if elapsed > 0.0 {
+                        count as f64 / elapsed
+                    } else {
+                        0.0
+                    },
+                    queue_depth: metrics.queue_depth.load(Ordering::Relaxed),
+                    latency_avg: Duration::from_nanos(busy.checked_div(count).unwrap_or(0)),
+                    latency_max: Duration::from_nanos(metrics.max_ns.swap(0, Ordering::Relaxed)),
+                }
+            })
+            .collect()
+    }
+
+    /// 停止全部阶段，返回未能在超时前结束的阶段名称
+    pub fn shutdown(&mut self, timeout: Duration) -> Vec<String> {
+        self.supervisor.token().cancel(CancelReason::Requested);
+        self.supervisor.shutdown(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Health;
+    use std::sync::Mutex;
+
+    struct Counter(u32);
+
+    impl Stage for Counter {
+        type Input = ();
+        type Output = u32;
+
+        fn process(&mut self, _: &(), output: &mut u32) -> anyhow::Result<bool> {
+            if self.0 == 5 {
+                crate::current().cancel(CancelReason::Requested);
+                return Ok(false);
+            }
+            self.0 += 1;
+            *output = self.0;
+            Ok(true)
+        }
+    }
+
+    struct Double;
+
+    impl Stage for Double {
+        type Input = u32;
+        type Output = u32;
+
+        fn process(&mut self, input: &u32, output: &mut u32) -> anyhow::Result<bool> {
+            anyhow::ensure!(*input != 0, "输入为零");
+            *output = input * 2;
+            Ok(true)
+        }
+    }
+
+    struct Collect(Arc<Mutex<Vec<u32>>>);
+
+    impl Stage for Collect {
+        type Input = u32;
+        type Output = ();
+
+        fn process(&mut self, input: &u32, _: &mut ()) -> anyhow::Result<bool> {
+            self.0.lock().unwrap().push(*input);
+            Ok(false)
+        }
+    }
+
+    /// 等待直到全部阶段结束，最多一秒
+    fn wait_finished(pipeline: &Pipeline) {
+        let deadline = Timestamp::now() + Duration::from_secs(1);
+        while Timestamp::now() < deadline
+            && pipeline
+                .status()
+                .iter()
+                .any(|status| status.health == Health::Running)
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let parent = CancelToken::new("根");
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = PipelineBuilder::with_parent(&parent, "测试")
+            .mode(TubeMode::Fifo)
+            .pool(8)
+            .source("计数", Counter(0))
+            .stage("翻倍", Double)
+            .sink("收集", Collect(collected.clone()))
+            .build()
+            .unwrap();
+
+        // 源阶段结束后下游处理完剩余数据再结束
+        wait_finished(&pipeline);
+        assert_eq!(*collected.lock().unwrap(), [2, 4, 6, 8, 10]);
+        assert!(!pipeline.token().is_cancelled());
+        let report = pipeline.report();
+        assert_eq!(report.len(), 3);
+        assert_eq!(report[1].processed, 5);
+        assert_eq!(report[2].queue_depth, 0);
+        let text = metrics::render_prometheus();
+        assert!(text.contains("pipeline_processed_total{pipeline=\"测试\",stage=\"计数\"}"));
+        assert!(text.contains("tube_sent_total{tube=\"测试/翻倍\"}"));
+        assert!(pipeline.shutdown(Duration::from_secs(1)).is_empty());
+    }
+
+    struct Zero;
+
+    impl Stage for Zero {
+        type Input = ();
+        type Output = u32;
+
+        fn process(&mut self, _: &(), output: &mut u32) -> anyhow::Result<bool> {
+            std::thread::sleep(Duration::from_millis(1));
+            *output = 0;
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_pipeline_error() {
+        let parent = CancelToken::new("根");
+        let mut pipeline = PipelineBuilder::with_parent(&parent, "测试")
+            .source("零", Zero)
+            .stage("翻倍", Double)
+            .sink("收集", Collect(Arc::default()))
+            .build()
+            .unwrap();
+
+        // 出错的阶段取消整条流水线，但不影响上级
+        assert!(pipeline
+            .token()
+            .wait_timeout(Duration::from_secs(1))
+            .is_some());
+        assert_eq!(
+            pipeline.token().reason(),
+            Some(CancelReason::Error("阶段翻倍出错：输入为零".to_string()))
+        );
+        wait_finished(&pipeline);
+        assert!(!parent.is_cancelled());
+        assert!(pipeline.shutdown(Duration::from_secs(1)).is_empty());
+    }
+}
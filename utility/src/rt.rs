@@ -0,0 +1,306 @@
+//! 实时线程配置
+//!
+//! 为具名线程配置绑定的 CPU 核心与 `SCHED_FIFO` 实时优先级，并可通过 [`lock_memory`] 锁定进程
+//! 内存，避免视觉线程与其他进程争抢 CPU 或因缺页产生延迟抖动。
+//!
+//! 启动时通过 [`configure`] 按线程名称登记配置，[`Supervisor`](crate::Supervisor) 启动的线程
+//! （包括流水线的各阶段）会在运行前自动应用与线程同名的配置。各阶段的线程名称见 [`CAMERA`] 等
+//! 常量，登记与启动线程时应使用同一常量，[`unmatched`] 列出尚无同名线程启动的登记。进程缺少
+//! 相应权限（如未授予 `CAP_SYS_NICE`、`CAP_IPC_LOCK`）或平台不支持时仅输出警告，线程以普通调度
+//! 继续运行。
+//!
+//! ```rust
+//! use utility::rt::{self, ThreadConfig};
+//!
+//! rt::configure(rt::DETECT, ThreadConfig::new().cores(&[2, 3]).priority(80));
+//! std::thread::spawn(|| {
+//!     rt::apply_current(rt::DETECT);
+//!     // 工作
+//! });
+//! ```
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    io,
+    sync::{LazyLock, Mutex},
+};
+
+use log::{info, warn};
+
+/// 相机采集线程的名称
+pub const CAMERA: &str = "相机";
+/// 检测线程的名称
+pub const DETECT: &str = "检测";
+/// 追踪线程的名称
+pub const TRACK: &str = "追踪";
+/// 可视化服务线程的名称
+pub const GUI: &str = "可视化";
+
+/// `SCHED_FIFO` 优先级的取值范围
+pub const PRIORITY_RANGE: std::ops::RangeInclusive<i32> = 1..=99;
+
+/// 单个线程的实时配置
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadConfig {
+    /// 允许运行的 CPU 核心，为空时不限制
+    pub cores: Vec<usize>,
+    /// `SCHED_FIFO` 优先级，为 `None` 时保持普通调度
+    pub priority: Option<i32>,
+}
+
+impl ThreadConfig {
+    /// 不绑定核心、保持普通调度的配置
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 绑定到指定的 CPU 核心
+    pub fn cores(mut self, cores: &[usize]) -> Self {
+        self.cores = cores.to_vec();
+        self
+    }
+
+    /// 使用指定优先级的 `SCHED_FIFO` 调度
+    ///
+    /// # Panics
+    /// 优先级不在 [`PRIORITY_RANGE`] 内时 panic
+    pub fn priority(mut self, priority: i32) -> Self {
+        assert!(
+            PRIORITY_RANGE.contains(&priority),
+            "[utility] 实时优先级{}超出范围{:?}",
+            priority,
+            PRIORITY_RANGE
+        );
+        self.priority = Some(priority);
+        self
+    }
+
+    /// 是否不做任何修改
+    pub fn is_empty(&self) -> bool {
+        self.cores.is_empty() && self.priority.is_none()
+    }
+
+    /// 将配置应用到当前线程，失败的项目输出警告后跳过
+    ///
+    /// # 返回值
+    /// 全部项目都成功应用时返回 `true`
+    pub fn apply(&self, name: &str) -> bool {
+        let mut ok = true;
+        if !self.cores.is_empty() {
+            if let Err(err) = set_affinity(&self.cores) {
+                warn!(
+                    "[utility] 无法将线程{}绑定到核心{:?}：{}",
+                    name, self.cores, err
+                );
+                ok = false;
+            }
+        }
+        if let Some(priority) = self.priority {
+            if let Err(err) = set_priority(priority) {
+                warn!(
+                    "[utility] 无法为线程{}设置实时优先级{}：{}{}，将以普通调度运行",
+                    name,
+                    priority,
+                    err,
+                    permission_hint(&err, "CAP_SYS_NICE")
+                );
+                ok = false;
+            }
+        }
+        if ok && !self.is_empty() {
+            info!("[utility] 线程{}已应用实时配置{:?}", name, self);
+        }
+        ok
+    }
+}
+
+static REGISTRY: LazyLock<Mutex<HashMap<String, ThreadConfig>>> = LazyLock::new(Default::default);
+
+/// 调用过 [`apply_current`] 的线程名称
+static STARTED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+thread_local! {
+    /// 当前线程应用的登记名称与实时配置
+    static CURRENT: RefCell<Option<(String, ThreadConfig)>> = const { RefCell::new(None) };
+}
+
+/// 登记具名线程的实时配置，此后启动的同名线程将应用该配置
+///
+/// 空配置会移除先前的登记。
+pub fn configure(name: &str, config: ThreadConfig) {
+    let mut registry = REGISTRY.lock().expect("锁中毒");
+    if config.is_empty() {
+        registry.remove(name);
+    } else {
+        registry.insert(name.to_string(), config);
+    }
+}
+
+/// 为指定名称登记的实时配置
+pub fn config_of(name: &str) -> Option<ThreadConfig> {
+    REGISTRY.lock().expect("锁中毒").get(name).cloned()
+}
+
+/// 将为 `name` 登记的实时配置应用到当前线程，未登记时不做任何修改
+pub fn apply_current(name: &str) {
+    STARTED.lock().expect("锁中毒").insert(name.to_string());
+    if let Some(config) = config_of(name) {
+        config.apply(name);
+        CURRENT.with(|current| *current.borrow_mut() = Some((name.to_string(), config)));
+    }
+}
+
+/// 当前线程通过 [`apply_current`] 应用的登记名称与实时配置，部分项目可能因权限不足未能生效
+pub fn current() -> Option<(String, ThreadConfig)> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// 已登记实时配置、但尚无同名线程启动的名称，按名称排序
+pub fn unmatched() -> Vec<String> {
+    let started = STARTED.lock().expect("锁中毒");
+    let mut names: Vec<_> = REGISTRY
+        .lock()
+        .expect("锁中毒")
+        .keys()
+        .filter(|name| !started.contains(*name))
+        .cloned()
+        .collect();
+    names.sort();
+    names
+}
+
+/// 将当前线程绑定到指定的 CPU 核心
+#[cfg(target_os = "linux")]
+pub fn set_affinity(cores: &[usize]) -> io::Result<()> {
+    // SAFETY: cpu_set_t 是普通的位图，全零即空集合
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &core in cores {
+            if core >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("核心编号{}超出范围", core),
+                ));
+            }
+            libc::CPU_SET(core, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_affinity(_cores: &[usize]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// 将当前线程设为指定优先级的 `SCHED_FIFO` 调度
+#[cfg(target_os = "linux")]
+pub fn set_priority(priority: i32) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    // SAFETY: pthread_self 总是有效的线程句柄
+    let code =
+        unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_priority(_priority: i32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// 锁定进程当前及此后分配的全部内存，失败时输出警告
+///
+/// # 返回值
+/// 成功锁定时返回 `true`
+pub fn lock_memory() -> bool {
+    match mlockall() {
+        Ok(()) => {
+            info!("[utility] 已锁定进程内存");
+            true
+        }
+        Err(err) => {
+            warn!(
+                "[utility] 无法锁定进程内存：{}{}",
+                err,
+                permission_hint(&err, "CAP_IPC_LOCK")
+            );
+            false
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn mlockall() -> io::Result<()> {
+    // SAFETY: mlockall 不涉及任何指针
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mlockall() -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// 权限不足时提示所需的 capability
+fn permission_hint(err: &io::Error, capability: &str) -> String {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => format!("（进程缺少 {} 权限）", capability),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        configure("测试线程", ThreadConfig::new().cores(&[0]).priority(10));
+        assert_eq!(
+            config_of("测试线程"),
+            Some(ThreadConfig {
+                cores: vec![0],
+                priority: Some(10),
+            })
+        );
+        // 空配置移除登记
+        configure("测试线程", ThreadConfig::new());
+        assert_eq!(config_of("测试线程"), None);
+    }
+
+    #[test]
+    fn test_unmatched() {
+        configure("未启动的线程", ThreadConfig::new().cores(&[0]));
+        assert!(unmatched().contains(&"未启动的线程".to_string()));
+        std::thread::spawn(|| apply_current("未启动的线程"))
+            .join()
+            .unwrap();
+        assert!(!unmatched().contains(&"未启动的线程".to_string()));
+        configure("未启动的线程", ThreadConfig::new());
+    }
+
+    #[test]
+    fn test_apply_fallback() {
+        // 无法应用时仅输出警告并返回 false，不会 panic
+        let config = ThreadConfig::new().cores(&[usize::MAX]);
+        std::thread::spawn(move || assert!(!config.apply("测试线程")))
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_priority_range() {
+        ThreadConfig::new().priority(100);
+    }
+}
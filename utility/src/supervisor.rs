@@ -0,0 +1,421 @@
+//! 线程监督
+//!
+//! [`Supervisor`] 持有一组具名的工作线程，捕获每次运行返回的错误与 panic，并按
+//! [`RestartPolicy`] 决定是否重启。每个工作线程拥有监督者令牌的子令牌，每次运行再派生一个
+//! 子令牌作为线程的作用域，因此 [`ensure_or_stop!`](crate::ensure_or_stop) 等宏只会结束本次运行，
+//! 由监督者按策略重启，而不会影响其他线程。
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use utility::{Backoff, RestartPolicy, Supervisor};
+//!
+//! let mut supervisor = Supervisor::new("示例");
+//! supervisor
+//!     .spawn("工作", RestartPolicy::OnFailure(Backoff::default()), |token| {
+//!         while !token.is_cancelled() {
+//!             // 工作
+//!             # break;
+//!         }
+//!         Ok(())
+//!     })
+//!     .unwrap();
+//! supervisor.shutdown(Duration::from_secs(1));
+//! ```
+use std::{
+    any::Any,
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError};
+use log::{error, info, warn};
+
+use crate::{root, rt, CancelReason, CancelToken};
+
+/// 默认的关闭等待时间
+pub const DEFAULT_JOIN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 重启的退避策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    /// 首次重启前的等待时间，之后每次加倍
+    pub initial: Duration,
+    /// 等待时间的上限
+    pub max: Duration,
+    /// 最多重启的次数，`None` 表示不限
+    pub limit: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            limit: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// 第 `attempt` 次重启（从 1 开始）前的等待时间
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max)
+    }
+}
+
+/// 重启策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// 从不重启
+    Never,
+    /// 仅在返回错误、panic 或作用域因错误被取消时重启
+    OnFailure(Backoff),
+    /// 除被监督者停止外，结束后总是重启
+    Always(Backoff),
+}
+
+/// 工作线程的健康状态
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    /// 正在运行
+    Running,
+    /// 等待重启
+    Restarting { attempt: u32, delay: Duration },
+    /// 正常结束且不再重启
+    Exited,
+    /// 失败且不再重启
+    Failed(String),
+    /// 已被监督者停止
+    Stopped,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Health::Running => write!(f, "运行中"),
+            Health::Restarting { attempt, delay } => {
+                write!(f, "{:?}后第{}次重启", delay, attempt)
+            }
+            Health::Exited => write!(f, "已结束"),
+            Health::Failed(message) => write!(f, "已失败：{}", message),
+            Health::Stopped => write!(f, "已停止"),
+        }
+    }
+}
+
+/// 工作线程的状态
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStatus {
+    pub name: String,
+    pub health: Health,
+    /// 已重启的次数
+    pub restarts: u32,
+    /// 最近一次失败的原因
+    pub last_error: Option<String>,
+}
+
+struct Worker {
+    token: CancelToken,
+    status: Arc<Mutex<WorkerStatus>>,
+    handle: JoinHandle<()>,
+    /// 线程结束时断开
+    done: Receiver<()>,
+}
+
+/// 持有并监督一组具名工作线程，丢弃时以 [`DEFAULT_JOIN_TIMEOUT`] 关闭全部线程
+pub struct Supervisor {
+    token: CancelToken,
+    workers: Vec<Worker>,
+}
+
+impl Supervisor {
+    /// 创建根令牌之下的监督者
+    pub fn new(name: &str) -> Self {
+        Self::with_parent(root(), name)
+    }
+
+    /// 创建指定令牌之下的监督者，上级令牌取消时全部工作线程随之停止
+    pub fn with_parent(parent: &CancelToken, name: &str) -> Self {
+        Self {
+            token: parent.child(name),
+            workers: Vec::new(),
+        }
+    }
+
+    /// 监督者的令牌
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+
+    /// 启动具名工作线程
+    ///
+    /// 每次运行时 `f` 收到本次运行的令牌，应在令牌被取消后尽快返回。线程启动后首先应用通过
+    /// [`rt::configure`](crate::rt::configure) 为同名线程登记的实时配置。
+    ///
+    /// # 返回值
+    /// 无法创建线程时返回错误
+    pub fn spawn<F>(&mut self, name: &str, policy: RestartPolicy, f: F) -> io::Result<()>
+    where
+        F: FnMut(&CancelToken) -> anyhow::Result<()> + Send + 'static,
+    {
+        let token = self.token.child(name);
+        let status = Arc::new(Mutex::new(WorkerStatus {
+            name: name.to_string(),
+            health: Health::Running,
+            restarts: 0,
+            last_error: None,
+        }));
+        let (sender, done) = bounded::<()>(0);
+        let handle = thread::Builder::new().name(name.to_string()).spawn({
+            let token = token.clone();
+            let status = status.clone();
+            let name = name.to_string();
+            move || {
+                rt::apply_current(&name);
+                run(&token, policy, &status, f);
+                drop(sender);
+            }
+        })?;
+        self.workers.push(Worker {
+            token,
+            status,
+            handle,
+            done,
+        });
+        Ok(())
+    }
+
+    /// 全部工作线程的状态，按启动顺序排列
+    pub fn status(&self) -> Vec<WorkerStatus> {
+        self.workers
+            .iter()
+            .map(|worker| worker.status.lock().expect("锁中毒").clone())
+            .collect()
+    }
+
+    /// 是否全部工作线程都在运行或正常结束
+    pub fn is_healthy(&self) -> bool {
+        self.status()
+            .iter()
+            .all(|status| matches!(status.health, Health::Running | Health::Exited))
+    }
+
+    /// 按启动顺序的逆序逐个停止工作线程，每个线程最多等待 `timeout`
+    ///
+    /// # 返回值
+    /// 未能在超时前结束的线程名称，这些线程将被分离
+    pub fn shutdown(&mut self, timeout: Duration) -> Vec<String> {
+        let mut detached = Vec::new();
+        while let Some(worker) = self.workers.pop() {
+            let name = worker.status.lock().expect("锁中毒").name.clone();
+            worker.token.cancel(CancelReason::Requested);
+            // 线程结束时发送端断开
+            match worker.done.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = worker.handle.join();
+                    info!("[utility] 线程{}已停止", name);
+                }
+                _ => {
+                    warn!("[utility] 线程{}未能在{:?}内停止，已分离", name, timeout);
+                    detached.push(name);
+                }
+            }
+        }
+        self.token.cancel(CancelReason::Requested);
+        detached
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.shutdown(DEFAULT_JOIN_TIMEOUT);
+    }
+}
+
+/// 运行并按策略重启工作线程，直到不再重启或令牌被取消
+fn run<F>(token: &CancelToken, policy: RestartPolicy, status_lock: &Mutex<WorkerStatus>, mut f: F)
+where
+    F: FnMut(&CancelToken) -> anyhow::Result<()>,
+{
+    let name = token.name().to_string();
+    let mut attempt = 0;
+    loop {
+        let scope = token.child(&format!("第{}次运行", attempt + 1));
+        let result = {
+            let _scope = scope.enter();
+            panic::catch_unwind(AssertUnwindSafe(|| f(&scope)))
+        };
+        let failure = match result {
+            Ok(Ok(())) => match scope.reason() {
+                Some(CancelReason::Error(message)) => Some(message),
+                _ => None,
+            },
+            Ok(Err(err)) => Some(format!("{:#}", err)),
+            Err(payload) => Some(format!("panic：{}", panic_message(&*payload))),
+        };
+        scope.cancel(CancelReason::Requested);
+
+        let mut status = status_lock.lock().expect("锁中毒");
+        if let Some(message) = &failure {
+            error!("[utility] 线程{}失败：{}", name, message);
+            status.last_error = Some(message.clone());
+        }
+        if token.is_cancelled() {
+            status.health = Health::Stopped;
+            return;
+        }
+        let backoff = match policy {
+            RestartPolicy::Never => None,
+            RestartPolicy::OnFailure(backoff) => failure.is_some().then_some(backoff),
+            RestartPolicy::Always(backoff) => Some(backoff),
+        };
+        attempt += 1;
+        let Some(backoff) =
+            backoff.filter(|backoff| backoff.limit.is_none_or(|limit| attempt <= limit))
+        else {
+            if backoff.is_some() {
+                warn!("[utility] 线程{}已达到重启次数上限", name);
+            }
+            status.health = match failure {
+                Some(message) => Health::Failed(message),
+                None => Health::Exited,
+            };
+            return;
+        };
+        let delay = backoff.delay(attempt);
+        status.health = Health::Restarting { attempt, delay };
+        drop(status);
+
+        info!("[utility] 线程{}将在{:?}后第{}次重启", name, delay, attempt);
+        let stopped = token.wait_timeout(delay).is_some();
+        let mut status = status_lock.lock().expect("锁中毒");
+        if stopped {
+            status.health = Health::Stopped;
+            return;
+        }
+        status.health = Health::Running;
+        status.restarts = attempt;
+    }
+}
+
+/// 提取 panic 的消息
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(ToString::to_string)
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "未知原因".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn backoff(limit: Option<u32>) -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(4),
+            limit,
+        }
+    }
+
+    /// 等待直到条件满足，最多一秒
+    fn wait_until(cond: impl Fn() -> bool) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(1);
+        while !cond() && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = backoff(None);
+        let delays: Vec<_> = (1..=4).map(|attempt| backoff.delay(attempt)).collect();
+        assert_eq!(delays, [1, 2, 4, 4].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn test_restart_on_failure() {
+        let parent = CancelToken::new("根");
+        let mut supervisor = Supervisor::with_parent(&parent, "测试");
+        let runs = Arc::new(AtomicU32::new(0));
+        supervisor
+            .spawn("失败", RestartPolicy::OnFailure(backoff(Some(2))), {
+                let runs = runs.clone();
+                move |_| match runs.fetch_add(1, Ordering::Relaxed) {
+                    0 => anyhow::bail!("出错"),
+                    1 => panic!("崩溃"),
+                    _ => {
+                        // 宏出错只取消本次运行
+                        fn work() {
+                            crate::ensure_or_stop!(false, "条件不满足");
+                        }
+                        work();
+                        Ok(())
+                    }
+                }
+            })
+            .unwrap();
+        supervisor
+            .spawn("正常", RestartPolicy::Never, |_| Ok(()))
+            .unwrap();
+
+        wait_until(|| matches!(supervisor.status()[0].health, Health::Failed(_)));
+        assert!(!supervisor.is_healthy());
+        let status = supervisor.status();
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+        assert_eq!(status[0].restarts, 2);
+        assert_eq!(status[0].health, Health::Failed("条件不满足".to_string()));
+        assert_eq!(status[1].health, Health::Exited);
+        assert!(!parent.is_cancelled());
+    }
+
+    #[test]
+    fn test_apply_rt_config() {
+        let config = rt::ThreadConfig::new().cores(&[0]);
+        rt::configure("实时工作", config.clone());
+        let parent = CancelToken::new("根");
+        let mut supervisor = Supervisor::with_parent(&parent, "测试");
+        let (sender, applied) = bounded(1);
+        supervisor
+            .spawn("实时工作", RestartPolicy::Never, move |_| {
+                sender.send(rt::current())?;
+                Ok(())
+            })
+            .unwrap();
+
+        // 工作线程在运行前应用了与其同名的登记配置
+        let applied = applied.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(applied, Some(("实时工作".to_string(), config)));
+        assert!(!rt::unmatched().contains(&"实时工作".to_string()));
+        rt::configure("实时工作", rt::ThreadConfig::new());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let parent = CancelToken::new("根");
+        let mut supervisor = Supervisor::with_parent(&parent, "测试");
+        supervisor
+            .spawn("循环", RestartPolicy::Always(backoff(None)), |token| {
+                token.wait();
+                Ok(())
+            })
+            .unwrap();
+        supervisor
+            .spawn("阻塞", RestartPolicy::Never, |_| {
+                thread::sleep(Duration::from_millis(200));
+                Ok(())
+            })
+            .unwrap();
+
+        let detached = supervisor.shutdown(Duration::from_millis(20));
+        assert_eq!(detached, ["阻塞"]);
+        assert!(supervisor.status().is_empty());
+    }
+}
@@ -0,0 +1,469 @@
+//! 时间基准与时钟同步
+//!
+//! 各 crate 统一使用 [`Timestamp`] 表示主机的单调时间，即自进程时间零点起的纳秒数，可以直接
+//! 比较、相减与序列化。相机、下位机等设备的时间以各自时间零点起的 [`Duration`] 表示：
+//!
+//! - [`TickConverter`] 将设备的计数器（可能回绕）换算为设备时间；
+//! - [`ClockSync`] 由往返请求或单向到达的样本在线估计设备时钟相对主机时钟的偏移与漂移，
+//!   从而在两个时间域之间换算。
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use utility::time::{ClockSync, TickConverter, Timestamp};
+//!
+//! // 1 MHz 的 32 位计数器
+//! let mut ticks = TickConverter::new(1_000_000, 32);
+//! let remote = ticks.to_duration(2_000);
+//! assert_eq!(remote, Duration::from_millis(2));
+//!
+//! let mut sync = ClockSync::new(32);
+//! let sent = Timestamp::now();
+//! // 向下位机请求时间，收到回复
+//! let received = Timestamp::now();
+//! sync.add_round_trip(sent, remote, received);
+//! let local = sync.to_local(remote).unwrap();
+//! assert!(sent <= local && local <= received);
+//! ```
+use std::{
+    collections::VecDeque,
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// 进程的时间零点，首次使用时确定
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// 主机的单调时间，自进程时间零点起的纳秒数
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// 进程的时间零点
+    pub const ZERO: Timestamp = Timestamp(0);
+
+    /// 当前时间
+    pub fn now() -> Self {
+        Self::from_instant(Instant::now())
+    }
+
+    /// 由 [`Instant`] 换算，早于时间零点的时刻视为零点
+    pub fn from_instant(instant: Instant) -> Self {
+        Self::from_duration(instant.saturating_duration_since(*EPOCH))
+    }
+
+    /// 换算为 [`Instant`]
+    pub fn to_instant(self) -> Instant {
+        *EPOCH + self.as_duration()
+    }
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Self(nanos)
+    }
+
+    /// 由自时间零点起的时长构造，超出 `u64` 纳秒范围时饱和
+    pub fn from_duration(duration: Duration) -> Self {
+        Self(u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX))
+    }
+
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// 自时间零点起的时长
+    pub const fn as_duration(self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 * 1e-9
+    }
+
+    /// 自 `earlier` 起经过的时长，`earlier` 晚于自身时返回零
+    pub fn saturating_duration_since(self, earlier: Timestamp) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// 自 `earlier` 起经过的时长，`earlier` 晚于自身时返回 `None`
+    pub fn checked_duration_since(self, earlier: Timestamp) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    /// 自该时刻起到现在经过的时长
+    pub fn elapsed(self) -> Duration {
+        Timestamp::now().saturating_duration_since(self)
+    }
+
+    /// 两个时刻之差（`self - other`）的纳秒数，可以为负
+    pub fn signed_nanos_since(self, other: Timestamp) -> i64 {
+        (self.0 as i128 - other.0 as i128) as i64
+    }
+}
+
+impl From<Instant> for Timestamp {
+    fn from(instant: Instant) -> Self {
+        Self::from_instant(instant)
+    }
+}
+
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, rhs: Duration) -> Timestamp {
+        Timestamp(self.0.saturating_add(Timestamp::from_duration(rhs).0))
+    }
+}
+
+impl AddAssign<Duration> for Timestamp {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    /// 早于时间零点时饱和为零点
+    fn sub(self, rhs: Duration) -> Timestamp {
+        Timestamp(self.0.saturating_sub(Timestamp::from_duration(rhs).0))
+    }
+}
+
+impl SubAssign<Duration> for Timestamp {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub for Timestamp {
+    type Output = Duration;
+
+    /// 与 [`saturating_duration_since`](Timestamp::saturating_duration_since) 相同
+    fn sub(self, rhs: Timestamp) -> Duration {
+        self.saturating_duration_since(rhs)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.6}s", self.as_secs_f64())
+    }
+}
+
+/// 将设备的计数器换算为设备时间
+///
+/// 计数器位宽小于 64 位时会回绕，只要相邻两次读数间隔不超过半个回绕周期即可正确展开。
+#[derive(Debug, Clone)]
+pub struct TickConverter {
+    /// 计数频率（Hz）
+    frequency: u64,
+    /// 计数器的掩码
+    mask: u64,
+    /// 上一次展开后的计数值
+    last: Option<u64>,
+}
+
+impl TickConverter {
+    /// 频率为 `frequency` Hz、位宽为 `bits` 的计数器
+    ///
+    /// # Panics
+    /// 频率为零或位宽不在 1..=64 内时 panic
+    pub fn new(frequency: u64, bits: u32) -> Self {
+        assert!(frequency > 0, "[utility] 计数频率不能为零");
+        assert!((1..=64).contains(&bits), "[utility] 计数器位宽{}无效", bits);
+        Self {
+            frequency,
+            mask: u64::MAX >> (64 - bits),
+            last: None,
+        }
+    }
+
+    /// 展开回绕后的计数值，首次读数原样返回
+    pub fn unwrap(&mut self, ticks: u64) -> u64 {
+        let ticks = ticks & self.mask;
+        let unwrapped = match self.last {
+            None => ticks,
+            Some(last) => {
+                // 相对上次读数的变化量，按位宽取模后解释为有符号数
+                let delta = ticks.wrapping_sub(last) & self.mask;
+                let half = (self.mask >> 1) + 1;
+                if self.mask != u64::MAX && delta >= half {
+                    last.wrapping_sub((self.mask - delta) + 1)
+                } else {
+                    last.wrapping_add(delta)
+                }
+            }
+        };
+        self.last = Some(unwrapped);
+        unwrapped
+    }
+
+    /// 将计数值展开并换算为设备时间
+    pub fn to_duration(&mut self, ticks: u64) -> Duration {
+        let ticks = self.unwrap(ticks);
+        self.duration_of(ticks)
+    }
+
+    /// 将已展开的计数值换算为时长，不改变展开状态
+    pub fn duration_of(&self, ticks: u64) -> Duration {
+        let nanos = ticks as u128 * 1_000_000_000 / self.frequency as u128;
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+}
+
+/// 估计的漂移的上限，超出时视为样本异常
+const MAX_DRIFT: f64 = 1e-3;
+
+/// 单个同步样本：在主机时刻 `local` 附近，主机时间减设备时间的取值区间
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    local: Timestamp,
+    /// 区间下界，单向样本没有下界
+    low: Option<i64>,
+    high: i64,
+}
+
+/// 时钟偏移与漂移的估计
+///
+/// 在主机时刻 `local` 处，`主机时间 - 设备时间 = offset + drift * (local - reference)`。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockEstimate {
+    /// 估计所参照的主机时刻，即最新样本的时刻
+    pub reference: Timestamp,
+    /// 参照时刻处主机时间减设备时间的纳秒数
+    pub offset: f64,
+    /// 设备时钟相对主机时钟的漂移，正值表示设备时钟走得慢
+    pub drift: f64,
+    /// 偏移的不确定度，仅有单向样本时为 `None`
+    pub uncertainty: Option<Duration>,
+}
+
+/// 在线估计设备时钟相对主机时钟的偏移与漂移
+///
+/// 每个样本给出主机时间减设备时间的一个取值区间：往返样本的区间为请求发出与收到回复之间，
+/// 单向样本（如相机帧到达）只有上界，即假设传输延迟非负。在最近 `capacity` 个样本上以最小二乘
+/// 拟合漂移，扣除漂移后取全部区间的交集作为偏移的估计：有下界时取交集中点，否则取上界，
+/// 因此单向样本的估计偏向传输延迟最小的样本。
+#[derive(Debug, Clone)]
+pub struct ClockSync {
+    capacity: usize,
+    samples: VecDeque<Sample>,
+    estimate: Option<ClockEstimate>,
+}
+
+impl ClockSync {
+    /// 使用最近 `capacity` 个样本进行估计
+    ///
+    /// # Panics
+    /// `capacity` 为零时 panic
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "[utility] 同步样本数量不能为零");
+        Self {
+            capacity,
+            samples: VecDeque::with_capacity(capacity),
+            estimate: None,
+        }
+    }
+
+    /// 添加往返样本：主机在 `sent` 发出请求，设备回复其时间 `remote`，主机在 `received` 收到
+    ///
+    /// `received` 早于 `sent` 的样本被忽略。
+    pub fn add_round_trip(&mut self, sent: Timestamp, remote: Duration, received: Timestamp) {
+        if received < sent {
+            return;
+        }
+        let remote = Timestamp::from_duration(remote);
+        let middle = sent + (received - sent) / 2;
+        self.push(Sample {
+            local: middle,
+            low: Some(sent.signed_nanos_since(remote)),
+            high: received.signed_nanos_since(remote),
+        });
+    }
+
+    /// 添加单向样本：设备在其时间 `remote` 产生的数据于主机时刻 `received` 到达
+    pub fn add_one_way(&mut self, remote: Duration, received: Timestamp) {
+        let remote = Timestamp::from_duration(remote);
+        self.push(Sample {
+            local: received,
+            low: None,
+            high: received.signed_nanos_since(remote),
+        });
+    }
+
+    /// 清空全部样本，如设备重启导致时钟跳变时
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.estimate = None;
+    }
+
+    /// 当前的估计，尚无样本时为 `None`
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        self.estimate
+    }
+
+    /// 将设备时间换算为主机时间
+    pub fn to_local(&self, remote: Duration) -> Option<Timestamp> {
+        let estimate = self.estimate?;
+        // 以参照时刻为原点：local = remote + offset + drift * (local - reference)
+        let remote = Timestamp::from_duration(remote).signed_nanos_since(estimate.reference);
+        let local = (remote as f64 + estimate.offset) / (1.0 - estimate.drift);
+        Some(offset_by(estimate.reference, local))
+    }
+
+    /// 将主机时间换算为设备时间
+    pub fn to_remote(&self, local: Timestamp) -> Option<Duration> {
+        let estimate = self.estimate?;
+        let local = local.signed_nanos_since(estimate.reference) as f64;
+        let remote = local - estimate.offset - estimate.drift * local;
+        Some(offset_by(estimate.reference, remote).as_duration())
+    }
+
+    fn push(&mut self, sample: Sample) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.estimate = Some(self.fit());
+    }
+
+    fn fit(&self) -> ClockEstimate {
+        let reference = self.samples.back().expect("至少有一个样本").local;
+        let x = |sample: &Sample| sample.local.signed_nanos_since(reference) as f64;
+        let y = |sample: &Sample| match sample.low {
+            Some(low) => (low as f64 + sample.high as f64) / 2.0,
+            None => sample.high as f64,
+        };
+
+        // 最小二乘拟合漂移
+        let n = self.samples.len() as f64;
+        let mean_x = self.samples.iter().map(x).sum::<f64>() / n;
+        let mean_y = self.samples.iter().map(y).sum::<f64>() / n;
+        let (mut sxx, mut sxy) = (0.0, 0.0);
+        for sample in &self.samples {
+            let dx = x(sample) - mean_x;
+            sxx += dx * dx;
+            sxy += dx * (y(sample) - mean_y);
+        }
+        let drift = if sxx > 0.0 {
+            (sxy / sxx).clamp(-MAX_DRIFT, MAX_DRIFT)
+        } else {
+            0.0
+        };
+
+        // 扣除漂移后取区间的交集
+        let mut low = f64::NEG_INFINITY;
+        let mut high = f64::INFINITY;
+        for sample in &self.samples {
+            let correction = drift * x(sample);
+            if let Some(sample_low) = sample.low {
+                low = low.max(sample_low as f64 - correction);
+            }
+            high = high.min(sample.high as f64 - correction);
+        }
+        let (offset, uncertainty) = if low.is_finite() {
+            // 区间不相交时（漂移估计误差或时钟跳变）同样取中点
+            let uncertainty = Duration::from_nanos(((high - low).abs() / 2.0) as u64);
+            ((low + high) / 2.0, Some(uncertainty))
+        } else {
+            (high, None)
+        };
+        ClockEstimate {
+            reference,
+            offset,
+            drift,
+            uncertainty,
+        }
+    }
+}
+
+/// `base` 加上可以为负的纳秒数，结果早于时间零点时饱和为零点
+fn offset_by(base: Timestamp, nanos: f64) -> Timestamp {
+    let nanos = base.as_nanos() as f64 + nanos.round();
+    Timestamp::from_nanos(nanos.clamp(0.0, u64::MAX as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        let start = Timestamp::now();
+        let later = start + Duration::from_millis(5);
+        assert_eq!(later - start, Duration::from_millis(5));
+        assert_eq!(start - later, Duration::ZERO);
+        assert_eq!(start.checked_duration_since(later), None);
+        assert_eq!(later.signed_nanos_since(start), 5_000_000);
+        assert_eq!(start.signed_nanos_since(later), -5_000_000);
+        assert_eq!(Timestamp::from_instant(start.to_instant()), start);
+        assert_eq!(Timestamp::ZERO - Duration::from_secs(1), Timestamp::ZERO);
+        assert_eq!(
+            serde_json::to_string(&later).unwrap(),
+            later.as_nanos().to_string()
+        );
+    }
+
+    #[test]
+    fn test_tick_wraparound() {
+        // 1 kHz 的 8 位计数器
+        let mut ticks = TickConverter::new(1000, 8);
+        assert_eq!(ticks.unwrap(250), 250);
+        assert_eq!(ticks.unwrap(10), 266);
+        assert_eq!(ticks.to_duration(100), Duration::from_millis(356));
+        // 小幅回退（乱序）不视为回绕
+        assert_eq!(ticks.unwrap(90), 346);
+
+        let mut ticks = TickConverter::new(1_000_000_000, 64);
+        assert_eq!(ticks.unwrap(u64::MAX - 1), u64::MAX - 1);
+        assert_eq!(
+            ticks.duration_of(1_500_000_000),
+            Duration::from_millis(1500)
+        );
+    }
+
+    #[test]
+    fn test_clock_sync() {
+        // 设备时钟比主机时钟晚 1 秒启动，且每秒慢 100 微秒
+        let drift = 1e-4;
+        let remote_at = |local: Timestamp| {
+            let local = local.as_nanos() as f64;
+            Duration::from_nanos((local - 1e9 - drift * local) as u64)
+        };
+        let mut sync = ClockSync::new(16);
+        assert_eq!(sync.to_local(Duration::ZERO), None);
+        for i in 0..16u64 {
+            let sent = Timestamp::from_nanos(2_000_000_000 + i * 100_000_000);
+            // 往返时间在 1~3 毫秒之间变化
+            let rtt = Duration::from_micros(1000 + (i * 7919 % 2000));
+            let received = sent + rtt;
+            sync.add_round_trip(sent, remote_at(sent + rtt / 2), received);
+        }
+        let estimate = sync.estimate().unwrap();
+        assert!((estimate.drift - drift).abs() < 1e-5, "{:?}", estimate);
+        assert!(estimate.uncertainty.unwrap() < Duration::from_millis(1));
+
+        let local = Timestamp::from_nanos(3_600_000_000);
+        let error = |a: Timestamp, b: Timestamp| a.signed_nanos_since(b).abs();
+        assert!(error(sync.to_local(remote_at(local)).unwrap(), local) < 100_000);
+        let remote = sync.to_remote(local).unwrap();
+        assert!(remote.abs_diff(remote_at(local)) < Duration::from_micros(100));
+
+        // 单向样本：估计接近延迟最小的样本
+        let mut sync = ClockSync::new(8);
+        for (i, delay) in [5, 2, 8, 3].into_iter().enumerate() {
+            let event = Timestamp::from_nanos(2_000_000_000 + i as u64 * 10_000_000);
+            sync.add_one_way(remote_at(event), event + Duration::from_millis(delay));
+        }
+        let event = Timestamp::from_nanos(2_050_000_000);
+        let estimated = sync.to_local(remote_at(event)).unwrap();
+        assert!(estimated >= event);
+        assert!(estimated - event < Duration::from_millis(3));
+    }
+}
@@ -0,0 +1,403 @@
+//! 逐帧延迟追踪
+//!
+//! 每帧数据携带一个 [`TraceContext`]，记录采集时间以及经过各阶段时的时间戳。帧处理完毕
+//! （如发出云台指令）时调用 [`record`]，端到端延迟与各阶段耗时汇总到直方图中，可通过
+//! [`summary`] 读取 p50/p95/p99，端到端延迟同时登记为 [`metrics`](crate::metrics) 中的
+//! `trace_end_to_end_seconds` 直方图。调用 [`dump_to`] 后还会将每帧的原始时间线写入 Chrome
+//! trace（Perfetto 兼容）格式的 JSON 文件，供离线分析。
+//!
+//! ```rust
+//! use utility::{trace, TraceContext};
+//!
+//! let mut context = TraceContext::new(1);
+//! context.stamp("检测");
+//! context.stamp("追踪");
+//! trace::record(&context);
+//! assert_eq!(trace::summary().end_to_end.count, 1);
+//! ```
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{metrics, Timestamp};
+
+/// 每帧最多记录的时间戳数量，超出的部分被忽略
+pub const MAX_STAMPS: usize = 8;
+
+/// 随帧传递的追踪信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 帧序号
+    pub frame: u64,
+    /// 采集（曝光）时间
+    pub capture: Timestamp,
+    stamps: [Option<(&'static str, Timestamp)>; MAX_STAMPS],
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::with_capture(0, Timestamp::ZERO)
+    }
+}
+
+impl TraceContext {
+    /// 以当前时间作为采集时间
+    pub fn new(frame: u64) -> Self {
+        Self::with_capture(frame, Timestamp::now())
+    }
+
+    /// 指定采集时间，如相机驱动给出的曝光时间
+    pub fn with_capture(frame: u64, capture: Timestamp) -> Self {
+        Self {
+            frame,
+            capture,
+            stamps: [None; MAX_STAMPS],
+        }
+    }
+
+    /// 在阶段处理完毕时记录时间戳
+    pub fn stamp(&mut self, stage: &'static str) {
+        if let Some(slot) = self.stamps.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((stage, Timestamp::now()));
+        }
+    }
+
+    /// 已记录的时间戳，按记录顺序排列
+    pub fn stamps(&self) -> impl Iterator<Item = (&'static str, Timestamp)> + '_ {
+        self.stamps.iter().map_while(|stamp| *stamp)
+    }
+
+    /// 从采集到最后一个时间戳的耗时
+    pub fn elapsed(&self) -> Duration {
+        self.stamps().last().map_or(Duration::ZERO, |(_, at)| {
+            at.saturating_duration_since(self.capture)
+        })
+    }
+}
+
+/// 每个 2 的幂区间再等分的份数，相对误差不超过 1/16
+const SUB_BUCKETS: u64 = 16;
+
+/// 以纳秒为单位的对数分桶直方图
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// 首次记录时分配
+    counts: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            counts: Vec::new(),
+            count: 0,
+            max: 0,
+        }
+    }
+
+    fn bucket(ns: u64) -> usize {
+        if ns < SUB_BUCKETS {
+            return ns as usize;
+        }
+        let exp = 63 - u64::from(ns.leading_zeros());
+        let mantissa = (ns >> (exp - 4)) & (SUB_BUCKETS - 1);
+        ((exp - 3) * SUB_BUCKETS + mantissa) as usize
+    }
+
+    /// 分桶的下界
+    fn lower_bound(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < SUB_BUCKETS {
+            return bucket;
+        }
+        let (exp, mantissa) = (bucket / SUB_BUCKETS + 3, bucket % SUB_BUCKETS);
+        (SUB_BUCKETS + mantissa) << (exp - 4)
+    }
+
+    pub fn record(&mut self, value: Duration) {
+        let ns = value.as_nanos().min(u64::MAX as u128) as u64;
+        let bucket = Self::bucket(ns);
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.max = self.max.max(ns);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// 第 `q`（0 到 1）分位数，没有记录时为零
+    pub fn percentile(&self, q: f64) -> Duration {
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                // 最大值所在的分桶直接取最大值
+                let ns = if seen == self.count {
+                    self.max
+                } else {
+                    Self::lower_bound(bucket)
+                };
+                return Duration::from_nanos(ns);
+            }
+        }
+        Duration::ZERO
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let us = |value: Duration| value.as_secs_f64() * 1e6;
+        LatencySummary {
+            count: self.count,
+            p50_us: us(self.percentile(0.50)),
+            p95_us: us(self.percentile(0.95)),
+            p99_us: us(self.percentile(0.99)),
+            max_us: us(self.max()),
+        }
+    }
+}
+
+/// 延迟的分位数，单位为微秒
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50_us: f64,
+    pub p95_us: f64,
+    pub p99_us: f64,
+    pub max_us: f64,
+}
+
+/// 单个阶段的耗时，即从上一个时间戳（或采集）到该阶段时间戳的间隔
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StageLatency {
+    pub stage: String,
+    #[serde(flatten)]
+    pub latency: LatencySummary,
+}
+
+/// 延迟统计
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct TraceSummary {
+    /// 从采集到最后一个时间戳的延迟
+    pub end_to_end: LatencySummary,
+    /// 各阶段的耗时，按首次出现的顺序排列
+    pub stages: Vec<StageLatency>,
+}
+
+struct Tracer {
+    end_to_end: Histogram,
+    stages: Vec<(&'static str, Histogram)>,
+    dump: Option<BufWriter<File>>,
+    /// 已写入的事件数量
+    events: u64,
+}
+
+static END_TO_END: LazyLock<metrics::Histogram> =
+    LazyLock::new(|| metrics::histogram("trace_end_to_end_seconds", &[], metrics::LATENCY_BUCKETS));
+
+static TRACER: Mutex<Tracer> = Mutex::new(Tracer {
+    end_to_end: Histogram::new(),
+    stages: Vec::new(),
+    dump: None,
+    events: 0,
+});
+
+impl Tracer {
+    fn stage(&mut self, name: &'static str) -> (usize, &mut Histogram) {
+        let index = match self.stages.iter().position(|(stage, _)| *stage == name) {
+            Some(index) => index,
+            None => {
+                self.stages.push((name, Histogram::new()));
+                self.stages.len() - 1
+            }
+        };
+        (index, &mut self.stages[index].1)
+    }
+
+    fn summary(&self) -> TraceSummary {
+        TraceSummary {
+            end_to_end: self.end_to_end.summary(),
+            stages: self
+                .stages
+                .iter()
+                .map(|(stage, histogram)| StageLatency {
+                    stage: stage.to_string(),
+                    latency: histogram.summary(),
+                })
+                .collect(),
+        }
+    }
+
+    /// 写入一个 Chrome trace 的完整事件
+    fn write_event(
+        &mut self,
+        name: &str,
+        tid: usize,
+        start: Timestamp,
+        end: Timestamp,
+        frame: u64,
+    ) -> io::Result<()> {
+        let Some(dump) = self.dump.as_mut() else {
+            return Ok(());
+        };
+        let us = |at: Timestamp| at.as_nanos() as f64 * 1e-3;
+        let event = json!({
+            "name": name,
+            "ph": "X",
+            "ts": us(start),
+            "dur": us(end) - us(start),
+            "pid": 1,
+            "tid": tid,
+            "args": { "frame": frame },
+        });
+        let separator = if self.events == 0 { "" } else { ",\n" };
+        write!(dump, "{}{}", separator, event)?;
+        self.events += 1;
+        Ok(())
+    }
+
+    fn record(&mut self, context: &TraceContext) -> io::Result<()> {
+        let mut previous = context.capture;
+        let mut end = None;
+        for (name, at) in context.stamps() {
+            let (index, histogram) = self.stage(name);
+            histogram.record(at.saturating_duration_since(previous));
+            // 第 0 条轨道用于整帧
+            self.write_event(name, index + 1, previous, at, context.frame)?;
+            previous = at;
+            end = Some(at);
+        }
+        if let Some(end) = end {
+            let latency = end.saturating_duration_since(context.capture);
+            self.end_to_end.record(latency);
+            END_TO_END.observe_duration(latency);
+            self.write_event("帧", 0, context.capture, end, context.frame)?;
+        }
+        Ok(())
+    }
+}
+
+fn tracer() -> std::sync::MutexGuard<'static, Tracer> {
+    TRACER.lock().expect("锁中毒")
+}
+
+/// 记录处理完毕的一帧
+pub fn record(context: &TraceContext) {
+    let mut tracer = tracer();
+    if let Err(err) = tracer.record(context) {
+        log::warn!("[追踪] 写入追踪文件失败，停止写入：{}", err);
+        tracer.dump = None;
+    }
+}
+
+/// 自上次清空以来的延迟统计
+pub fn summary() -> TraceSummary {
+    tracer().summary()
+}
+
+/// 读取延迟统计并清空直方图，用于按时间窗口统计
+pub fn take_summary() -> TraceSummary {
+    let mut tracer = tracer();
+    let summary = tracer.summary();
+    tracer.end_to_end = Histogram::new();
+    tracer.stages.clear();
+    summary
+}
+
+/// 开始将每帧的时间线写入 Chrome trace 格式的文件，已有的文件将被覆盖
+///
+/// 文件为 JSON 数组格式，可直接在 `chrome://tracing` 或 Perfetto 中打开；程序异常退出导致
+/// 缺少结尾的 `]` 时同样可以打开。
+pub fn dump_to(path: impl AsRef<Path>) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path.as_ref())?);
+    writeln!(file, "[")?;
+    let mut tracer = tracer();
+    if let Some(mut previous) = tracer.dump.replace(file) {
+        writeln!(previous, "\n]")?;
+        previous.flush()?;
+    }
+    tracer.events = 0;
+    log::info!("[追踪] 开始写入追踪文件{}", path.as_ref().display());
+    Ok(())
+}
+
+/// 停止写入追踪文件并补全结尾
+pub fn stop_dump() -> io::Result<()> {
+    if let Some(mut dump) = tracer().dump.take() {
+        writeln!(dump, "\n]")?;
+        dump.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.percentile(0.5), Duration::ZERO);
+        for us in 1..=100 {
+            histogram.record(Duration::from_micros(us));
+        }
+        let within = |value: Duration, expected: u64| {
+            let expected = Duration::from_micros(expected).as_secs_f64();
+            (value.as_secs_f64() - expected).abs() <= expected / 16.0
+        };
+        assert!(within(histogram.percentile(0.50), 50));
+        assert!(within(histogram.percentile(0.99), 99));
+        assert_eq!(histogram.percentile(1.0), Duration::from_micros(100));
+        assert_eq!(histogram.count(), 100);
+
+        for ns in [0, 15, 16, 17, 1000, 1 << 40] {
+            let bucket = Histogram::bucket(ns);
+            assert!(Histogram::lower_bound(bucket) <= ns);
+            assert!(ns < Histogram::lower_bound(bucket + 1));
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let capture = Timestamp::now();
+        let mut context = TraceContext::with_capture(7, capture);
+        context.stamp("测试检测");
+        context.stamp("测试追踪");
+        let stages: Vec<_> = context.stamps().map(|(name, _)| name).collect();
+        assert_eq!(stages, ["测试检测", "测试追踪"]);
+        assert!(context.elapsed() > Duration::ZERO);
+
+        let path = std::env::temp_dir().join(format!("quasar_{}_trace.json", std::process::id()));
+        dump_to(&path).unwrap();
+        record(&context);
+        stop_dump().unwrap();
+        let summary = summary();
+        assert!(summary
+            .stages
+            .iter()
+            .any(|stage| stage.stage == "测试追踪" && stage.latency.count >= 1));
+
+        let events: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let events = events.as_array().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[2]["name"], "帧");
+        assert_eq!(events[0]["args"]["frame"], 7);
+        std::fs::remove_file(path).unwrap();
+    }
+}
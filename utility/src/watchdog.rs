@@ -0,0 +1,267 @@
+//! 软件看门狗
+//!
+//! 各阶段通过 [`register`] 登记截止时间并在每次循环时调用 [`Heartbeat::beat`]。监视线程发现
+//! 某个阶段超过截止时间未响应时输出错误日志，按 [`WatchdogAction`] 处理，并向 [`subscribe`]
+//! 得到的接收端发送 [`WatchdogEvent`]（可视化服务将其转发给客户端）。阶段恢复响应后同样发送
+//! 事件。每次停滞只处理一次，直到阶段再次响应。
+//!
+//! 阻塞在外部调用（如相机 SDK）中的线程无法响应取消，[`WatchdogAction::Cancel`] 只能在其返回后
+//! 结束本次运行；此时可选用 [`WatchdogAction::Stop`] 停止整个程序，由外部重新启动。
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use utility::watchdog::{self, WatchdogAction};
+//!
+//! let heartbeat = watchdog::register("检测", Duration::from_millis(200), WatchdogAction::Cancel);
+//! while !utility::is_stopped() {
+//!     heartbeat.beat();
+//!     // 工作
+//!     # break;
+//! }
+//! ```
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, LazyLock, Mutex, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::{current, root, CancelReason, CancelToken, Timestamp};
+
+/// 监视线程检查各阶段的间隔
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// 阶段超过截止时间未响应时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchdogAction {
+    /// 仅输出日志并发送事件
+    Log,
+    /// 取消登记时所在的作用域，由监督者按重启策略重启该阶段
+    Cancel,
+    /// 取消根令牌，停止整个程序
+    Stop,
+}
+
+/// 看门狗事件
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WatchdogEvent {
+    /// 阶段名称
+    pub stage: String,
+    /// 自上次响应起经过的毫秒数
+    pub elapsed_ms: u64,
+    /// 截止时间的毫秒数
+    pub deadline_ms: u64,
+    pub action: WatchdogAction,
+    /// 为 `true` 时表示阶段已恢复响应
+    pub recovered: bool,
+}
+
+struct Entry {
+    name: String,
+    deadline: Duration,
+    action: WatchdogAction,
+    /// 登记时所在的作用域
+    token: CancelToken,
+    /// 上次响应的时刻（纳秒）
+    last_beat: AtomicU64,
+    stalled: AtomicBool,
+}
+
+/// 阶段的心跳，丢弃时取消登记
+pub struct Heartbeat {
+    entry: Arc<Entry>,
+}
+
+impl Heartbeat {
+    /// 报告阶段仍在正常运行
+    pub fn beat(&self) {
+        self.entry
+            .last_beat
+            .store(Timestamp::now().as_nanos(), Ordering::Release);
+    }
+}
+
+/// 看门狗，持有一个监视线程
+///
+/// 通常使用全局的看门狗，即本模块的 [`register`] 与 [`subscribe`]。
+pub struct Watchdog {
+    shared: Arc<Shared>,
+    token: CancelToken,
+}
+
+#[derive(Default)]
+struct Shared {
+    entries: Mutex<Vec<Weak<Entry>>>,
+    subscribers: Mutex<Vec<Sender<WatchdogEvent>>>,
+}
+
+impl Watchdog {
+    /// 启动看门狗，`parent` 取消时监视线程随之结束
+    ///
+    /// # Panics
+    /// 无法创建监视线程时 panic
+    pub fn new(parent: &CancelToken) -> Self {
+        let shared = Arc::new(Shared::default());
+        let token = parent.child("看门狗");
+        thread::Builder::new()
+            .name("看门狗".to_string())
+            .spawn({
+                let shared = shared.clone();
+                let token = token.clone();
+                move || {
+                    while token.wait_timeout(CHECK_INTERVAL).is_none() {
+                        shared.check(Timestamp::now());
+                    }
+                }
+            })
+            .expect("[utility] 无法创建看门狗线程");
+        Self { shared, token }
+    }
+
+    /// 以当前作用域登记阶段，登记时视为已响应一次
+    pub fn register(&self, name: &str, deadline: Duration, action: WatchdogAction) -> Heartbeat {
+        let entry = Arc::new(Entry {
+            name: name.to_string(),
+            deadline,
+            action,
+            token: current(),
+            last_beat: AtomicU64::new(Timestamp::now().as_nanos()),
+            stalled: AtomicBool::new(false),
+        });
+        let mut entries = self.shared.entries.lock().expect("锁中毒");
+        entries.retain(|entry| entry.strong_count() > 0);
+        entries.push(Arc::downgrade(&entry));
+        Heartbeat { entry }
+    }
+
+    /// 订阅看门狗事件
+    pub fn subscribe(&self) -> Receiver<WatchdogEvent> {
+        let (sender, receiver) = unbounded();
+        self.shared.subscribers.lock().expect("锁中毒").push(sender);
+        receiver
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.token.cancel(CancelReason::Requested);
+    }
+}
+
+impl Shared {
+    fn check(&self, now: Timestamp) {
+        let entries: Vec<_> = self
+            .entries
+            .lock()
+            .expect("锁中毒")
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for entry in entries {
+            let last_beat = Timestamp::from_nanos(entry.last_beat.load(Ordering::Acquire));
+            let elapsed = now.saturating_duration_since(last_beat);
+            let stalled = elapsed > entry.deadline;
+            if stalled == entry.stalled.swap(stalled, Ordering::AcqRel) {
+                continue;
+            }
+            if stalled {
+                error!(
+                    "[utility] 看门狗：{}已{:?}未响应（截止时间{:?}）",
+                    entry.name, elapsed, entry.deadline
+                );
+                let reason = || {
+                    CancelReason::Error(format!("看门狗：{}超过{:?}未响应", entry.name, elapsed))
+                };
+                match entry.action {
+                    WatchdogAction::Log => {}
+                    WatchdogAction::Cancel => {
+                        entry.token.cancel(reason());
+                    }
+                    WatchdogAction::Stop => {
+                        root().cancel(reason());
+                    }
+                }
+            } else {
+                info!("[utility] 看门狗：{}已恢复响应", entry.name);
+            }
+            self.publish(WatchdogEvent {
+                stage: entry.name.clone(),
+                elapsed_ms: elapsed.as_millis() as u64,
+                deadline_ms: entry.deadline.as_millis() as u64,
+                action: entry.action,
+                recovered: !stalled,
+            });
+        }
+    }
+
+    fn publish(&self, event: WatchdogEvent) {
+        self.subscribers
+            .lock()
+            .expect("锁中毒")
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+static WATCHDOG: LazyLock<Watchdog> = LazyLock::new(|| Watchdog::new(root()));
+
+/// 在全局看门狗中以当前作用域登记阶段
+pub fn register(name: &str, deadline: Duration, action: WatchdogAction) -> Heartbeat {
+    WATCHDOG.register(name, deadline, action)
+}
+
+/// 订阅全局看门狗的事件
+pub fn subscribe() -> Receiver<WatchdogEvent> {
+    WATCHDOG.subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let parent = CancelToken::new("根");
+        let watchdog = Watchdog::new(&parent);
+        let events = watchdog.subscribe();
+        let stage = parent.child("阶段");
+        let heartbeat = {
+            let _scope = stage.enter();
+            watchdog.register(
+                "测试阶段",
+                Duration::from_millis(50),
+                WatchdogAction::Cancel,
+            )
+        };
+
+        // 持续响应时不触发
+        for _ in 0..10 {
+            heartbeat.beat();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(events.try_recv().is_err());
+
+        // 停滞后取消登记时的作用域，且只触发一次
+        let event = events.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(event.stage, "测试阶段");
+        assert!(!event.recovered);
+        assert!(event.elapsed_ms >= 50);
+        assert!(matches!(stage.reason(), Some(CancelReason::Error(_))));
+        assert!(!parent.is_cancelled());
+        assert!(events.recv_timeout(Duration::from_millis(100)).is_err());
+
+        heartbeat.beat();
+        let event = events.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(event.recovered);
+
+        // 丢弃心跳后不再监视
+        drop(heartbeat);
+        assert!(events.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}